@@ -0,0 +1,98 @@
+//! Counting global allocator behind the `alloc-stats` feature, so allocation
+//! regressions show up as numbers instead of needing heaptrack
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering::Relaxed};
+
+#[global_allocator]
+static COUNTING: Counting = Counting;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+static REALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+static DEALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+static BYTES_ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+static LIVE_BYTES: AtomicUsize = AtomicUsize::new(0);
+static PEAK_BYTES: AtomicUsize = AtomicUsize::new(0);
+
+///Forwards to the system allocator, counting calls and bytes on the way
+struct Counting;
+
+fn grew(bytes: usize) {
+    BYTES_ALLOCATED.fetch_add(bytes, Relaxed);
+    let live = LIVE_BYTES.fetch_add(bytes, Relaxed) + bytes;
+    PEAK_BYTES.fetch_max(live, Relaxed);
+}
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = unsafe { System.alloc(layout) };
+        if !ptr.is_null() {
+            ALLOCATIONS.fetch_add(1, Relaxed);
+            grew(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let ptr = unsafe { System.alloc_zeroed(layout) };
+        if !ptr.is_null() {
+            ALLOCATIONS.fetch_add(1, Relaxed);
+            grew(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_ptr = unsafe { System.realloc(ptr, layout, new_size) };
+        if !new_ptr.is_null() {
+            REALLOCATIONS.fetch_add(1, Relaxed);
+            if new_size > layout.size() {
+                grew(new_size - layout.size());
+            } else {
+                LIVE_BYTES.fetch_sub(layout.size() - new_size, Relaxed);
+            }
+        }
+        new_ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) };
+        DEALLOCATIONS.fetch_add(1, Relaxed);
+        LIVE_BYTES.fetch_sub(layout.size(), Relaxed);
+    }
+}
+
+///Counters since the start of the process
+#[derive(Debug, Clone, Copy)]
+pub struct AllocStats {
+    pub allocations: usize,
+    pub reallocations: usize,
+    pub deallocations: usize,
+    pub bytes_allocated: usize,
+    pub peak_bytes: usize,
+}
+
+pub fn stats() -> AllocStats {
+    AllocStats {
+        allocations: ALLOCATIONS.load(Relaxed),
+        reallocations: REALLOCATIONS.load(Relaxed),
+        deallocations: DEALLOCATIONS.load(Relaxed),
+        bytes_allocated: BYTES_ALLOCATED.load(Relaxed),
+        peak_bytes: PEAK_BYTES.load(Relaxed),
+    }
+}
+
+impl fmt::Display for AllocStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "allocs {}  reallocs {}  frees {}  allocated {} bytes  peak {} bytes",
+            self.allocations,
+            self.reallocations,
+            self.deallocations,
+            self.bytes_allocated,
+            self.peak_bytes
+        )
+    }
+}
@@ -0,0 +1,110 @@
+//! Per-image metrics for auditing a dataset, one CSV row per file so they can be sorted,
+//! filtered and joined with whatever other tables the dataset has.
+
+use crate::format::ImageFormat;
+use crate::img::PixelFormat;
+use crate::qoi;
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::time::{Duration, Instant};
+
+pub const HEADER: &str =
+    "path,format,width,height,pixels,unique_colors,alpha,qoi_bytes,decode_ms,error";
+
+///How an image uses its alpha channel
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Alpha {
+    ///The pixel format has no alpha channel
+    None,
+    ///It has one, but every pixel is opaque
+    Opaque,
+    ///Every pixel is either opaque or fully transparent
+    Binary,
+    ///Some pixels are partly transparent
+    Partial,
+}
+
+impl Alpha {
+    fn as_str(self) -> &'static str {
+        match self {
+            Alpha::None => "none",
+            Alpha::Opaque => "opaque",
+            Alpha::Binary => "binary",
+            Alpha::Partial => "partial",
+        }
+    }
+}
+
+pub struct Metrics {
+    pub format: String,
+    pub width: u32,
+    pub height: u32,
+    pub pixels: PixelFormat,
+    pub unique_colors: usize,
+    pub alpha: Alpha,
+    ///Size of the image encoded as QOI
+    pub qoi_bytes: usize,
+    ///Time to decode the file's bytes, not counting reading them
+    pub decode: Duration,
+}
+
+///Decode `data` and measure the result. Colors are counted after conversion to RGBA8.
+pub fn measure(format: &dyn ImageFormat, data: Vec<u8>) -> Result<Metrics, String> {
+    let start = Instant::now();
+    let img = format.decode(data)?;
+    let decode = start.elapsed();
+
+    let mut rgba = vec![];
+    img.rgba8_into(&mut rgba);
+    let colors: HashSet<u32> = rgba
+        .chunks_exact(4)
+        .map(|pixel| u32::from_ne_bytes([pixel[0], pixel[1], pixel[2], pixel[3]]))
+        .collect();
+    let alphas = |keep: fn(u8) -> bool| rgba.chunks_exact(4).all(|pixel| keep(pixel[3]));
+    let alpha = if !img.format().has_alpha() {
+        Alpha::None
+    } else if alphas(|a| a == 255) {
+        Alpha::Opaque
+    } else if alphas(|a| a == 0 || a == 255) {
+        Alpha::Binary
+    } else {
+        Alpha::Partial
+    };
+    Ok(Metrics {
+        format: format.name().to_string(),
+        width: img.0,
+        height: img.1,
+        pixels: img.format(),
+        unique_colors: colors.len(),
+        alpha,
+        qoi_bytes: qoi::encode_img(img).len(),
+        decode,
+    })
+}
+
+///One CSV line (without the newline) for `path`: its metrics, or blanks and the error
+pub fn row(path: &str, metrics: &Result<Metrics, String>) -> String {
+    match metrics {
+        Ok(m) => format!(
+            "{},{},{},{},{:?},{},{},{},{:.3},",
+            field(path),
+            field(&m.format),
+            m.width,
+            m.height,
+            m.pixels,
+            m.unique_colors,
+            m.alpha.as_str(),
+            m.qoi_bytes,
+            m.decode.as_secs_f64() * 1000.0
+        ),
+        Err(e) => format!("{},,,,,,,,,{}", field(path), field(e)),
+    }
+}
+
+///Quote a field if it has a comma, quote or line break in it (RFC 4180)
+fn field(s: &str) -> Cow<'_, str> {
+    match s.contains([',', '"', '\n', '\r']) {
+        true => format!("\"{}\"", s.replace('"', "\"\"")).into(),
+        false => s.into(),
+    }
+}
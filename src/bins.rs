@@ -0,0 +1,124 @@
+//! Simple visual properties for sorting a pile of images into folders: how bright, what
+//! color, how big and how busy each one is.
+
+use crate::img::RawImage;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Property {
+    ///Mean Rec. 601 luma, 0 to 255
+    Brightness,
+    ///The hue most saturated pixels have, or gray if few are
+    Hue,
+    ///Width times height
+    Size,
+    ///Shannon entropy of the luma histogram in bits, 0 (flat) to 8 (noise)
+    Entropy,
+}
+
+///Hue bins, by sixths of the color wheel starting at red, then gray
+const HUES: [&str; 7] = ["red", "yellow", "green", "cyan", "blue", "magenta", "gray"];
+
+///Below this share of saturated pixels an image counts as gray
+const COLORFUL: f64 = 0.1;
+
+impl Property {
+    ///The property's value for an image. Hue is the index of its name in [`HUES`].
+    pub fn measure(self, img: &RawImage) -> f64 {
+        if self == Property::Size {
+            return img.0 as f64 * img.1 as f64;
+        }
+        let mut rgba = vec![];
+        img.rgba8_into(&mut rgba);
+        let pixels = (rgba.len() / 4).max(1) as f64;
+        let luma = |p: &[u8]| (p[0] as u32 * 299 + p[1] as u32 * 587 + p[2] as u32 * 114) / 1000;
+        match self {
+            Property::Brightness => {
+                let sum: u64 = rgba.chunks_exact(4).map(|p| luma(p) as u64).sum();
+                sum as f64 / pixels
+            }
+            Property::Entropy => {
+                let mut histogram = [0u64; 256];
+                for p in rgba.chunks_exact(4) {
+                    histogram[luma(p) as usize] += 1;
+                }
+                histogram
+                    .iter()
+                    .filter(|&&count| count > 0)
+                    .map(|&count| {
+                        let p = count as f64 / pixels;
+                        p * (1.0 / p).log2()
+                    })
+                    .sum()
+            }
+            _ => {
+                let mut sectors = [0u64; 6];
+                for p in rgba.chunks_exact(4) {
+                    if let Some(hue) = hue(p[0], p[1], p[2]) {
+                        sectors[((hue + 30.0) / 60.0) as usize % 6] += 1;
+                    }
+                }
+                let (sector, &count) = sectors
+                    .iter()
+                    .enumerate()
+                    .max_by_key(|&(_, count)| count)
+                    .unwrap();
+                match count as f64 / pixels >= COLORFUL {
+                    true => sector as f64,
+                    false => 6.0,
+                }
+            }
+        }
+    }
+
+    ///Split the images into `bins` groups of about equal size by their values, returning
+    ///each image's bin and the folder name of every bin. Equal values always share a bin,
+    ///so some can end up empty. Hue has its own seven bins.
+    pub fn bin(self, values: &[f64], bins: usize) -> (Vec<usize>, Vec<String>) {
+        if self == Property::Hue {
+            let names = HUES.iter().map(|name| name.to_string()).collect();
+            return (values.iter().map(|&v| v as usize).collect(), names);
+        }
+        let mut order: Vec<usize> = (0..values.len()).collect();
+        order.sort_by(|&a, &b| values[a].total_cmp(&values[b]));
+        let bins = bins.clamp(1, values.len().max(1));
+        let mut assigned = vec![0; values.len()];
+        let mut ranges = vec![(f64::MAX, f64::MIN); bins];
+        let mut bin = 0;
+        for (rank, &i) in order.iter().enumerate() {
+            if rank == 0 || values[i] != values[order[rank - 1]] {
+                bin = rank * bins / values.len();
+            }
+            assigned[i] = bin;
+            ranges[bin] = (ranges[bin].0.min(values[i]), ranges[bin].1.max(values[i]));
+        }
+        // Numbered first so the folders list in order
+        let names = ranges
+            .iter()
+            .enumerate()
+            .map(|(n, &(low, high))| match self {
+                Property::Entropy => format!("{}_{:.1}-{:.1}", n + 1, low, high),
+                _ => format!("{}_{:.0}-{:.0}", n + 1, low, high),
+            })
+            .collect();
+        (assigned, names)
+    }
+}
+
+///Hue in degrees, or None for pixels too dark or unsaturated to have a meaningful one
+fn hue(r: u8, g: u8, b: u8) -> Option<f64> {
+    let (r, g, b) = (r as f64, g as f64, b as f64);
+    let max = r.max(g).max(b);
+    let min = r.min(g).min(b);
+    if max < 48.0 || (max - min) / max < 0.25 {
+        return None;
+    }
+    let delta = max - min;
+    let hue = if max == r {
+        (g - b) / delta
+    } else if max == g {
+        (b - r) / delta + 2.0
+    } else {
+        (r - g) / delta + 4.0
+    };
+    Some((hue * 60.0).rem_euclid(360.0))
+}
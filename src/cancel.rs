@@ -0,0 +1,75 @@
+//! Ctrl-C for long-running commands. The first press asks them to stop once the file or
+//! frame in hand is done, so they can report how far they got. A second press quits at
+//! once. Either way nothing is left half written: outputs go to a temporary file that is
+//! only renamed into place when complete, and any still open are removed on the way out.
+
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, Once};
+
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+static PARTIAL: Mutex<Vec<PathBuf>> = Mutex::new(vec![]);
+
+///Handed to the loops that should stop early on Ctrl-C
+#[derive(Clone, Copy)]
+pub struct Cancel {
+    flag: &'static AtomicBool,
+}
+
+impl Cancel {
+    ///Take over Ctrl-C for the rest of the process
+    pub fn on_ctrl_c() -> Self {
+        static INSTALL: Once = Once::new();
+        INSTALL.call_once(|| {
+            let installed = ctrlc::set_handler(|| {
+                if REQUESTED.swap(true, Ordering::SeqCst) {
+                    for path in PARTIAL.lock().unwrap().drain(..) {
+                        fs::remove_file(path).ok();
+                    }
+                    std::process::exit(130);
+                }
+                eprintln!("Stopping after the current step (Ctrl-C again to quit now)");
+            });
+            if let Err(e) = installed {
+                eprintln!("Ctrl-C will stop immediately: {}", e);
+            }
+        });
+        Cancel { flag: &REQUESTED }
+    }
+
+    pub fn requested(&self) -> bool {
+        self.flag.load(Ordering::SeqCst)
+    }
+}
+
+///Write a whole file, see [`write_with`]
+pub fn write_file(path: &Path, data: impl AsRef<[u8]>) -> Result<(), String> {
+    write_with(path, |file| {
+        file.write_all(data.as_ref()).map_err(|e| e.to_string())
+    })
+}
+
+///Fill `.<name>.partial` next to `path`, then rename it to `path`. A failed or interrupted
+///write removes the temporary file and leaves whatever was at `path` before.
+pub fn write_with(
+    path: &Path,
+    fill: impl FnOnce(&mut File) -> Result<(), String>,
+) -> Result<(), String> {
+    let mut name = std::ffi::OsString::from(".");
+    name.push(path.file_name().ok_or("Output path has no file name")?);
+    name.push(".partial");
+    let temp = path.with_file_name(name);
+
+    PARTIAL.lock().unwrap().push(temp.clone());
+    let result = File::create(&temp)
+        .map_err(|e| e.to_string())
+        .and_then(|mut file| fill(&mut file))
+        .and_then(|_| fs::rename(&temp, path).map_err(|e| e.to_string()));
+    PARTIAL.lock().unwrap().retain(|p| *p != temp);
+    if result.is_err() {
+        fs::remove_file(&temp).ok();
+    }
+    result
+}
@@ -1,19 +1,85 @@
 use crate::commands::Command;
-use clap::Parser;
+use crate::examples;
+use clap::{CommandFactory, FromArgMatches, Parser};
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::sync::Mutex;
+use tracing_chrome::FlushGuard;
+use tracing_subscriber::prelude::*;
+
+///Writes out the rest of the --trace-out file when dropped
+static TRACE_GUARD: Mutex<Option<FlushGuard>> = Mutex::new(None);
+
+///Finish the --trace-out file, if there is one. Window event loops exit the process
+///without returning, so they call this on their way out.
+pub fn finish_trace() {
+    if let Ok(mut guard) = TRACE_GUARD.lock() {
+        guard.take();
+    }
+}
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 pub struct Cli {
     #[command(subcommand)]
     command: Command,
+    #[arg(
+        long,
+        global = true,
+        help = "Record a timeline of the run as a chrome://tracing (or Perfetto) JSON file"
+    )]
+    trace_out: Option<PathBuf>,
+    #[cfg(feature = "alloc-stats")]
+    #[arg(
+        long,
+        global = true,
+        help = "Print allocation counts when the command finishes"
+    )]
+    alloc_stats: bool,
 }
 
 impl Cli {
-    pub fn run(self) {
+    ///Parse the command line like `parse`, with each subcommand's examples appended to its
+    ///`--help`
+    pub fn parse_with_examples() -> Self {
+        let mut command = Cli::command();
+        let names: Vec<String> = command
+            .get_subcommands()
+            .map(|sub| sub.get_name().to_string())
+            .collect();
+        for name in names {
+            if let Some(help) = examples::help_for(&name) {
+                command = command.mut_subcommand(name, |sub| sub.after_help(help));
+            }
+        }
+        let matches = command.get_matches();
+        Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit())
+    }
+
+    ///Run the command, printing any error to stderr and turning it into a failing exit code
+    pub fn run(self) -> ExitCode {
+        if let Some(path) = self.trace_out {
+            let (layer, guard) = tracing_chrome::ChromeLayerBuilder::new()
+                .file(path)
+                .include_args(true)
+                .build();
+            tracing_subscriber::registry().with(layer).init();
+            *TRACE_GUARD.lock().unwrap() = Some(guard);
+        }
+        #[cfg(feature = "alloc-stats")]
+        let alloc_stats = self.alloc_stats;
         let result = self.command.run();
+        finish_trace();
+        #[cfg(feature = "alloc-stats")]
+        if alloc_stats {
+            eprintln!("{}", crate::alloc::stats());
+        }
         match result {
-            Ok(_) => {}
-            Err(e) => println!("Error: {}", e),
+            Ok(_) => ExitCode::SUCCESS,
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                ExitCode::FAILURE
+            }
         }
     }
 }
@@ -1,104 +1,1126 @@
+use crate::audit;
+use crate::bins::Property;
+use crate::cancel::{self, Cancel};
+use crate::cli;
+use crate::corpus;
+use crate::dedupe;
+use crate::examples;
+use crate::filter::Filter;
+use crate::format;
 use crate::gfx;
-use crate::img::RawImage;
-use crate::png;
+use crate::gfx::ViewOptions;
+use crate::gif::{self, PaletteMode};
+use crate::img::{
+    ColorSpace, Dimensions, Header, PadFill, PadTo, PixelFormat, RawImage, Redaction,
+};
+use crate::lint;
+use crate::motion::{Background, MotionOutput};
+use crate::palette;
+use crate::phash;
+use crate::png::{self, PngPalette};
 use crate::ppm;
-use crate::qoi;
+use crate::progress::Progress;
+use crate::qoi::{self, QoiChannels};
+use crate::reduce::{self, ReduceOp};
+use crate::rename;
+use crate::shell;
+use crate::slice::{self, Borders};
+use crate::term;
+use crate::timings::{FrameStats, Timings};
 use clap::Subcommand;
+use std::cmp::Ordering;
+use std::collections::HashMap;
 use std::env;
+use std::ffi::{OsStr, OsString};
 use std::fs;
 use std::fs::File;
 use std::io::BufReader;
 use std::io::BufWriter;
 use std::io::prelude::*;
-use std::path::PathBuf;
+use std::io::{self, IsTerminal};
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+#[cfg(feature = "gui")]
 use winit::{
-    event::{Event, WindowEvent},
+    event::{ElementState, Event, KeyboardInput, StartCause, VirtualKeyCode, WindowEvent},
     event_loop::ControlFlow,
 };
 #[derive(Debug, Subcommand)]
+// Without the other commands, convert is the only big one; it's parsed once per run
+#[cfg_attr(not(feature = "cli"), allow(clippy::large_enum_variant))]
 pub enum Command {
-    /// Display a .ppm or .qoi image
-    Open { file_path: PathBuf },
-    /// Convert between image formats (.qoi, .ppm, .png)
+    /// Display an image, or step through a directory of them
+    #[cfg(feature = "cli")]
+    Open {
+        file_path: PathBuf,
+        #[arg(long, help = "Preview in the terminal instead of opening a window")]
+        term: bool,
+        #[arg(
+            long,
+            value_enum,
+            default_value_t = SortOrder::Name,
+            help = "Order to step through a directory in (arrow keys move between images)"
+        )]
+        sort: SortOrder,
+        #[arg(long, help = "Shorthand for --sort random")]
+        random: bool,
+        #[arg(long, help = "Seed for --sort random, to get the same order again")]
+        seed: Option<u64>,
+        #[command(flatten)]
+        view_options: ViewOptions,
+    },
+    /// Convert between image formats (.qoi, .ppm, .pam, .png, .ff; .pgm, .pbm, .jpg, .tif and .gif as input)
     Convert {
         files: Vec<PathBuf>,
         #[arg(short, long, help = "Output file path (for single file conversion)")]
         output: Option<PathBuf>,
+        #[command(flatten)]
+        batch_options: BatchOptions,
+        #[arg(
+            long,
+            help = "Print how long reading, decoding, encoding and writing took"
+        )]
+        timings: bool,
+        #[command(flatten)]
+        input_options: InputOptions,
+        #[command(flatten)]
+        encode_options: EncodeOptions,
+    },
+    /// Combine a sequence of images into one animated file
+    #[cfg(feature = "cli")]
+    Concat {
+        files: Vec<PathBuf>,
+        #[arg(short, long, help = "Output file path")]
+        output: PathBuf,
+        #[arg(
+            long = "to",
+            help = "Output format (only gif for now); defaults to the output extension"
+        )]
+        to: Option<String>,
+        #[arg(long, value_enum, default_value_t = PaletteMode::PerFrame)]
+        palette: PaletteMode,
+        #[arg(long, default_value_t = 10.0, help = "Frames per second")]
+        fps: f32,
+    },
+    /// Combine same-sized frames pixel by pixel, e.g. to denoise or stack a static shot
+    #[cfg(feature = "cli")]
+    Reduce {
+        files: Vec<PathBuf>,
+        #[arg(long, value_enum)]
+        op: ReduceOp,
+        #[arg(short, long, help = "Output file path")]
+        output: PathBuf,
+    },
+    /// Find what moves against a slowly updated background, frame by frame
+    #[cfg(feature = "cli")]
+    Motion {
+        files: Vec<PathBuf>,
+        #[arg(
+            short,
+            long,
+            help = "Output path with a frame number placeholder, e.g. masks/%05d.qoi"
+        )]
+        output: PathBuf,
+        #[arg(long = "write", value_enum, default_value_t = MotionOutput::Mask)]
+        write: MotionOutput,
+        #[arg(
+            long,
+            default_value_t = 24,
+            help = "How far a channel must stray from the background to count as motion"
+        )]
+        threshold: u8,
+        #[arg(
+            long,
+            default_value_t = 0.05,
+            help = "How fast the background absorbs changes, from 0 (never) to 1 (at once)"
+        )]
+        learning_rate: f32,
+    },
+    /// Encode an image sequence into a video by piping frames through ffmpeg
+    #[cfg(feature = "cli")]
+    ExportVideo {
+        files: Vec<PathBuf>,
+        #[arg(short, long, help = "Output video path, e.g. out.mp4 or out.webm")]
+        output: PathBuf,
+        #[arg(long, default_value_t = 25.0, help = "Frames per second")]
+        fps: f32,
+        #[arg(long, default_value = "ffmpeg", help = "ffmpeg executable to run")]
+        ffmpeg: PathBuf,
+    },
+    /// Split a video into numbered image frames by reading them from ffmpeg
+    #[cfg(feature = "cli")]
+    ImportVideo {
+        input: PathBuf,
+        #[arg(
+            short,
+            long,
+            help = "Output path with a frame number placeholder, e.g. frames/%05d.qoi"
+        )]
+        output: PathBuf,
+        #[arg(long, help = "Resample to this many frames per second")]
+        fps: Option<f32>,
+        #[arg(long, default_value = "ffmpeg", help = "ffmpeg executable to run")]
+        ffmpeg: PathBuf,
+    },
+    /// Print each image's size, channels, bit depth and colorspace without decoding pixels
+    Info {
+        #[arg(help = "Image files, or directories of them")]
+        paths: Vec<PathBuf>,
+    },
+    /// Write a CSV of per-image metrics (size, colors, alpha, QOI size, decode time)
+    #[cfg(feature = "cli")]
+    Audit {
+        #[arg(help = "Image files, or directories to search recursively")]
+        paths: Vec<PathBuf>,
+        #[arg(
+            long,
+            short,
+            default_value = "-",
+            help = "CSV file to write, or - for stdout"
+        )]
+        out: PathBuf,
+    },
+    /// Find images with identical pixels, and with --near, ones that look alike
+    #[cfg(feature = "cli")]
+    Dedupe {
+        #[arg(help = "Image files, or directories to search recursively")]
+        paths: Vec<PathBuf>,
+        #[arg(
+            long,
+            value_name = "BITS",
+            help = "Also group images whose 64-bit perceptual hashes differ in at most BITS bits (try 5)"
+        )]
+        near: Option<u32>,
+        #[arg(
+            long,
+            help = "Replace exact duplicates with hard links to the first file of the group in the same format"
+        )]
+        link: bool,
+        #[arg(
+            long,
+            value_name = "DIR",
+            conflicts_with = "link",
+            help = "Move every file in a group but the first into DIR"
+        )]
+        move_to: Option<PathBuf>,
+    },
+    /// Print each image's 64-bit perceptual hash, for finding look-alikes by Hamming distance
+    #[cfg(feature = "cli")]
+    Phash {
+        #[arg(help = "Image files, or directories to search recursively")]
+        paths: Vec<PathBuf>,
+    },
+    /// Read or change a PNG's text metadata (tEXt, zTXt and iTXt chunks)
+    #[cfg(feature = "cli")]
+    Meta {
+        #[command(subcommand)]
+        action: MetaAction,
+    },
+    /// Move (or symlink) images into folders by brightness, hue, size or entropy
+    #[cfg(feature = "cli")]
+    Sort {
+        #[arg(help = "Image files, or directories to search recursively")]
+        paths: Vec<PathBuf>,
+        #[arg(long, value_enum)]
+        by: Property,
+        #[arg(long, value_name = "DIR", help = "Where to make the bin folders")]
+        into: PathBuf,
+        #[arg(
+            long,
+            default_value_t = 5,
+            help = "How many bins to split into, by equal counts (hue always has its seven)"
+        )]
+        bins: usize,
+        #[arg(
+            long,
+            help = "Leave the files where they are and link to them from the bins"
+        )]
+        symlink: bool,
+        #[arg(long, help = "Only print how many images would go in each bin")]
+        dry_run: bool,
+    },
+    /// Crop away uniform borders, e.g. letterboxing
+    #[cfg(feature = "cli")]
+    Trim {
+        input: PathBuf,
+        #[arg(short, long, help = "Output file path")]
+        output: PathBuf,
+        #[arg(
+            long,
+            default_value_t = 0,
+            help = "How far a channel may stray from the border color and still count as border"
+        )]
+        tolerance: u8,
+    },
+    /// Load an image and crop, resize, inspect and save it from a prompt
+    #[cfg(feature = "cli")]
+    Shell { input: PathBuf },
+    /// Split a UI image into its 9-slice regions, written as .qoi files
+    #[cfg(feature = "cli")]
+    Slice {
+        input: PathBuf,
+        #[arg(
+            long,
+            value_name = "N|V,H|T,R,B,L",
+            help = "Border sizes in pixels, in CSS border-image-slice order"
+        )]
+        borders: Borders,
+        #[arg(
+            short,
+            long,
+            help = "Directory to write <name>-<region>.qoi files to, e.g. button-top-left.qoi"
+        )]
+        output: PathBuf,
+        #[arg(
+            long,
+            value_name = "WxH",
+            value_parser = slice::parse_size,
+            help = "Also write <name>-preview.qoi, stretched to this size"
+        )]
+        preview: Option<(u32, u32)>,
+    },
+    /// Pull an image's main colors out as a palette file (.gpl, .act or .png)
+    #[cfg(feature = "cli")]
+    PaletteExtract {
+        input: PathBuf,
+        #[arg(
+            long,
+            default_value_t = 16,
+            value_parser = clap::value_parser!(u16).range(1..=256),
+            help = "Number of colors to keep"
+        )]
+        colors: u16,
+        #[arg(
+            short,
+            long,
+            help = "Palette file to write: .gpl (GIMP), .act (Adobe) or .png"
+        )]
+        output: PathBuf,
+    },
+    /// Print worked pipelines that combine qoi with other tools
+    #[cfg(feature = "cli")]
+    Examples {
+        #[arg(
+            long,
+            help = "Run every example in a scratch directory and report failures"
+        )]
+        check: bool,
+    },
+    /// Write a set of small edge-case QOI, PNG and PPM files, e.g. to seed a fuzzer
+    #[cfg(feature = "cli")]
+    GenCorpus { out_dir: PathBuf },
+    /// Compare our codecs against reference tools (qoiconv, pngcheck) over some images
+    #[cfg(feature = "cli")]
+    Difftest {
+        #[arg(help = "Image files, or directories of them")]
+        paths: Vec<PathBuf>,
+        #[arg(
+            long,
+            default_value = "qoiconv",
+            help = "Reference QOI converter to run"
+        )]
+        qoiconv: PathBuf,
+        #[arg(long, default_value = "pngcheck", help = "PNG validator to run")]
+        pngcheck: PathBuf,
+    },
+    /// Check that an image survives a trip through every pair of writable formats unchanged
+    #[cfg(feature = "cli")]
+    Roundtrip {
+        #[arg(help = "Source image; 16-bit ones are compared at 8 bits")]
+        input: PathBuf,
+    },
+    /// Report QOI files that decode but break the spec or waste space
+    Lint {
+        #[arg(help = ".qoi files, or directories of them")]
+        paths: Vec<PathBuf>,
+        #[arg(
+            long,
+            help = "Rewrite files with findings as clean, canonically encoded QOI"
+        )]
+        fix: bool,
         #[arg(
-            short = 't',
-            long = "target",
-            help = "Target file extension for batch conversion (qoi, ppm, png)"
+            long,
+            requires = "fix",
+            help = "Keep bytes found after the end marker when fixing"
         )]
-        target_extension: Option<String>,
+        keep_trailer: bool,
     },
     /// Create a .qoi or .ppm image from a dimension-prefixed RGBA byte stream stdin
+    #[cfg(feature = "cli")]
     Write {
         output_path: PathBuf,
         #[arg(short, long, default_value_t = false)]
         forever: bool,
         #[arg(short, long, default_value_t = true)]
         numbered: bool,
+        #[arg(
+            long,
+            help = "Report incoming and processing frame rates on stderr every second and on exit"
+        )]
+        stats: bool,
     },
     /// View a dimension-prefixed RGBA byte stream in stdin
+    #[cfg(feature = "cli")]
     View,
 }
 
 impl Command {
     pub fn run(self) -> Result<(), String> {
         match self {
-            Command::Open { file_path } => open(&file_path),
+            #[cfg(feature = "cli")]
+            Command::Open {
+                file_path,
+                term,
+                sort,
+                random,
+                seed,
+                view_options,
+            } => {
+                let sort = if random { SortOrder::Random } else { sort };
+                open(&file_path, term, sort, seed, view_options)
+            }
             Command::Convert {
                 files,
                 output,
-                target_extension,
-            } => convert(&files, output.as_ref(), target_extension.as_ref()),
+                batch_options,
+                timings,
+                input_options,
+                encode_options,
+            } => convert(
+                &files,
+                output.as_ref(),
+                &batch_options,
+                timings,
+                &input_options,
+                &encode_options,
+                Cancel::on_ctrl_c(),
+            ),
+            #[cfg(feature = "cli")]
+            Command::Concat {
+                files,
+                output,
+                to,
+                palette,
+                fps,
+            } => concat(&files, &output, to.as_deref(), palette, fps),
+            #[cfg(feature = "cli")]
+            Command::Reduce { files, op, output } => {
+                let img = reduce::reduce(&files, op, load_image)?;
+                save_image(img, &output)
+            }
+            #[cfg(feature = "cli")]
+            Command::Motion {
+                files,
+                output,
+                write,
+                threshold,
+                learning_rate,
+            } => motion(
+                &files,
+                &output,
+                write,
+                threshold,
+                learning_rate,
+                Cancel::on_ctrl_c(),
+            ),
+            #[cfg(feature = "cli")]
+            Command::ExportVideo {
+                mut files,
+                output,
+                fps,
+                ffmpeg,
+            } => export_video(&mut files, &output, fps, &ffmpeg, Cancel::on_ctrl_c()),
+            #[cfg(feature = "cli")]
+            Command::ImportVideo {
+                input,
+                output,
+                fps,
+                ffmpeg,
+            } => import_video(&input, &output, fps, &ffmpeg, Cancel::on_ctrl_c()),
+            #[cfg(feature = "cli")]
+            Command::Trim {
+                input,
+                output,
+                tolerance,
+            } => {
+                let img = trim(load_image(&input)?, tolerance, &input)?;
+                save_image(img, &output)
+            }
+            #[cfg(feature = "cli")]
+            Command::Shell { input } => shell::run(&input, load_image(&input)?, save_image),
+            #[cfg(feature = "cli")]
+            Command::Slice {
+                input,
+                borders,
+                output,
+                preview,
+            } => nine_slice(&input, &borders, &output, preview),
+            #[cfg(feature = "cli")]
+            Command::PaletteExtract {
+                input,
+                colors,
+                output,
+            } => palette_extract(&input, colors as usize, &output),
+            #[cfg(feature = "cli")]
+            Command::Examples { check: false } => {
+                examples::print();
+                Ok(())
+            }
+            #[cfg(feature = "cli")]
+            Command::Examples { check: true } => match examples::check()? {
+                0 => Ok(()),
+                failed => Err(format!("{} example(s) failed", failed)),
+            },
+            #[cfg(feature = "cli")]
+            Command::GenCorpus { out_dir } => gen_corpus(&out_dir),
+            #[cfg(feature = "cli")]
+            Command::Difftest {
+                paths,
+                qoiconv,
+                pngcheck,
+            } => difftest(&paths, &qoiconv, &pngcheck),
+            Command::Info { paths } => info(&paths),
+            #[cfg(feature = "cli")]
+            Command::Audit { paths, out } => audit(&paths, &out, Cancel::on_ctrl_c()),
+            #[cfg(feature = "cli")]
+            Command::Phash { paths } => phash(&paths),
+            #[cfg(feature = "cli")]
+            Command::Sort {
+                paths,
+                by,
+                into,
+                bins,
+                symlink,
+                dry_run,
+            } => sort_into_bins(
+                &paths,
+                by,
+                &into,
+                bins,
+                symlink,
+                dry_run,
+                Cancel::on_ctrl_c(),
+            ),
+            #[cfg(feature = "cli")]
+            Command::Meta { action } => meta(action),
+            #[cfg(feature = "cli")]
+            Command::Dedupe {
+                paths,
+                near,
+                link,
+                move_to,
+            } => dedupe(&paths, near, link, move_to.as_deref(), Cancel::on_ctrl_c()),
+            #[cfg(feature = "cli")]
+            Command::Roundtrip { input } => roundtrip(&input),
+            Command::Lint {
+                paths,
+                fix,
+                keep_trailer,
+            } => lint(&paths, fix, keep_trailer),
+            #[cfg(feature = "cli")]
             Command::Write {
                 output_path,
                 forever,
                 numbered,
-            } => write(forever, numbered, &output_path),
+                stats,
+            } => write(forever, numbered, stats, &output_path, Cancel::on_ctrl_c()),
+            #[cfg(feature = "cli")]
             Command::View => view(),
         }
     }
 }
 
-fn open(file_path: &PathBuf) -> Result<(), String> {
-    let img_result = fs::read(&file_path);
-    if let Err(e) = img_result {
-        return Err(e.to_string());
+///The file extension as a str, or "" if it is missing or not valid UTF-8. None of the
+///formats we know have non-UTF-8 extensions, so that case only ever means "unsupported".
+fn extension_str(path: &Path) -> &str {
+    path.extension().and_then(OsStr::to_str).unwrap_or("")
+}
+
+///Decode an image file, picking the format from its extension
+#[tracing::instrument(skip_all, fields(path = %file_path.display()))]
+fn load_image(file_path: &Path) -> Result<RawImage, String> {
+    let data = fs::read(file_path).map_err(|e| e.to_string())?;
+    decode_image(file_path, data)
+}
+
+///Decode a file's contents, picking the format from the file's extension
+fn decode_image(file_path: &Path, data: Vec<u8>) -> Result<RawImage, String> {
+    decode_as(extension_str(file_path), data)
+}
+
+///Decode data in the format that files with this extension have
+fn decode_as(extension: &str, data: Vec<u8>) -> Result<RawImage, String> {
+    let format = format::lookup(extension).ok_or_else(|| {
+        format!(
+            "Invalid file extension provided. Only {} are supported",
+            format::extension_list("and")
+        )
+    })?;
+    format.decode(data)
+}
+
+fn is_supported_image(path: &Path) -> bool {
+    format::lookup(extension_str(path)).is_some()
+}
+
+///Where `convert` reads from, for pipes and files whose extension doesn't say
+#[derive(Debug, Clone, Default, clap::Args)]
+pub struct InputOptions {
+    #[arg(
+        long,
+        value_name = "EXT",
+        help = "Read the input as this format (e.g. png), for stdin (-) or a missing or misleading extension"
+    )]
+    from: Option<String>,
+    #[arg(
+        long,
+        value_parser = parse_byte_size,
+        help = "How big stdin is expected to be (e.g. 2GB), to allocate once and show read progress"
+    )]
+    stdin_size: Option<usize>,
+    #[arg(
+        long,
+        help = "Reject QOI input with a missing end marker, a run past the last pixel or data after it, instead of decoding what's there"
+    )]
+    strict: bool,
+}
+
+impl InputOptions {
+    ///The extension of the format `path` holds
+    fn extension<'a>(&'a self, path: &'a Path) -> &'a str {
+        self.from.as_deref().unwrap_or(extension_str(path))
     }
+}
 
-    let img: RawImage;
-    if file_path.extension().unwrap_or_default() == "qoi" {
-        img = qoi::parse_img(img_result.unwrap().into_iter());
-    } else if file_path.extension().unwrap_or_default() == "ppm" {
-        img = ppm::parse_img(img_result.unwrap().into_iter());
-    } else if file_path.extension().unwrap_or_default() == "png" {
-        img = png::parse_img(img_result.unwrap().into_iter());
-    } else {
-        return Err(
-            "Invalid file extension provided. Only .ppm, .qoi, and .png are supported".into(),
-        );
+///Where `convert` writes when given many files
+#[derive(Debug, Clone, Default, clap::Args)]
+pub struct BatchOptions {
+    #[arg(
+        short = 't',
+        long = "target",
+        help = "Target file extension for batch conversion (qoi, ppm, png)"
+    )]
+    target_extension: Option<String>,
+    // clap turns a literal {n} in help into a line break, so the help can't show it
+    #[arg(
+        long,
+        value_name = "TEMPLATE",
+        conflicts_with = "output",
+        help = "Name batch outputs from a template of the fields name, n (position in the batch), w, h, date and time in braces, e.g. '{date}_{w}x{h}_{name}'; dates come from EXIF, PNG tIME or the file's modification time"
+    )]
+    rename: Option<String>,
+    #[arg(
+        short,
+        long,
+        help = "How many files to convert at once (default: one per CPU core)"
+    )]
+    jobs: Option<NonZeroUsize>,
+}
+
+///Output settings shared by the commands that write image files
+#[derive(Debug, Clone, Default, clap::Args)]
+pub struct EncodeOptions {
+    #[arg(
+        long,
+        value_parser = parse_byte_size,
+        help = "Make .qoi output fit in this many bytes (e.g. 100KB) by posterizing and downscaling"
+    )]
+    target_size: Option<usize>,
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = PngPalette::Off,
+        help = "Write palette-based PNGs: auto (when <= 256 colors and smaller), force, or off"
+    )]
+    png_palette: PngPalette,
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = QoiChannels::Format,
+        help = "Channels a .qoi header declares: format (3 unless the pixels have alpha), auto (3 if all opaque), rgb or rgba"
+    )]
+    qoi_channels: QoiChannels,
+    #[arg(
+        long,
+        help = "Guarantee nothing but pixels and dimensions reaches the output, listing what was dropped"
+    )]
+    strip: bool,
+    #[arg(
+        long,
+        value_name = "WxH+X+Y[,blur|fill=#000]",
+        help = "Black out (or blur) a region before encoding; can be given several times"
+    )]
+    redact: Vec<Redaction>,
+    #[arg(
+        long,
+        value_name = "EXPR",
+        help = "Per-pixel expression run before encoding, e.g. 'out = vec4(in.b, in.g, in.r, in.a)'; can be given several times"
+    )]
+    filter: Vec<Filter>,
+    #[arg(
+        long,
+        value_name = "TOLERANCE",
+        num_args = 0..=1,
+        default_missing_value = "0",
+        help = "Crop away borders the color of the top-left pixel, give or take TOLERANCE per channel"
+    )]
+    trim: Option<u8>,
+    #[arg(
+        long,
+        value_name = "pow2|multiple:N",
+        help = "Grow the canvas to a power of two or a multiple of N, e.g. for GPU textures"
+    )]
+    pad_to: Option<PadTo>,
+    #[arg(
+        long,
+        value_name = "edge|transparent|#rrggbb",
+        default_value = "transparent",
+        help = "What --pad-to fills the new space with; edge repeats the outermost pixels"
+    )]
+    pad_fill: PadFill,
+    #[arg(
+        long,
+        help = "Write .ppm as plain-text P3, one pixel per line, to read or diff pixel values"
+    )]
+    plain: bool,
+    #[arg(
+        long,
+        help = "Dither 16-bit input (e.g. 16-bit PNG scans) down to 8 bits instead of rounding, to avoid banding"
+    )]
+    dither: bool,
+    #[arg(
+        long,
+        help = "Convert PNG or QOI input that declares another gamma (e.g. linear) to sRGB, and label the output sRGB"
+    )]
+    to_srgb: bool,
+}
+
+impl EncodeOptions {
+    ///Whether the output is plain enough for a format-to-format transcode that skips RawImage
+    fn is_plain(&self) -> bool {
+        self.target_size.is_none()
+            && !self.to_srgb
+            && self.png_palette == PngPalette::Off
+            && self.redact.is_empty()
+            && self.filter.is_empty()
+            && self.trim.is_none()
+            && self.pad_to.is_none()
+    }
+}
+
+#[derive(Debug, Subcommand)]
+pub enum MetaAction {
+    /// Print every text entry as `key: value`, or only the value of KEY
+    Get { file: PathBuf, key: Option<String> },
+    /// Set KEY to VALUE, replacing any entries KEY already has
+    Set {
+        file: PathBuf,
+        key: String,
+        value: String,
+    },
+    /// Remove every entry for KEY
+    Remove { file: PathBuf, key: String },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum SortOrder {
+    Name,
+    Mtime,
+    Size,
+    Random,
+}
+
+///List the images in a directory. Only names are read up front; file metadata is only
+///fetched when sorting by mtime or size, so huge directories list quickly.
+///Ties (and the input to a shuffle) are broken by name, so the result never depends
+///on the order the filesystem happens to enumerate entries in.
+fn list_images(dir: &Path, sort: SortOrder, seed: Option<u64>) -> Result<Vec<PathBuf>, String> {
+    let mut files: Vec<PathBuf> = fs::read_dir(dir)
+        .map_err(|e| e.to_string())?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| is_supported_image(path))
+        .collect();
+
+    files.sort();
+    match sort {
+        SortOrder::Name => {}
+        SortOrder::Mtime => {
+            files.sort_by_cached_key(|path| fs::metadata(path).and_then(|m| m.modified()).ok())
+        }
+        SortOrder::Size => {
+            files.sort_by_cached_key(|path| fs::metadata(path).map(|m| m.len()).ok())
+        }
+        SortOrder::Random => {
+            let seed = seed.unwrap_or_else(|| {
+                let seed = std::time::SystemTime::now()
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .map(|d| d.as_nanos() as u64)
+                    .unwrap_or_default();
+                eprintln!("Shuffling with --seed {}", seed);
+                seed
+            });
+            shuffle(&mut files, seed);
+        }
+    }
+    Ok(files)
+}
+
+///Fisher-Yates with xorshift64*, which is plenty for picking a viewing order
+fn shuffle<T>(items: &mut [T], seed: u64) {
+    let mut state = seed | 1;
+    for i in (1..items.len()).rev() {
+        state ^= state >> 12;
+        state ^= state << 25;
+        state ^= state >> 27;
+        let j = (state.wrapping_mul(0x2545_F491_4F6C_DD1D) % (i as u64 + 1)) as usize;
+        items.swap(i, j);
     }
+}
 
-    display(img, file_path.to_str().unwrap());
+fn open(
+    file_path: &Path,
+    term: bool,
+    sort: SortOrder,
+    seed: Option<u64>,
+    view_options: ViewOptions,
+) -> Result<(), String> {
+    if !term {
+        return open_window(file_path, sort, seed, view_options);
+    }
+    if file_path.is_dir() {
+        return Err("--term previews a single file, not a directory".into());
+    }
+    let img = load_image(file_path)?;
+    print!("{}", term::render(&img, term::columns()));
     Ok(())
 }
 
-fn display(img: RawImage, title: &str) {
-    let RawImage(width, height, pixel_buf) = img;
-    let (mut gfx, event_loop) = gfx::Gfx::new(width, height, title);
+///Why `open` and `view` fail in a build without the gui feature
+#[cfg(not(feature = "gui"))]
+const NO_VIEWER: &str = "compiled without viewer support (rebuild with the gui feature, or preview in the terminal with open --term)";
+
+#[cfg(not(feature = "gui"))]
+fn open_window(_: &Path, _: SortOrder, _: Option<u64>, _: ViewOptions) -> Result<(), String> {
+    Err(NO_VIEWER.into())
+}
+
+///Show a file in a window, playing animated GIFs, or step through a directory of them
+#[cfg(feature = "gui")]
+fn open_window(
+    file_path: &Path,
+    sort: SortOrder,
+    seed: Option<u64>,
+    view_options: ViewOptions,
+) -> Result<(), String> {
+    if file_path.is_dir() {
+        let files = list_images(file_path, sort, seed)?;
+        if files.is_empty() {
+            return Err(format!(
+                "No {} images in that directory",
+                format::extension_list("or")
+            ));
+        }
+        return browse(files, view_options);
+    }
+
+    if extension_str(file_path) == "gif" {
+        let data = fs::read(file_path).map_err(|e| e.to_string())?;
+        let mut frames = gif::parse_frames(&data, usize::MAX)?;
+        let title = file_path.to_string_lossy();
+        return match frames.len() {
+            0 => Err("GIF has no frames".into()),
+            1 => display(frames.remove(0).0, &title, view_options),
+            _ => play(frames, &title, view_options),
+        };
+    }
+
+    display(
+        load_image(file_path)?,
+        &file_path.to_string_lossy(),
+        view_options,
+    )
+}
+
+///What happened to a file during a browse session
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Triage {
+    Rejected,
+    Trashed,
+}
+
+///Move a file into a `rejected` folder next to it, returning its new path
+fn reject(path: &Path) -> Result<PathBuf, String> {
+    move_into(
+        path,
+        &path.parent().unwrap_or(Path::new(".")).join("rejected"),
+    )
+}
+
+///Move a file into `dir`, creating it if needed, without replacing anything already there
+fn move_into(path: &Path, dir: &Path) -> Result<PathBuf, String> {
+    fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+    let target = dir.join(path.file_name().ok_or("No file name")?);
+    if target.exists() {
+        return Err(format!("{} already exists", target.display()));
+    }
+    fs::rename(path, &target).map_err(|e| e.to_string())?;
+    Ok(target)
+}
+
+///Step through `files` in a window: Right/Space/PageDown for the next image, Left/Backspace/
+///PageUp for the previous one, Home/End to jump to either end, Escape or Q to quit.
+///R (or Delete) moves the current file into a `rejected` folder beside it and T sends it to
+///the system trash; nothing is deleted outright. What was moved is listed on exit.
+#[cfg(feature = "gui")]
+fn browse(mut files: Vec<PathBuf>, view_options: ViewOptions) -> Result<(), String> {
+    let title = |index: usize, files: &[PathBuf]| {
+        format!(
+            "[{}/{}] {}",
+            index + 1,
+            files.len(),
+            files[index].to_string_lossy()
+        )
+    };
+
+    let mut index = 0;
+    let mut triaged: Vec<(PathBuf, Triage)> = vec![];
+    let img = load_image(&files[index])?;
+    let (mut gfx, event_loop) = gfx::Gfx::new(img.0, img.1, &title(index, &files), view_options)?;
+    gfx.display(&img.convert(PixelFormat::Rgba8).2);
+    gfx.render();
+
+    event_loop.run(move |event, _, control_flow| {
+        *control_flow = ControlFlow::Wait;
+
+        match event {
+            Event::WindowEvent {
+                event: WindowEvent::CloseRequested,
+                ..
+            } => *control_flow = ControlFlow::Exit,
+            Event::WindowEvent {
+                event:
+                    WindowEvent::KeyboardInput {
+                        input:
+                            KeyboardInput {
+                                state: ElementState::Pressed,
+                                virtual_keycode: Some(key),
+                                ..
+                            },
+                        ..
+                    },
+                ..
+            } => {
+                let last = files.len() - 1;
+                index = match key {
+                    VirtualKeyCode::Right | VirtualKeyCode::Space | VirtualKeyCode::PageDown => {
+                        if index == last {
+                            0
+                        } else {
+                            index + 1
+                        }
+                    }
+                    VirtualKeyCode::Left | VirtualKeyCode::Back | VirtualKeyCode::PageUp => {
+                        if index == 0 { last } else { index - 1 }
+                    }
+                    VirtualKeyCode::Home => 0,
+                    VirtualKeyCode::End => last,
+                    VirtualKeyCode::R | VirtualKeyCode::Delete | VirtualKeyCode::T => {
+                        let action = if key == VirtualKeyCode::T {
+                            Triage::Trashed
+                        } else {
+                            Triage::Rejected
+                        };
+                        let result = match action {
+                            Triage::Rejected => reject(&files[index]).map(|_| ()),
+                            Triage::Trashed => {
+                                trash::delete(&files[index]).map_err(|e| e.to_string())
+                            }
+                        };
+                        if let Err(e) = result {
+                            eprintln!("{}: {}", files[index].display(), e);
+                            return;
+                        }
+                        triaged.push((files.remove(index), action));
+                        if files.is_empty() {
+                            *control_flow = ControlFlow::Exit;
+                            return;
+                        }
+                        // The next file slid into this slot
+                        if index == files.len() { 0 } else { index }
+                    }
+                    VirtualKeyCode::Escape | VirtualKeyCode::Q => {
+                        *control_flow = ControlFlow::Exit;
+                        return;
+                    }
+                    _ => return,
+                };
+                match load_image(&files[index]).and_then(|img| {
+                    gfx.resize(img.0, img.1)?;
+                    Ok(img)
+                }) {
+                    Ok(img) => {
+                        gfx.set_title(&title(index, &files));
+                        gfx.display(&img.convert(PixelFormat::Rgba8).2);
+                        gfx.request_redraw();
+                    }
+                    Err(e) => eprintln!("{}: {}", files[index].display(), e),
+                }
+            }
+            Event::RedrawRequested(_) => gfx.render(),
+            Event::LoopDestroyed => {
+                cli::finish_trace();
+                if triaged.is_empty() {
+                    return;
+                }
+                for (path, action) in &triaged {
+                    let verb = match action {
+                        Triage::Rejected => "rejected",
+                        Triage::Trashed => "trashed",
+                    };
+                    let path = term::label(&path.to_string_lossy(), usize::MAX);
+                    println!("{}: {}", verb, path);
+                }
+                let rejected = triaged
+                    .iter()
+                    .filter(|(_, a)| *a == Triage::Rejected)
+                    .count();
+                println!(
+                    "{} moved to rejected/, {} sent to trash",
+                    rejected,
+                    triaged.len() - rejected
+                );
+            }
+            _ => {}
+        }
+    });
+}
+
+///Show the image in a window, or describe it (and preview it, if stdout is a terminal)
+///when no window can be opened.
+#[cfg(feature = "gui")]
+fn display(img: RawImage, title: &str, view_options: ViewOptions) -> Result<(), String> {
+    let (mut gfx, event_loop) = match gfx::Gfx::new(img.0, img.1, title, view_options) {
+        Ok(window) => window,
+        Err(e) => {
+            eprintln!("Could not open a window: {}", e);
+            let title = term::label(title, usize::MAX);
+            println!("{}: {}x{} {:?}", title, img.0, img.1, img.format());
+            if io::stdout().is_terminal() {
+                print!("{}", term::render(&img, term::columns()));
+            } else {
+                eprintln!("Use `qoi open --term` to preview in a terminal");
+            }
+            return Ok(());
+        }
+    };
+    let RawImage(_, _, pixel_buf, ..) = img.convert(PixelFormat::Rgba8);
     gfx.display(&pixel_buf);
     gfx.render();
     event_loop.run(move |event, _, control_flow| {
         *control_flow = ControlFlow::Wait;
 
-        if let Event::WindowEvent {
-            event: WindowEvent::CloseRequested,
-            ..
-        } = event
-        {
-            *control_flow = ControlFlow::Exit;
+        match event {
+            Event::WindowEvent {
+                event: WindowEvent::CloseRequested,
+                ..
+            } => *control_flow = ControlFlow::Exit,
+            Event::LoopDestroyed => cli::finish_trace(),
+            _ => {}
+        }
+    });
+}
+
+///Loop RGBA8 animation frames in a window, each shown for its own delay. Space pauses and
+///resumes, Left and Right step one frame (pausing first), Escape or Q quits.
+#[cfg(feature = "gui")]
+fn play(
+    frames: Vec<(RawImage, Duration)>,
+    title: &str,
+    view_options: ViewOptions,
+) -> Result<(), String> {
+    let frame_title = |index: usize, paused: bool| {
+        let state = if paused { ", paused" } else { "" };
+        format!("[{}/{}{}] {}", index + 1, frames.len(), state, title)
+    };
+    let (first, first_delay) = &frames[0];
+    let Ok((mut gfx, event_loop)) = gfx::Gfx::new(
+        first.0,
+        first.1,
+        &frame_title(0, false),
+        view_options.clone(),
+    ) else {
+        // Let display explain and fall back to a still preview
+        return display(first.clone(), title, view_options);
+    };
+    gfx.display(&first.2);
+    gfx.render();
+    let titles: Vec<[String; 2]> = (0..frames.len())
+        .map(|i| [frame_title(i, false), frame_title(i, true)])
+        .collect();
+
+    let mut index = 0;
+    let mut paused = false;
+    let mut next_frame = Instant::now() + *first_delay;
+    event_loop.run(move |event, _, control_flow| {
+        let last = frames.len() - 1;
+        let mut step_to = None;
+        match event {
+            Event::NewEvents(StartCause::ResumeTimeReached { .. }) if !paused => {
+                step_to = Some(if index == last { 0 } else { index + 1 });
+            }
+            Event::WindowEvent {
+                event: WindowEvent::CloseRequested,
+                ..
+            } => *control_flow = ControlFlow::Exit,
+            Event::WindowEvent {
+                event:
+                    WindowEvent::KeyboardInput {
+                        input:
+                            KeyboardInput {
+                                state: ElementState::Pressed,
+                                virtual_keycode: Some(key),
+                                ..
+                            },
+                        ..
+                    },
+                ..
+            } => match key {
+                VirtualKeyCode::Space => {
+                    paused = !paused;
+                    next_frame = Instant::now() + frames[index].1;
+                    gfx.set_title(&titles[index][paused as usize]);
+                }
+                VirtualKeyCode::Right => {
+                    paused = true;
+                    step_to = Some(if index == last { 0 } else { index + 1 });
+                }
+                VirtualKeyCode::Left => {
+                    paused = true;
+                    step_to = Some(if index == 0 { last } else { index - 1 });
+                }
+                VirtualKeyCode::Escape | VirtualKeyCode::Q => *control_flow = ControlFlow::Exit,
+                _ => {}
+            },
+            Event::RedrawRequested(_) => gfx.render(),
+            Event::LoopDestroyed => cli::finish_trace(),
+            _ => {}
+        }
+        if let Some(step_to) = step_to {
+            index = step_to;
+            // Scheduled from the previous deadline so playback doesn't drift
+            next_frame = (next_frame + frames[index].1).max(Instant::now());
+            gfx.set_title(&titles[index][paused as usize]);
+            gfx.display(&frames[index].0.2);
+            gfx.request_redraw();
+        }
+        if *control_flow != ControlFlow::Exit {
+            *control_flow = match paused {
+                true => ControlFlow::Wait,
+                false => ControlFlow::WaitUntil(next_frame),
+            };
         }
     });
 }
@@ -106,44 +1128,127 @@ fn display(img: RawImage, title: &str) {
 fn convert(
     files: &[PathBuf],
     output: Option<&PathBuf>,
-    target_extension: Option<&String>,
+    batch: &BatchOptions,
+    timings: bool,
+    input_options: &InputOptions,
+    encode_options: &EncodeOptions,
+    cancel: Cancel,
 ) -> Result<(), String> {
-    if files.len() < 1 {
+    if files.is_empty() {
         return Err("At least one input file is required".into());
     }
-
-    if files.len() == 1 && output.is_some() {
-        return convert_single(&files[0], output.unwrap());
+    if batch.rename.is_some() && files.len() < 3 {
+        return Err(
+            "--rename names the outputs of a batch (3+ files); use --output for one file".into(),
+        );
     }
 
-    if files.len() == 2 && output.is_some() {
-        return convert_single(&files[0], output.unwrap());
+    if let (1 | 2, Some(output)) = (files.len(), output) {
+        let spent = convert_single(&files[0], output, input_options, encode_options, true)?;
+        if timings {
+            println!("{}", spent);
+        }
+        return Ok(());
     }
 
     if files.len() >= 3 {
         let first_ext = files[0].extension().unwrap_or_default();
-        for file in files.iter() {
-            let ext = file.extension().unwrap_or_default();
-            assert_eq!(
-                ext, first_ext,
-                "All input files must have the same extension"
-            );
+        if files
+            .iter()
+            .any(|file| file.extension().unwrap_or_default() != first_ext)
+        {
+            return Err("All input files must have the same extension".into());
         }
 
-        let target_ext = if let Some(target) = target_extension {
+        let target_ext = if let Some(target) = &batch.target_extension {
             target.as_str()
         } else {
-            match first_ext.to_str().unwrap_or("") {
-                "ppm" => "qoi",
+            match extension_str(&files[0]) {
                 "qoi" => "ppm",
-                "png" => "qoi",
-                _ => "ppm",
+                _ => "qoi",
             }
         };
 
-        for file_path in files {
-            let output_path = file_path.with_extension(target_ext);
-            convert_single(file_path, &output_path)?;
+        // Same order every run, whatever order the shell or caller listed them in
+        let mut files = files.to_vec();
+        files.sort();
+        files.dedup();
+        let outputs = match &batch.rename {
+            Some(template) => renamed_outputs(&files, template, target_ext)?,
+            None => files
+                .iter()
+                .map(|file| file.with_extension(target_ext))
+                .collect(),
+        };
+        let jobs = batch
+            .jobs
+            .or_else(|| std::thread::available_parallelism().ok())
+            .map_or(1, NonZeroUsize::get)
+            .min(files.len());
+        // Workers take the next file until none are left, one fails or Ctrl-C is pressed
+        let next = AtomicUsize::new(0);
+        let done = AtomicUsize::new(0);
+        let total = Mutex::new(Timings::default());
+        let failed: Mutex<Option<String>> = Mutex::new(None);
+        std::thread::scope(|scope| {
+            for _ in 0..jobs {
+                scope.spawn(|| {
+                    while !cancel.requested() && failed.lock().unwrap().is_none() {
+                        let index = next.fetch_add(1, AtomicOrdering::SeqCst);
+                        let Some((file_path, output_path)) =
+                            files.get(index).zip(outputs.get(index))
+                        else {
+                            break;
+                        };
+                        let converted = output_path
+                            .parent()
+                            .map_or(Ok(()), fs::create_dir_all)
+                            .map_err(|e| e.to_string())
+                            .and_then(|_| {
+                                convert_single(
+                                    file_path,
+                                    output_path,
+                                    input_options,
+                                    encode_options,
+                                    jobs == 1,
+                                )
+                            });
+                        match converted {
+                            Ok(spent) => {
+                                if timings {
+                                    println!("{}  {}", spent, file_path.display());
+                                }
+                                *total.lock().unwrap() += spent;
+                                done.fetch_add(1, AtomicOrdering::SeqCst);
+                            }
+                            Err(e) => {
+                                failed.lock().unwrap().get_or_insert(format!(
+                                    "{}: {}",
+                                    file_path.display(),
+                                    e
+                                ));
+                            }
+                        }
+                    }
+                });
+            }
+        });
+        let (total, done) = (total.into_inner().unwrap(), done.into_inner());
+        if let Some(e) = failed.into_inner().unwrap() {
+            return Err(e);
+        }
+        if done < files.len() {
+            if timings {
+                println!("{}  ({} of {} files)", total, done, files.len());
+            }
+            return Err(format!(
+                "Interrupted after converting {} of {} files",
+                done,
+                files.len()
+            ));
+        }
+        if timings {
+            println!("{}  ({} files)", total, files.len());
         }
         return Ok(());
     }
@@ -151,124 +1256,1563 @@ fn convert(
     Err("Invalid arguments: provide either 1-2 files with --output, or 3+ files with same extension".into())
 }
 
-fn convert_single(file_path: &PathBuf, output_path: &PathBuf) -> Result<(), String> {
-    let img_result = fs::read(&file_path);
-    if let Err(e) = img_result {
-        return Err(e.to_string());
+///Where each file of a batch goes under a `--rename` template, worked out before any is
+///written so two inputs can't silently end up at the same name
+fn renamed_outputs(
+    files: &[PathBuf],
+    template: &str,
+    target_ext: &str,
+) -> Result<Vec<PathBuf>, String> {
+    let template = rename::Template::parse(template)?;
+    let mut outputs = vec![];
+    let mut sources: HashMap<PathBuf, &Path> = HashMap::new();
+    for (n, file) in files.iter().enumerate() {
+        let data = fs::read(file).map_err(|e| format!("{}: {}", file.display(), e))?;
+        let extension = extension_str(file);
+        let format = format::lookup(extension).ok_or_else(|| {
+            format!(
+                "{}: not a {} file",
+                file.display(),
+                format::extension_list("or")
+            )
+        })?;
+        let header = format
+            .read_header(&data)
+            .map_err(|e| format!("{}: {}", file.display(), e))?;
+        let taken = rename::taken(file, extension, &data)?;
+        let name = template.render(&rename::Fields {
+            name: &file.file_stem().unwrap_or_default().to_string_lossy(),
+            n: n + 1,
+            count: files.len(),
+            width: header.width,
+            height: header.height,
+            taken: &taken,
+        });
+        let output = file.with_file_name(format!("{}.{}", name, target_ext));
+        if let Some(other) = sources.insert(output.clone(), file) {
+            return Err(format!(
+                "{} and {} would both be written to {}; add {{n}} to the template to tell them apart",
+                other.display(),
+                file.display(),
+                output.display()
+            ));
+        }
+        outputs.push(output);
     }
+    Ok(outputs)
+}
 
-    let input_ext = file_path
-        .extension()
-        .unwrap_or_default()
-        .to_str()
-        .unwrap_or("");
-    let output_ext = output_path
-        .extension()
-        .unwrap_or_default()
-        .to_str()
-        .unwrap_or("");
-
-    let img = match input_ext {
-        "ppm" => ppm::parse_img(img_result.unwrap().into_iter()),
-        "qoi" => qoi::parse_img(img_result.unwrap().into_iter()),
-        "png" => png::parse_img(img_result.unwrap().into_iter()),
-        _ => return Err("Unsupported input format".into()),
+#[tracing::instrument(skip_all, fields(path = %file_path.display()))]
+fn convert_single(
+    file_path: &Path,
+    output_path: &Path,
+    input: &InputOptions,
+    options: &EncodeOptions,
+    show_progress: bool,
+) -> Result<Timings, String> {
+    let mut spent = Timings::default();
+    let from = input.extension(file_path);
+    let expected_len = match file_path == Path::new("-") {
+        true if input.from.is_none() => {
+            return Err(
+                "Reading stdin (-) needs --from to name its format, e.g. --from png".into(),
+            );
+        }
+        true => input.stdin_size.unwrap_or(0),
+        false => fs::metadata(file_path).map_or(0, |m| m.len() as usize),
     };
-
-    let encoded_data = match output_ext {
-        "ppm" => ppm::encode_img(img),
-        "qoi" => qoi::encode_img(img),
-        "png" => png::encode_img(img),
-        _ => return Err("Unsupported output format".into()),
+    let mut progress = Progress::new(file_path, expected_len);
+    if !show_progress {
+        progress.hide();
+    }
+    let data = Timings::time(&mut spent.read, || {
+        read_input(file_path, input.stdin_size, &mut progress)
+    })?;
+    // PNG to PNG keeps what still holds after re-encoding, unless told to strip it
+    let to = extension_str(output_path);
+    let mut carried = match (from, to, options.strip) {
+        ("png", "png", false) => png::carried_chunks(&data)?,
+        _ => vec![],
     };
+    let transfer = source_transfer(from, &data);
+    if options.to_srgb && to == "png" && !options.strip {
+        carried.retain(|chunk| !png::COLOR_CHUNKS.contains(&&chunk.chunk_type().bytes()));
+        carried.push(png::srgb_chunk());
+    }
+    if options.strip {
+        let dropped = metadata(from, &data);
+        if dropped.is_empty() {
+            println!("{}: no metadata to strip", file_path.display());
+        } else {
+            println!("{}: stripped {}", file_path.display(), dropped.join(", "));
+        }
+    }
 
-    fs::write(output_path, encoded_data).map_err(|e| e.to_string())
-}
-
-fn write(forever: bool, numbered: bool, output_path: &PathBuf) -> Result<(), String> {
-    use std::io::{self, Read};
+    // Rows go straight from the QOI decoder into the PNG encoder and out to the file, so
+    // huge images never exist in memory as a whole. All of that counts as encode time.
+    if (from, extension_str(output_path)) == ("qoi", "png") && options.is_plain() && !input.strict {
+        Timings::time(&mut spent.encode, || {
+            cancel::write_with(output_path, |file| {
+                png::transcode_qoi_with_progress(&data, BufWriter::new(file), |row, rows| {
+                    progress.update(row as u64, rows as u64)
+                })
+            })
+        })?;
+        return Ok(spent);
+    }
 
-    let mut input = io::BufReader::new(io::stdin());
-    let extension = output_path
-        .extension()
-        .unwrap_or_default()
-        .to_str()
-        .unwrap();
-
-    let path = output_path.parent().ok_or("No parent directory")?;
-    let stem = output_path
-        .file_stem()
-        .unwrap_or_default()
-        .to_str()
-        .unwrap();
+    progress.stage("decoding");
+    let mut img = Timings::time(&mut spent.decode, || match (from, input.strict) {
+        ("qoi", true) => qoi::decode_strict(&data).map_err(|e| e.to_string()),
+        _ => decode_as(from, data),
+    })?;
+    if options.to_srgb {
+        match &transfer {
+            Some(png::Transfer::Gamma(gamma)) => img = img.gamma_to_srgb(*gamma as f64 / 100_000.0),
+            Some(png::Transfer::Icc(name)) if !name.contains("sRGB") => eprintln!(
+                "{}: ICC profile '{}' can't be applied, so the pixels are left as they are",
+                file_path.display(),
+                name
+            ),
+            _ => {}
+        }
+        img.4 = ColorSpace::Srgb;
+    }
+    if options.dither {
+        img = img.dither_to_rgba8();
+    }
+    for filter in &options.filter {
+        img = filter.apply(img);
+    }
+    for redaction in &options.redact {
+        if !img.redact(redaction) {
+            eprintln!(
+                "{}: redact region {}x{}+{}+{} is outside the {}x{} image",
+                file_path.display(),
+                redaction.width,
+                redaction.height,
+                redaction.x,
+                redaction.y,
+                img.0,
+                img.1
+            );
+        }
+    }
+    if let Some(tolerance) = options.trim {
+        img = trim(img, tolerance, file_path)?;
+    }
+    let original_size = (img.0, img.1);
+    if let Some(pad_to) = options.pad_to {
+        let (width, height) = pad_to.size(img.0, img.1);
+        // An empty image padded to a multiple stays empty
+        if (width, height) != (img.0, img.1) {
+            img = img.pad(width, height, options.pad_fill)?;
+        }
+    }
+    let padded = (img.0, img.1) != original_size;
+    progress.stage("encoding");
+    let encoded_data = Timings::time(&mut spent.encode, || {
+        encode_image(img, output_path, options)
+            .map(|encoded| match carried.is_empty() {
+                true => encoded,
+                false => png::insert_chunks(&encoded, &carried),
+            })
+            .map(|encoded| match padded {
+                true => record_original_size(encoded, output_path, original_size, options.strip),
+                false => encoded,
+            })
+    })?;
+    progress.stage("writing");
+    Timings::time(&mut spent.write, || {
+        cancel::write_file(output_path, encoded_data)
+    })?;
+    Ok(spent)
+}
 
-    let mut n = 0;
+///Read a whole input, `-` meaning stdin. A pipe doesn't say how long it is, so stdin is
+///read in chunks into a buffer that grows as needed, or is sized once from `size_hint`.
+fn read_input(
+    path: &Path,
+    size_hint: Option<usize>,
+    progress: &mut Progress,
+) -> Result<Vec<u8>, String> {
+    if path != Path::new("-") {
+        return fs::read(path).map_err(|e| e.to_string());
+    }
+    let mut data = Vec::with_capacity(size_hint.unwrap_or(0));
+    let mut stdin = io::stdin().lock();
+    let mut chunk = vec![0; 1 << 20];
     loop {
-        n += 1;
-        let mut w_buf = [0u8; 4];
-        let mut h_buf = [0u8; 4];
-        input.read_exact(&mut w_buf);
-        input.read_exact(&mut h_buf);
-        let w = u32::from_be_bytes(w_buf);
-        let h = u32::from_be_bytes(h_buf);
-        let image_size = (w as usize)
-            .checked_mul(h as usize)
-            .and_then(|s| s.checked_mul(4))
-            .ok_or("Image dimensions too large")?;
-        let mut image_data = vec![0u8; image_size];
-        if let Err(e) = input.read_exact(&mut image_data) {
-            return Err(e.to_string());
+        match stdin.read(&mut chunk) {
+            Ok(0) => return Ok(data),
+            Ok(n) => data.extend_from_slice(&chunk[..n]),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(format!("stdin: {}", e)),
         }
+        if let Some(total) = size_hint {
+            progress.update(data.len() as u64, total as u64);
+        }
+    }
+}
 
-        let img = RawImage(w, h, image_data);
-
-        let out_path = if numbered {
-            PathBuf::from(format!(
-                "{}/{}{:0>5}.{}",
-                path.display(),
-                stem,
-                n,
-                extension
-            ))
-        } else {
-            PathBuf::from(format!("{}/{}.{}", path.display(), stem, extension))
-        };
+///How the input's samples relate to light, for the formats that say: PNG's color chunks
+///and QOI's colorspace byte
+fn source_transfer(extension: &str, data: &[u8]) -> Option<png::Transfer> {
+    match extension {
+        "png" => png::transfer(data).ok().flatten(),
+        "qoi" => match qoi::read_header(data).ok()?.colorspace? {
+            ColorSpace::Srgb => Some(png::Transfer::Srgb),
+            ColorSpace::Linear => Some(png::Transfer::Gamma(100_000)),
+        },
+        _ => None,
+    }
+}
 
-        let result = match extension {
-            "qoi" => fs::write(out_path, qoi::encode_img(img)).map_err(|e| e.to_string()),
-            "ppm" => fs::write(out_path, ppm::encode_img(img)).map_err(|e| e.to_string()),
-            "png" => fs::write(out_path, png::encode_img(img)).map_err(|e| e.to_string()),
-            _ => Err("Unsupported output format.".into()),
-        };
+///Describe everything in a file besides pixels and dimensions. Only PNG text, pHYs, tIME
+///and color chunks are carried into converted output, and only into PNG, plus the
+///colorspace into QOI.
+fn metadata(extension: &str, data: &[u8]) -> Vec<String> {
+    match extension {
+        "png" => png::ancillary_chunks(data)
+            .unwrap_or_default()
+            .iter()
+            .map(|chunk| {
+                let kind = chunk.chunk_type().to_string();
+                let keyword = chunk.data().split(|&b| b == 0).next().unwrap_or_default();
+                if matches!(kind.as_str(), "tEXt" | "zTXt" | "iTXt") {
+                    let keyword = term::label(&String::from_utf8_lossy(keyword), 80);
+                    format!("{} '{}' ({} bytes)", kind, keyword, chunk.length())
+                } else {
+                    format!("{} ({} bytes)", kind, chunk.length())
+                }
+            })
+            .collect(),
+        "ppm" | "pgm" => ppm::comments(data)
+            .iter()
+            .map(|comment| format!("comment '{}'", term::label(comment, 80)))
+            .collect(),
+        "qoi" => {
+            let trailer = lint::lint(data).trailer.len();
+            if trailer > 0 {
+                vec![format!("{} bytes after the end marker", trailer)]
+            } else {
+                vec![]
+            }
+        }
+        _ => vec![],
+    }
+}
 
-        if let Err(e) = result {
-            return Err(e);
+///Note the size an image had before --pad-to in the output file, where the format has room
+///for it, so the padding can be cropped off again later
+fn record_original_size(
+    encoded: Vec<u8>,
+    output_path: &Path,
+    (width, height): (u32, u32),
+    strip: bool,
+) -> Vec<u8> {
+    let size = format!("{}x{}", width, height);
+    let note = |why: &str| {
+        println!(
+            "{}: padded from {}, not recorded since {}",
+            output_path.display(),
+            size,
+            why
+        );
+    };
+    if strip {
+        note("--strip is on");
+        return encoded;
+    }
+    match extension_str(output_path) {
+        "png" => png::insert_text(&encoded, "OriginalSize", &size),
+        // A comment right after the magic number, which both netpbm formats allow
+        "ppm" => [
+            &encoded[..2],
+            format!("\n# original-size {}\n", size).as_bytes(),
+            &encoded[2..],
+        ]
+        .concat(),
+        "pam" => [
+            &encoded[..3],
+            format!("# original-size {}\n", size).as_bytes(),
+            &encoded[3..],
+        ]
+        .concat(),
+        ext => {
+            note(&format!(".{} has nowhere to keep it", ext));
+            encoded
         }
+    }
+}
 
-        if !forever {
-            return Ok(());
+///Crop `img` to its content, saying what was cut. Images that are all border are kept as they are.
+fn trim(img: RawImage, tolerance: u8, file_path: &Path) -> Result<RawImage, String> {
+    match img.content_bounds(tolerance) {
+        Some((x, y, width, height)) if (width, height) != (img.0, img.1) => {
+            println!(
+                "{}: trimmed {}x{} to {}x{}+{}+{}",
+                file_path.display(),
+                img.0,
+                img.1,
+                width,
+                height,
+                x,
+                y
+            );
+            img.crop(x, y, width, height)
+        }
+        Some(_) => Ok(img),
+        None => {
+            println!("{}: nothing but border, not trimmed", file_path.display());
+            Ok(img)
         }
     }
 }
 
-fn view() -> Result<(), String> {
-    use std::io::{self, Read};
+///Encode as .qoi in at most `budget` bytes, saying what had to go to get there
+fn encode_within(img: RawImage, output_path: &Path, budget: usize) -> Result<Vec<u8>, String> {
+    if extension_str(output_path) != "qoi" {
+        return Err("--target-size only works for .qoi output".into());
+    }
+    let (encoded, sacrifice) = qoi::encode_within(img, budget)
+        .ok_or_else(|| format!("Nothing fits in {} bytes, not even a 1x1 image", budget))?;
+    println!(
+        "{}: {} bytes, {}",
+        output_path.display(),
+        encoded.len(),
+        sacrifice
+    );
+    Ok(encoded)
+}
 
-    let mut input = io::BufReader::new(io::stdin());
+///Parse sizes like 2048, 100KB, 1.5MB or 64KiB into bytes
+fn parse_byte_size(text: &str) -> Result<usize, String> {
+    let text = text.trim();
+    let split = text
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(text.len());
+    let (number, unit) = text.split_at(split);
+    let number: f64 = number
+        .parse()
+        .map_err(|_| format!("'{}' is not a size like 100KB", text))?;
+    let scale = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 1.0,
+        "k" | "kb" => 1e3,
+        "m" | "mb" => 1e6,
+        "kib" => 1024.0,
+        "mib" => 1024.0 * 1024.0,
+        _ => return Err(format!("Unknown size unit '{}'", unit)),
+    };
+    Ok((number * scale) as usize)
+}
+
+///Encode an image in the format given by the output path's extension
+fn encode_image(
+    img: RawImage,
+    output_path: &Path,
+    options: &EncodeOptions,
+) -> Result<Vec<u8>, String> {
+    if let Some(budget) = options.target_size {
+        return encode_within(img, output_path, budget);
+    }
+    match extension_str(output_path) {
+        "ppm" if options.plain => Ok(ppm::encode_plain(img)),
+        "png" => png::encode_img_with(img, options.png_palette),
+        "qoi" => Ok(qoi::encode_img_with(img, options.qoi_channels)),
+        extension => format::lookup(extension)
+            .ok_or("Unsupported output format")?
+            .encode(img),
+    }
+}
+
+#[tracing::instrument(skip_all, fields(path = %output_path.display()))]
+fn save_image(img: RawImage, output_path: &Path) -> Result<(), String> {
+    let encoded_data = encode_image(img, output_path, &EncodeOptions::default())?;
+    cancel::write_file(output_path, encoded_data)
+}
+
+fn motion(
+    files: &[PathBuf],
+    output: &Path,
+    write: MotionOutput,
+    threshold: u8,
+    learning_rate: f32,
+    cancel: Cancel,
+) -> Result<(), String> {
+    if !(0.0..=1.0).contains(&learning_rate) {
+        return Err("--learning-rate must be between 0 and 1".into());
+    }
+    frame_path(output, 0)?; // Catch a bad pattern before loading anything
+    let first = files.first().ok_or("At least one input file is required")?;
+    let mut background = Background::new(&load_image(first)?, learning_rate, threshold);
+    let (mut moving, mut total) = (0, 0);
+    for (n, file) in files.iter().enumerate() {
+        if cancel.requested() {
+            return Err(format!("Interrupted after {} of {} frames", n, files.len()));
+        }
+        let (img, moved) = load_image(file)
+            .and_then(|img| background.apply(img, write))
+            .map_err(|e| format!("{}: {}", file.display(), e))?;
+        moving += moved;
+        total += Dimensions::new(img.0, img.1)?.pixels();
+        save_image(img, &frame_path(output, n)?)?;
+    }
+    println!(
+        "Wrote {} frames, {:.1}% of pixels moving",
+        files.len(),
+        moving as f64 * 100.0 / total.max(1) as f64
+    );
+    Ok(())
+}
+
+fn concat(
+    files: &[PathBuf],
+    output: &Path,
+    to: Option<&str>,
+    palette: PaletteMode,
+    fps: f32,
+) -> Result<(), String> {
+    let format = to.unwrap_or(extension_str(output));
+    if format != "gif" {
+        return Err(format!("Can't concatenate into '{}', only gif", format));
+    }
+    if files.is_empty() {
+        return Err("At least one input file is required".into());
+    }
+    if fps.is_nan() || fps <= 0.0 {
+        return Err("--fps must be positive".into());
+    }
+    let delay = (100.0 / fps).round().clamp(1.0, u16::MAX as f32) as u16;
+
+    let first = load_image(&files[0])?;
+    let (width, height) = (first.0, first.1);
+
+    // A global palette needs a look at every frame before the first one is written
+    let quantizer = match palette {
+        PaletteMode::PerFrame => None,
+        PaletteMode::Global => {
+            let stride = gif::sample_stride(files.len(), width, height);
+            let mut samples = vec![];
+            for file in files {
+                let RawImage(_, _, rgba, ..) = load_image(file)?.convert(PixelFormat::Rgba8);
+                samples.extend(rgba.chunks_exact(4).step_by(stride).flatten());
+            }
+            Some(gif::train_palette(&samples))
+        }
+    };
+
+    let mut encoder = gif::AnimationEncoder::new(width, height, quantizer, delay)?;
+    encoder.add_frame(first)?;
+    for file in &files[1..] {
+        encoder
+            .add_frame(load_image(file)?)
+            .map_err(|e| format!("{}: {}", file.display(), e))?;
+    }
+    cancel::write_file(output, encoder.finish()?)
+}
+
+///Orders names so that frame2 comes before frame10, unlike a shell glob
+fn natural_cmp(a: &OsStr, b: &OsStr) -> Ordering {
+    let (a, b) = (a.to_string_lossy(), b.to_string_lossy());
+    let (mut a, mut b) = (a.as_bytes(), b.as_bytes());
+    loop {
+        match (a.first(), b.first()) {
+            (None, None) => return Ordering::Equal,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(x), Some(y)) if x.is_ascii_digit() && y.is_ascii_digit() => {
+                let a_len = a.iter().take_while(|c| c.is_ascii_digit()).count();
+                let b_len = b.iter().take_while(|c| c.is_ascii_digit()).count();
+                let a_num = &a[a[..a_len].iter().take_while(|&&c| c == b'0').count()..a_len];
+                let b_num = &b[b[..b_len].iter().take_while(|&&c| c == b'0').count()..b_len];
+                let order = a_num.len().cmp(&b_num.len()).then(a_num.cmp(b_num));
+                if order != Ordering::Equal {
+                    return order;
+                }
+                (a, b) = (&a[a_len..], &b[b_len..]);
+            }
+            (Some(x), Some(y)) if x != y => return x.cmp(y),
+            _ => (a, b) = (&a[1..], &b[1..]),
+        }
+    }
+}
+
+fn export_video(
+    files: &mut [PathBuf],
+    output: &Path,
+    fps: f32,
+    ffmpeg: &Path,
+    cancel: Cancel,
+) -> Result<(), String> {
+    use std::process::{Command, Stdio};
+
+    if files.is_empty() {
+        return Err("At least one input file is required".into());
+    }
+    if fps.is_nan() || fps <= 0.0 {
+        return Err("--fps must be positive".into());
+    }
+    files.sort_by(|a, b| natural_cmp(a.as_os_str(), b.as_os_str()));
+
+    // Frames go in as a stream of PPMs; most codecs want even dimensions and yuv420p
+    let mut command = Command::new(ffmpeg);
+    command
+        .args(["-hide_banner", "-loglevel", "error", "-y"])
+        .args(["-f", "image2pipe", "-c:v", "ppm"])
+        .args(["-framerate", &fps.to_string(), "-i", "-"])
+        .args([
+            "-vf",
+            "pad=ceil(iw/2)*2:ceil(ih/2)*2",
+            "-pix_fmt",
+            "yuv420p",
+        ])
+        .arg(output)
+        .stdin(Stdio::piped());
+    let mut child = spawn_ffmpeg(ffmpeg, &mut command)?;
+
+    let mut stdin = BufWriter::new(child.stdin.take().ok_or("ffmpeg has no stdin")?);
+    let mut size = None;
+    let fed = files.iter().try_for_each(|file| {
+        if cancel.requested() {
+            return Err("Interrupted".into());
+        }
+        let img = load_image(file)?;
+        if *size.get_or_insert((img.0, img.1)) != (img.0, img.1) {
+            return Err(format!(
+                "{}: all frames must be the same size as the first",
+                file.display()
+            ));
+        }
+        stdin
+            .write_all(&ppm::encode_img(img))
+            .map_err(|e| format!("Writing to ffmpeg failed: {}", e))
+    });
+    let fed = fed.and_then(|_| stdin.flush().map_err(|e| e.to_string()));
+    drop(stdin); // Closing the pipe tells ffmpeg the sequence is over
+
+    if let Err(e) = fed {
+        let _ = child.kill();
+        let _ = child.wait();
+        let _ = fs::remove_file(output);
+        return Err(e);
+    }
+    let status = child.wait().map_err(|e| e.to_string())?;
+    if !status.success() {
+        let _ = fs::remove_file(output);
+        return Err(format!("ffmpeg exited with {}", status));
+    }
+    Ok(())
+}
+
+///Substitute a frame number for a printf-style `%d` or `%0Nd` in the file name
+fn frame_path(pattern: &Path, n: usize) -> Result<PathBuf, String> {
+    let name = pattern
+        .file_name()
+        .and_then(OsStr::to_str)
+        .ok_or("Output file name must be valid UTF-8")?;
+    let start = name
+        .find('%')
+        .ok_or("Output needs a %d placeholder, e.g. %05d")?;
+    let end = name[start..]
+        .find('d')
+        .map(|i| start + i)
+        .ok_or("Output needs a %d placeholder, e.g. %05d")?;
+    let width = match &name[start + 1..end] {
+        "" => 0,
+        spec if spec.starts_with('0') => spec.parse().map_err(|_| "Bad %d placeholder")?,
+        _ => return Err("Bad %d placeholder".into()),
+    };
+    let file_name = format!("{}{:0width$}{}", &name[..start], n, &name[end + 1..]);
+    Ok(pattern.with_file_name(file_name))
+}
+
+fn spawn_ffmpeg(
+    ffmpeg: &Path,
+    command: &mut std::process::Command,
+) -> Result<std::process::Child, String> {
+    command.spawn().map_err(|e| match e.kind() {
+        io::ErrorKind::NotFound => format!(
+            "Couldn't find '{}'; install ffmpeg or point --ffmpeg at it",
+            ffmpeg.display()
+        ),
+        _ => e.to_string(),
+    })
+}
+
+fn import_video(
+    input: &Path,
+    output: &Path,
+    fps: Option<f32>,
+    ffmpeg: &Path,
+    cancel: Cancel,
+) -> Result<(), String> {
+    use std::process::{Command, Stdio};
+
+    frame_path(output, 1)?; // Catch a bad pattern before starting ffmpeg
+    if let Some(dir) = output.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+    }
+
+    let mut command = Command::new(ffmpeg);
+    command
+        .args(["-hide_banner", "-loglevel", "error", "-i"])
+        .arg(input);
+    if let Some(fps) = fps {
+        if fps.is_nan() || fps <= 0.0 {
+            return Err("--fps must be positive".into());
+        }
+        command.arg("-vf").arg(format!("fps={}", fps));
+    }
+    command
+        .args(["-f", "image2pipe", "-c:v", "ppm", "-"])
+        .stdout(Stdio::piped());
+    let mut child = spawn_ffmpeg(ffmpeg, &mut command)?;
+
+    let stdout = child.stdout.take().ok_or("ffmpeg has no stdout")?;
+    let mut stream = BufReader::new(stdout)
+        .bytes()
+        .map_while(Result::ok)
+        .peekable();
+    let mut saved = Ok(0);
+    while let Some(img) = ppm::parse_frame(&mut stream) {
+        let n = saved.as_ref().map_or(0, |n| n + 1);
+        saved = frame_path(output, n).and_then(|path| save_image(img, &path).map(|_| n));
+        if saved.is_ok() && cancel.requested() {
+            saved = Err(format!("Interrupted after {} frames", n + 1));
+        }
+        if saved.is_err() {
+            let _ = child.kill();
+            break;
+        }
+    }
+    drop(stream);
+
+    let status = child.wait().map_err(|e| e.to_string())?;
+    let frames = saved?;
+    if !status.success() {
+        return Err(format!("ffmpeg exited with {}", status));
+    }
+    println!("Wrote {} frames", frames);
+    Ok(())
+}
+
+fn nine_slice(
+    input: &Path,
+    borders: &Borders,
+    out_dir: &Path,
+    preview: Option<(u32, u32)>,
+) -> Result<(), String> {
+    let img = load_image(input)?;
+    borders.check(img.0, img.1)?;
+    let stretched = preview
+        .map(|(width, height)| slice::stretch(&img, borders, width, height))
+        .transpose()?;
+    fs::create_dir_all(out_dir).map_err(|e| e.to_string())?;
+    let stem = input.file_stem().unwrap_or_default().to_string_lossy();
+    let write = |img: RawImage, suffix: &str| {
+        let path = out_dir.join(format!("{}-{}.qoi", stem, suffix));
+        println!("{}: {}x{}", path.display(), img.0, img.1);
+        save_image(img, &path)
+    };
+    for (region, x, y, width, height) in borders.regions(img.0, img.1) {
+        if width == 0 || height == 0 {
+            println!("{}: empty, skipped", region);
+            continue;
+        }
+        write(img.clone().crop(x, y, width, height)?, region)?;
+    }
+    if let Some(stretched) = stretched {
+        write(stretched, "preview")?;
+    }
+    Ok(())
+}
+
+fn palette_extract(input: &Path, colors: usize, output: &Path) -> Result<(), String> {
+    let img = load_image(input)?;
+    let colors = palette::extract(img, colors);
+    if colors.is_empty() {
+        return Err("The image is fully transparent, so there are no colors to extract".into());
+    }
+    let encoded = match extension_str(output) {
+        "gpl" => {
+            let name = input.file_stem().unwrap_or_default().to_string_lossy();
+            palette::encode_gpl(&colors, &term::label(&name, 80))
+        }
+        "act" => palette::encode_act(&colors),
+        "png" => png::encode_img(palette::to_image(&colors))?,
+        _ => return Err("Palette output must be .gpl, .act or .png".into()),
+    };
+    fs::write(output, encoded).map_err(|e| e.to_string())?;
+    println!("{}: {} colors", output.display(), colors.len());
+    Ok(())
+}
+
+fn gen_corpus(out_dir: &Path) -> Result<(), String> {
+    fs::create_dir_all(out_dir).map_err(|e| e.to_string())?;
+    let files = corpus::generate();
+    for (name, data) in &files {
+        fs::write(out_dir.join(name), data).map_err(|e| format!("{}: {}", name, e))?;
+    }
+    println!("Wrote {} files to {}", files.len(), out_dir.display());
+    Ok(())
+}
+
+///Replace each directory in `paths` with the images in it, sorted by name
+fn expand_dirs(paths: &[PathBuf]) -> Result<Vec<PathBuf>, String> {
+    let mut files = vec![];
+    for path in paths {
+        if path.is_dir() {
+            files.extend(list_images(path, SortOrder::Name, None)?);
+        } else {
+            files.push(path.clone());
+        }
+    }
+    Ok(files)
+}
+
+///Like [`expand_dirs`], but descending into subdirectories too
+fn expand_dirs_recursive(paths: &[PathBuf], files: &mut Vec<PathBuf>) -> Result<(), String> {
+    for path in paths {
+        if path.is_dir() {
+            files.extend(list_images(path, SortOrder::Name, None)?);
+            let mut dirs: Vec<PathBuf> = fs::read_dir(path)
+                .map_err(|e| format!("{}: {}", path.display(), e))?
+                .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+                .filter(|path| path.is_dir())
+                .collect();
+            dirs.sort();
+            expand_dirs_recursive(&dirs, files)?;
+        } else {
+            files.push(path.clone());
+        }
+    }
+    Ok(())
+}
+
+///One CSV row per image. Files that fail to decode get a row with the error, so the
+///table accounts for every file. Ctrl-C keeps the rows written so far.
+fn audit(paths: &[PathBuf], out: &Path, cancel: Cancel) -> Result<(), String> {
+    let mut files = vec![];
+    expand_dirs_recursive(paths, &mut files)?;
+    let mut done = 0;
+    let mut write_rows = |out: &mut dyn Write| -> Result<(), String> {
+        writeln!(out, "{}", audit::HEADER).map_err(|e| e.to_string())?;
+        for file in &files {
+            if cancel.requested() {
+                break;
+            }
+            let metrics = match format::lookup(extension_str(file)) {
+                Some(format) => fs::read(file)
+                    .map_err(|e| e.to_string())
+                    .and_then(|data| audit::measure(format.as_ref(), data)),
+                None => Err(format!("not a {} file", format::extension_list("or"))),
+            };
+            let row = audit::row(&file.display().to_string(), &metrics);
+            writeln!(out, "{}", row).map_err(|e| e.to_string())?;
+            done += 1;
+        }
+        out.flush().map_err(|e| e.to_string())
+    };
+    match out == Path::new("-") {
+        true => write_rows(&mut io::stdout().lock())?,
+        false => cancel::write_with(out, |file| write_rows(&mut BufWriter::new(file)))?,
+    }
+    match done < files.len() {
+        true => Err(format!(
+            "Interrupted after auditing {} of {} files",
+            done,
+            files.len()
+        )),
+        false => Ok(()),
+    }
+}
+
+///Print groups of duplicate images, first exact then (with `near`) similar ones, then
+///link or move all but the first of each group. Files that don't decode are reported and
+///left out.
+fn dedupe(
+    paths: &[PathBuf],
+    near: Option<u32>,
+    link: bool,
+    move_to: Option<&Path>,
+    cancel: Cancel,
+) -> Result<(), String> {
+    let mut candidates = vec![];
+    expand_dirs_recursive(paths, &mut candidates)?;
+    let (mut files, mut exact, mut perceptual) = (vec![], vec![], vec![]);
+    for (done, file) in candidates.iter().enumerate() {
+        if cancel.requested() {
+            return Err(format!(
+                "Interrupted after hashing {} of {} files, nothing was changed",
+                done,
+                candidates.len()
+            ));
+        }
+        match load_image(file) {
+            Ok(img) => {
+                exact.push(dedupe::pixel_hash(&img));
+                perceptual.push(phash::hash(&img));
+                files.push(file);
+            }
+            Err(e) => println!("{}: {}", file.display(), e),
+        }
+    }
+
+    let exact_groups = dedupe::exact_groups(&exact);
+    let mut skip = vec![false; files.len()];
+    for group in &exact_groups {
+        for &i in &group[1..] {
+            skip[i] = true;
+        }
+    }
+    let near_groups = match near {
+        Some(distance) => dedupe::near_groups(&perceptual, distance, &skip),
+        None => vec![],
+    };
+    let print = |title: &str, groups: &[Vec<usize>]| {
+        for group in groups {
+            println!("{}:", title);
+            for &i in group {
+                println!("  {}", files[i].display());
+            }
+        }
+    };
+    print("Identical pixels", &exact_groups);
+    print("Similar", &near_groups);
+    let duplicates: usize = exact_groups
+        .iter()
+        .chain(&near_groups)
+        .map(|group| group.len() - 1)
+        .sum();
+    println!(
+        "{} groups, {} duplicates in {} images",
+        exact_groups.len() + near_groups.len(),
+        duplicates,
+        files.len()
+    );
+
+    let mut failed = 0;
+    let mut report = |file: &Path, result: Result<(), String>| {
+        if let Err(e) = result {
+            println!("{}: {}", file.display(), e);
+            failed += 1;
+        }
+    };
+    if link {
+        for group in &exact_groups {
+            // A link makes both names the same file, so each one links to the first in
+            // its own format
+            for (n, &i) in group.iter().enumerate() {
+                let same_format = group[..n]
+                    .iter()
+                    .map(|&j| files[j])
+                    .find(|first| extension_str(first) == extension_str(files[i]));
+                if let Some(first) = same_format {
+                    report(files[i], hard_link_over(first, files[i]));
+                }
+            }
+        }
+    }
+    if let Some(dir) = move_to {
+        for group in exact_groups.iter().chain(&near_groups) {
+            for &i in &group[1..] {
+                report(files[i], move_into(files[i], dir).map(|_| ()));
+            }
+        }
+    }
+    match failed {
+        0 => Ok(()),
+        failed => Err(format!("{} duplicates couldn't be linked or moved", failed)),
+    }
+}
+
+fn meta(action: MetaAction) -> Result<(), String> {
+    let (file, key, value) = match action {
+        MetaAction::Get { file, key } => {
+            let data = fs::read(&file).map_err(|e| e.to_string())?;
+            if !data.starts_with(&png::STANDARD_HEADER) {
+                return Err(format!("{}: not a PNG file", file.display()));
+            }
+            let entries = png::text_entries(&data)?;
+            let Some(key) = key else {
+                for (key, value) in entries {
+                    println!("{}: {}", key, value);
+                }
+                return Ok(());
+            };
+            let values: Vec<String> = entries
+                .into_iter()
+                .filter(|(k, _)| *k == key)
+                .map(|(_, value)| value)
+                .collect();
+            if values.is_empty() {
+                return Err(format!("{} has no '{}' entry", file.display(), key));
+            }
+            for value in values {
+                println!("{}", value);
+            }
+            return Ok(());
+        }
+        MetaAction::Set { file, key, value } => (file, key, Some(value)),
+        MetaAction::Remove { file, key } => (file, key, None),
+    };
+    let data = fs::read(&file).map_err(|e| e.to_string())?;
+    let rewritten = png::set_text(&data, &key, value.as_deref())
+        .map_err(|e| format!("{}: {}", file.display(), e))?;
+    cancel::write_file(&file, rewritten)
+}
+
+///Measure every image, then move or link each into a bin folder under `into`. Nothing
+///is touched until all are measured, so Ctrl-C then leaves everything in place. Files
+///that don't decode are reported and stay where they are.
+fn sort_into_bins(
+    paths: &[PathBuf],
+    by: Property,
+    into: &Path,
+    bins: usize,
+    symlink: bool,
+    dry_run: bool,
+    cancel: Cancel,
+) -> Result<(), String> {
+    let mut candidates = vec![];
+    expand_dirs_recursive(paths, &mut candidates)?;
+    // Rerunning over the same tree shouldn't pick up what an earlier run binned. Links
+    // there resolve to elsewhere, so it's the folder the file is in that counts.
+    if let Ok(into) = fs::canonicalize(into) {
+        candidates.retain(|file| {
+            let dir = file.parent().filter(|dir| !dir.as_os_str().is_empty());
+            !fs::canonicalize(dir.unwrap_or(Path::new("."))).is_ok_and(|dir| dir.starts_with(&into))
+        });
+    }
+    let (mut files, mut values) = (vec![], vec![]);
+    for (done, file) in candidates.iter().enumerate() {
+        if cancel.requested() {
+            return Err(format!(
+                "Interrupted after measuring {} of {} files, nothing was moved",
+                done,
+                candidates.len()
+            ));
+        }
+        match load_image(file) {
+            Ok(img) => {
+                values.push(by.measure(&img));
+                files.push(file);
+            }
+            Err(e) => println!("{}: {}", file.display(), e),
+        }
+    }
+
+    let (assigned, names) = by.bin(&values, bins);
+    for (bin, name) in names.iter().enumerate() {
+        let count = assigned.iter().filter(|&&b| b == bin).count();
+        if count > 0 {
+            println!("{}: {} images", into.join(name).display(), count);
+        }
+    }
+    if dry_run {
+        return Ok(());
+    }
+    let mut failed = 0;
+    for (file, &bin) in files.iter().zip(&assigned) {
+        let dir = into.join(&names[bin]);
+        let result = match symlink {
+            true => link_into(file, &dir),
+            false => move_into(file, &dir).map(|_| ()),
+        };
+        if let Err(e) = result {
+            println!("{}: {}", file.display(), e);
+            failed += 1;
+        }
+    }
+    match failed {
+        0 => Ok(()),
+        failed => Err(format!(
+            "{} of {} files couldn't be binned",
+            failed,
+            files.len()
+        )),
+    }
+}
+
+///Make a symlink in `dir` to a file, by its absolute path so the link works from anywhere
+fn link_into(path: &Path, dir: &Path) -> Result<(), String> {
+    fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+    let target = fs::canonicalize(path).map_err(|e| e.to_string())?;
+    let link = dir.join(path.file_name().ok_or("No file name")?);
+    #[cfg(unix)]
+    let linked = std::os::unix::fs::symlink(target, &link);
+    #[cfg(windows)]
+    let linked = std::os::windows::fs::symlink_file(target, &link);
+    linked.map_err(|e| format!("{}: {}", link.display(), e))
+}
+
+///`<16 hex digits>  <path>` per image, like the output of sha256sum
+fn phash(paths: &[PathBuf]) -> Result<(), String> {
+    let mut files = vec![];
+    expand_dirs_recursive(paths, &mut files)?;
+    let mut failed = 0;
+    for file in &files {
+        match load_image(file) {
+            Ok(img) => println!("{:016x}  {}", phash::hash(&img), file.display()),
+            Err(e) => {
+                eprintln!("{}: {}", file.display(), e);
+                failed += 1;
+            }
+        }
+    }
+    match failed {
+        0 => Ok(()),
+        failed => Err(format!(
+            "{} of {} images couldn't be hashed",
+            failed,
+            files.len()
+        )),
+    }
+}
+
+///Replace `path` with a hard link to `original`, all at once so it's never missing
+fn hard_link_over(original: &Path, path: &Path) -> Result<(), String> {
+    let mut name = OsString::from(".");
+    name.push(path.file_name().ok_or("No file name")?);
+    name.push(".link");
+    let temp = path.with_file_name(name);
+    fs::hard_link(original, &temp).map_err(|e| e.to_string())?;
+    fs::rename(&temp, path).map_err(|e| {
+        fs::remove_file(&temp).ok();
+        e.to_string()
+    })
+}
+
+fn info(paths: &[PathBuf]) -> Result<(), String> {
+    let files = expand_dirs(paths)?;
+    let mut failed = 0;
+    for file in &files {
+        let len = match fs::metadata(file) {
+            Ok(metadata) => metadata.len(),
+            Err(e) => {
+                eprintln!("{}: {}", file.display(), e);
+                failed += 1;
+                continue;
+            }
+        };
+        let header = match format::lookup(extension_str(file)) {
+            Some(format) => read_file_header(file, format.as_ref()),
+            None => Err(format!("not a {} file", format::extension_list("or"))),
+        };
+        let Header {
+            width,
+            height,
+            channels,
+            bit_depth,
+            colorspace,
+        } = match header {
+            Ok(header) => header,
+            Err(e) => {
+                eprintln!("{}: {}", file.display(), e);
+                failed += 1;
+                continue;
+            }
+        };
+        let colorspace = match colorspace {
+            Some(ColorSpace::Srgb) => "sRGB",
+            Some(ColorSpace::Linear) => "linear",
+            None => "unspecified colorspace",
+        };
+        let mut line = format!(
+            "{}: {}x{}, {} channels, {}-bit, {}, {} bytes",
+            file.display(),
+            width,
+            height,
+            channels,
+            bit_depth,
+            colorspace,
+            len
+        );
+        if extension_str(file) == "qoi" {
+            let marker = match ends_with(file, &qoi::END_MARKER) {
+                true => qoi::END_MARKER.len() as u64,
+                false => 0,
+            };
+            let chunks = len.saturating_sub(14 + marker);
+            line.push_str(&format!(" ({} bytes of chunks)", chunks));
+        }
+        println!("{}", line);
+    }
+    match failed {
+        0 => Ok(()),
+        failed => Err(format!("{} of {} files failed", failed, files.len())),
+    }
+}
+
+///Enough of the start of a file for the headers of the formats we know, so `info`
+///doesn't read whole images
+const HEADER_PREFIX: u64 = 64 << 10;
+
+///A file's header, read from its first few KiB. Files that keep it further in (a TIFF
+///directory at the end, a JPEG with a big thumbnail) are read in full.
+fn read_file_header(file: &Path, format: &dyn format::ImageFormat) -> Result<Header, String> {
+    let mut prefix = vec![];
+    File::open(file)
+        .and_then(|f| f.take(HEADER_PREFIX).read_to_end(&mut prefix))
+        .map_err(|e| e.to_string())?;
+    match format.read_header(&prefix) {
+        Err(_) if prefix.len() as u64 == HEADER_PREFIX => {
+            format.read_header(&fs::read(file).map_err(|e| e.to_string())?)
+        }
+        header => header,
+    }
+}
+
+///Whether the file's last bytes are `suffix`, without reading the rest
+fn ends_with(file: &Path, suffix: &[u8]) -> bool {
+    let mut tail = vec![0; suffix.len()];
+    File::open(file)
+        .and_then(|mut f| {
+            f.seek(io::SeekFrom::End(-(suffix.len() as i64)))?;
+            f.read_exact(&mut tail)
+        })
+        .is_ok_and(|_| tail == suffix)
+}
+
+fn lint(paths: &[PathBuf], fix: bool, keep_trailer: bool) -> Result<(), String> {
+    let files: Vec<PathBuf> = expand_dirs(paths)?
+        .into_iter()
+        .filter(|path| extension_str(path) == "qoi")
+        .collect();
+    let (mut flagged, mut fixed) = (0, 0);
+    for file in &files {
+        let data = fs::read(file).map_err(|e| format!("{}: {}", file.display(), e))?;
+        let report = lint::lint(&data);
+        if report.findings.is_empty() {
+            continue;
+        }
+        flagged += 1;
+        for finding in &report.findings {
+            println!(
+                "{}:{:#x}: {}",
+                file.display(),
+                finding.offset,
+                finding.message
+            );
+        }
+        if !fix {
+            continue;
+        }
+        let Some(image) = report.image else {
+            println!(
+                "{}: can't be fixed, too little of it is usable",
+                file.display()
+            );
+            continue;
+        };
+        let mut encoded = qoi::encode_img(image);
+        if report.colorspace <= 1 {
+            encoded[13] = report.colorspace;
+        }
+        if keep_trailer {
+            encoded.extend_from_slice(report.trailer);
+        }
+        cancel::write_file(file, encoded).map_err(|e| format!("{}: {}", file.display(), e))?;
+        println!("{}: fixed", file.display());
+        fixed += 1;
+    }
+    println!("{} of {} files have findings", flagged, files.len());
+    if flagged > fixed {
+        return Err(format!("{} files have lint findings", flagged - fixed));
+    }
+    Ok(())
+}
+
+///Whether `tool` can be started at all; its exit status doesn't matter
+fn tool_available(tool: &Path) -> bool {
+    std::process::Command::new(tool)
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .status()
+        .is_ok()
+}
+
+///Run `tool` with `args`, turning a failed exit into its stderr
+fn run_tool(tool: &Path, args: &[&OsStr]) -> Result<(), String> {
+    let output = std::process::Command::new(tool)
+        .args(args)
+        .output()
+        .map_err(|e| format!("{}: {}", tool.display(), e))?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        Err(format!("{} {}", stdout.trim(), stderr.trim()).trim().into())
+    }
+}
+
+///Describe how two images differ, if they do
+fn pixel_divergence(ours: RawImage, reference: RawImage) -> Option<String> {
+    if (ours.0, ours.1) != (reference.0, reference.1) {
+        return Some(format!(
+            "size {}x{} vs reference {}x{}",
+            ours.0, ours.1, reference.0, reference.1
+        ));
+    }
+    let ours = ours.convert(PixelFormat::Rgba8).2;
+    let reference = reference.convert(PixelFormat::Rgba8).2;
+    let (i, (a, b)) = ours
+        .chunks_exact(4)
+        .zip(reference.chunks_exact(4))
+        .enumerate()
+        .find(|(_, (a, b))| a != b)?;
+    Some(format!("pixel {} is {:?}, reference has {:?}", i, a, b))
+}
+
+///Encode then decode, returning the decoded image and the size of the encoding
+fn through(format: &dyn format::ImageFormat, img: RawImage) -> Result<(RawImage, usize), String> {
+    let encoded = format.encode(img)?;
+    let len = encoded.len();
+    Ok((format.decode(encoded)?, len))
+}
+
+///Take the image from each writable format A through each B and back to A, printing a
+///matrix with A down the side, B across the top, and B's size where nothing changed
+fn roundtrip(input: &Path) -> Result<(), String> {
+    let source = load_image(input)?;
+    let expected = source.clone().convert(PixelFormat::Rgba8).2;
+    let mut formats: Vec<(String, Arc<dyn format::ImageFormat>)> = vec![];
+    for extension in format::extensions() {
+        if let Some(format) = format::lookup(&extension).filter(|f| f.can_encode())
+            && !formats.iter().any(|(_, f)| f.name() == format.name())
+        {
+            formats.push((extension, format));
+        }
+    }
+
+    print!("{:6}", "");
+    for (extension, _) in &formats {
+        print!("{:>12}", extension);
+    }
+    println!();
+    let mut failures = vec![];
+    for (outer_ext, outer) in &formats {
+        print!("{:6}", outer_ext);
+        for (inner_ext, inner) in &formats {
+            let route = format!("{} -> {} -> {}", outer_ext, inner_ext, outer_ext);
+            let result = through(outer.as_ref(), source.clone())
+                .and_then(|(img, _)| through(inner.as_ref(), img))
+                .and_then(|(img, size)| through(outer.as_ref(), img).map(|(img, _)| (img, size)));
+            let cell = match result {
+                Ok((img, size)) => {
+                    let rgba = img.convert(PixelFormat::Rgba8).2;
+                    if rgba == expected {
+                        format!("ok {}", size)
+                    } else {
+                        let changed = match rgba.len() == expected.len() {
+                            true => rgba
+                                .chunks_exact(4)
+                                .zip(expected.chunks_exact(4))
+                                .filter(|(a, b)| a != b)
+                                .count()
+                                .to_string(),
+                            false => "the size of".into(),
+                        };
+                        failures.push(format!(
+                            "{}: changed {} of {} pixels",
+                            route,
+                            changed,
+                            expected.len() / 4
+                        ));
+                        "changed".into()
+                    }
+                }
+                Err(e) => {
+                    failures.push(format!("{}: {}", route, e));
+                    "error".into()
+                }
+            };
+            print!("{:>12}", cell);
+        }
+        println!();
+    }
+    for failure in &failures {
+        println!("{}", failure);
+    }
+    match failures.len() {
+        0 => Ok(()),
+        failed => Err(format!(
+            "{} of {} round trips weren't lossless",
+            failed,
+            formats.len() * formats.len()
+        )),
+    }
+}
+
+///Run our decoder and encoders next to reference tools and report where they disagree.
+///.qoi inputs check our decoder against qoiconv's; anything else checks our encoder
+///against a qoiconv encode. Our PNG output is also run through pngcheck.
+fn difftest(paths: &[PathBuf], qoiconv: &Path, pngcheck: &Path) -> Result<(), String> {
+    let files = expand_dirs(paths)?;
+    let have_qoiconv = tool_available(qoiconv);
+    let have_pngcheck = tool_available(pngcheck);
+    if !have_qoiconv && !have_pngcheck {
+        return Err(format!(
+            "Neither '{}' nor '{}' could be run; point --qoiconv/--pngcheck at them",
+            qoiconv.display(),
+            pngcheck.display()
+        ));
+    }
+    for (tool, available) in [(qoiconv, have_qoiconv), (pngcheck, have_pngcheck)] {
+        if !available {
+            eprintln!("Skipping checks that need '{}'", tool.display());
+        }
+    }
+
+    let scratch = env::temp_dir().join(format!("qoi-difftest-{}", std::process::id()));
+    fs::create_dir_all(&scratch).map_err(|e| e.to_string())?;
+    let reference_png = scratch.join("reference.png");
+    let reference_qoi = scratch.join("reference.qoi");
+    let ours_png = scratch.join("ours.png");
+
+    let mut divergences = 0;
+    let mut report = |file: &Path, what: &str, detail: String| {
+        divergences += 1;
+        println!("{}: {}: {}", file.display(), what, detail);
+    };
+    for file in &files {
+        let ours = match load_image(file) {
+            Ok(img) => img,
+            Err(e) => {
+                report(file, "decode", e);
+                continue;
+            }
+        };
+
+        if have_qoiconv {
+            // A .qoi goes through the reference decoder, anything else through its encoder
+            let (reference_path, ours_decoded, ours_encoded) = if extension_str(file) == "qoi" {
+                (&reference_png, ours.clone(), None)
+            } else {
+                let encoded = qoi::encode_img(ours.clone());
+                let decoded = match qoi::decode(&encoded) {
+                    Ok(img) => img,
+                    Err(e) => {
+                        report(file, "qoi roundtrip", e.to_string());
+                        continue;
+                    }
+                };
+                (&reference_qoi, decoded, Some(encoded.len()))
+            };
+            let reference = run_tool(qoiconv, &[file.as_os_str(), reference_path.as_os_str()])
+                .and_then(|_| fs::read(reference_path).map_err(|e| e.to_string()));
+            match reference {
+                Ok(data) => {
+                    if let Some(len) = ours_encoded
+                        && len != data.len()
+                    {
+                        let detail = format!("{} bytes vs reference {}", len, data.len());
+                        report(file, "qoi size", detail);
+                    }
+                    match decode_image(reference_path, data) {
+                        Ok(reference) => {
+                            if let Some(detail) = pixel_divergence(ours_decoded, reference) {
+                                report(file, "pixels", detail);
+                            }
+                        }
+                        Err(e) => report(file, "reference decode", e),
+                    }
+                }
+                Err(e) => report(file, "qoiconv", e),
+            }
+        }
+
+        if have_pngcheck {
+            let checked = png::encode_img(ours)
+                .and_then(|encoded| fs::write(&ours_png, encoded).map_err(|e| e.to_string()))
+                .and_then(|_| run_tool(pngcheck, &[OsStr::new("-q"), ours_png.as_os_str()]));
+            if let Err(e) = checked {
+                report(file, "pngcheck", e);
+            }
+        }
+    }
+    let _ = fs::remove_dir_all(&scratch);
+
+    println!("{} files checked, {} divergences", files.len(), divergences);
+    if divergences > 0 {
+        return Err(format!(
+            "{} divergences from the reference tools",
+            divergences
+        ));
+    }
+    Ok(())
+}
+
+fn write(
+    forever: bool,
+    numbered: bool,
+    stats: bool,
+    output_path: &Path,
+    cancel: Cancel,
+) -> Result<(), String> {
+    use std::io::{self, Read};
+
+    let mut input = io::BufReader::new(io::stdin());
+    let extension = extension_str(output_path);
+    frame_file(output_path, numbered, 0)?; // Catch a bad path before reading anything
+
+    // Reused across frames so a steady stream of same-sized frames doesn't reallocate
+    let mut encoder = qoi::Encoder::new();
+    let mut image_data = vec![];
+    let mut encoded = vec![];
+    let mut frame_stats = FrameStats::new();
+
+    let mut n = 0;
+    let result = loop {
+        if cancel.requested() {
+            break Err(format!("Interrupted after {} frames", n));
+        }
+        n += 1;
+        let _frame = tracing::info_span!("frame", n).entered();
+        let wait_start = Instant::now();
+        let mut w_buf = [0u8; 4];
+        let mut h_buf = [0u8; 4];
+        if input.read_exact(&mut w_buf).is_err() {
+            break Ok(()); // End of stream
+        }
+        input.read_exact(&mut h_buf);
+        let w = u32::from_be_bytes(w_buf);
+        let h = u32::from_be_bytes(h_buf);
+        let size = Dimensions::new(w, h)?;
+        image_data.resize(size.byte_len(PixelFormat::Rgba8), 0);
+        if let Err(e) = input.read_exact(&mut image_data) {
+            break Err(e.to_string());
+        }
+        let waited = wait_start.elapsed();
+        let process_start = Instant::now();
+
+        let img = RawImage(
+            w,
+            h,
+            std::mem::take(&mut image_data),
+            PixelFormat::Rgba8,
+            ColorSpace::Srgb,
+        );
+
+        let out_path = frame_file(output_path, numbered, n)?;
+
+        let result = match extension {
+            "qoi" => {
+                encoder.encode_into(&img, &mut encoded);
+                image_data = img.2; // Hand the buffer back for the next frame
+                cancel::write_file(&out_path, &encoded)
+            }
+            extension => format::lookup(extension)
+                .ok_or_else(|| "Unsupported output format.".to_string())
+                .and_then(|format| format.encode(img))
+                .and_then(|encoded| cancel::write_file(&out_path, encoded)),
+        };
+
+        if result.is_err() {
+            break result;
+        }
+        frame_stats.record(waited, process_start.elapsed());
+        if stats && frame_stats.report_due() {
+            eprintln!("{}", frame_stats);
+        }
+
+        if !forever {
+            break Ok(());
+        }
+    };
+    if stats {
+        eprintln!("{}", frame_stats);
+    }
+    result
+}
+
+///Where `write` puts frame `n`: beside `output_path`, numbered after the stem if asked.
+///Built from OsStr pieces so non-UTF-8 stems and UNC/verbatim parents survive intact.
+fn frame_file(output_path: &Path, numbered: bool, n: usize) -> Result<PathBuf, String> {
+    let dir = output_path.parent().ok_or("No parent directory")?;
+    let mut file_name = OsString::from(output_path.file_stem().unwrap_or_default());
+    if numbered {
+        file_name.push(format!("{:0>5}", n));
+    }
+    file_name.push(".");
+    file_name.push(extension_str(output_path));
+    Ok(dir.join(file_name))
+}
+
+#[cfg(not(feature = "gui"))]
+fn view() -> Result<(), String> {
+    Err(NO_VIEWER.into())
+}
+
+#[cfg(feature = "gui")]
+fn view() -> Result<(), String> {
+    use std::io::{self, Read};
+
+    let mut input = io::BufReader::new(io::stdin());
 
     let mut w_buf = [0u8; 4];
     let mut h_buf = [0u8; 4];
     input.read_exact(&mut w_buf);
     input.read_exact(&mut h_buf);
-    let w = u32::from_be_bytes(w_buf);
-    let h = u32::from_be_bytes(h_buf);
-    let mut image_data = vec![0u8; (w * h * 4) as usize];
+    let size = Dimensions::new(u32::from_be_bytes(w_buf), u32::from_be_bytes(h_buf))?;
+    let mut image_data = vec![0u8; size.byte_len(PixelFormat::Rgba8)];
     if let Err(e) = input.read_exact(&mut image_data) {
         return Err(e.to_string());
     }
 
-    let img = RawImage(w, h, image_data);
+    let img = RawImage(
+        size.width(),
+        size.height(),
+        image_data,
+        PixelFormat::Rgba8,
+        ColorSpace::Srgb,
+    );
 
-    display(img, "Piped image");
-    Ok(())
+    display(img, "Piped image", ViewOptions::default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn extension_str_is_empty_unless_utf8() {
+        assert_eq!(extension_str(Path::new("frame.qoi")), "qoi");
+        assert_eq!(extension_str(Path::new("./dir.d/x.tar.PNG")), "PNG");
+        assert_eq!(extension_str(Path::new("dir.d/frame")), "");
+        assert_eq!(extension_str(Path::new(".qoi")), "");
+        assert_eq!(extension_str(Path::new("")), "");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn extension_str_of_non_utf8_paths() {
+        use std::os::unix::ffi::OsStrExt;
+        let path = |bytes: &[u8]| Path::new(OsStr::from_bytes(bytes)).to_path_buf();
+        assert_eq!(extension_str(&path(b"\xff\xfe.qoi")), "qoi");
+        assert_eq!(extension_str(&path(b"frame.q\xffi")), "");
+    }
+
+    #[test]
+    fn frame_file_keeps_relative_paths_relative() {
+        let frame = |path: &str, numbered, n| frame_file(Path::new(path), numbered, n).unwrap();
+        assert_eq!(frame("frame.qoi", true, 3), Path::new("frame00003.qoi"));
+        assert_eq!(frame("frame.qoi", false, 3), Path::new("frame.qoi"));
+        assert_eq!(
+            frame("../out/f.png", true, 123456),
+            Path::new("../out/f123456.png")
+        );
+        assert!(frame_file(Path::new("/"), true, 1).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn frame_file_keeps_non_utf8_stems() {
+        use std::os::unix::ffi::OsStrExt;
+        let path = Path::new(OsStr::from_bytes(b"out/\xfe\xff.qoi"));
+        let frame = frame_file(path, true, 7).unwrap();
+        assert_eq!(frame.as_os_str().as_bytes(), b"out/\xfe\xff00007.qoi");
+    }
 }
@@ -0,0 +1,170 @@
+//! Small images that poke at the corners of each format, for seeding fuzzers and for
+//! checking other decoders against ours. Files named `invalid-*` are deliberately broken.
+
+use crate::img::{ColorSpace, PixelFormat, RawImage};
+use crate::qoi::{self, END_MARKER};
+use crate::{png, ppm};
+
+///Every corpus file as (file name, contents)
+pub fn generate() -> Vec<(String, Vec<u8>)> {
+    let mut files = vec![];
+    for (name, img) in images() {
+        files.push((format!("{}.qoi", name), qoi::encode_img(img.clone())));
+        let png = png::encode_img(img.clone()).expect("corpus images have pixels");
+        files.push((format!("{}.png", name), png));
+        files.push((format!("{}.ppm", name), ppm::encode_img(img)));
+    }
+    files.extend(handmade_qoi());
+    files.extend(handmade_ppm());
+    files
+}
+
+fn rgba(width: u32, height: u32, pixels: impl IntoIterator<Item = [u8; 4]>) -> RawImage {
+    let data: Vec<u8> = pixels.into_iter().flatten().collect();
+    assert_eq!(data.len(), width as usize * height as usize * 4);
+    RawImage(width, height, data, PixelFormat::Rgba8, ColorSpace::Srgb)
+}
+
+///xorshift32, so the noisy images are the same on every run
+fn noise(len: usize) -> impl Iterator<Item = u8> {
+    let mut state = 0x9E37_79B9u32;
+    (0..len).map(move |_| {
+        state ^= state << 13;
+        state ^= state >> 17;
+        state ^= state << 5;
+        state as u8
+    })
+}
+
+///Images encoded through our own writers, in every format
+fn images() -> Vec<(&'static str, RawImage)> {
+    let black = [0, 0, 0, 255];
+    let clear = [0, 0, 0, 0];
+    // Same hash slot as black: 64 * 3 is a multiple of 64
+    let collides = [64, 0, 0, 255];
+
+    vec![
+        (
+            "1x1-rgb",
+            RawImage(1, 1, vec![255, 0, 0], PixelFormat::Rgb8, ColorSpace::Srgb),
+        ),
+        ("1x1-rgba", rgba(1, 1, [[0, 255, 0, 128]])),
+        // The first pixel hashes to the untouched slot 0, so encoders may emit INDEX 0
+        ("1x1-transparent-black", rgba(1, 1, [clear])),
+        // One literal plus a single full run of 62
+        ("max-run", rgba(63, 1, [black; 63])),
+        // Runs that have to be split across several chunks
+        ("long-run", rgba(200, 3, [[10, 20, 30, 255]; 600])),
+        (
+            "alpha-flips",
+            rgba(
+                16,
+                1,
+                (0..16).map(|i| [90, 90, 90, if i % 2 == 0 { 0 } else { 255 }]),
+            ),
+        ),
+        (
+            "hash-collisions",
+            rgba(
+                16,
+                1,
+                (0..16).map(|i| if i % 2 == 0 { black } else { collides }),
+            ),
+        ),
+        (
+            "gradient",
+            rgba(256, 1, (0..=255).map(|v| [v, v / 2, 255 - v, 255])),
+        ),
+        (
+            "noise-rgba-odd-size",
+            RawImage(
+                17,
+                13,
+                noise(17 * 13 * 4).collect(),
+                PixelFormat::Rgba8,
+                ColorSpace::Srgb,
+            ),
+        ),
+        (
+            "wide",
+            rgba(4096, 1, (0..4096).map(|i| [i as u8, 0, 0, 255])),
+        ),
+        (
+            "tall",
+            rgba(1, 4096, (0..4096).map(|i| [0, i as u8, 0, 255])),
+        ),
+        (
+            "gray",
+            RawImage(
+                8,
+                8,
+                (0..64).map(|v| v * 4).collect(),
+                PixelFormat::Gray8,
+                ColorSpace::Srgb,
+            ),
+        ),
+    ]
+}
+
+fn qoi_file(width: u32, height: u32, channels: u8, body: &[u8]) -> Vec<u8> {
+    let mut out = b"qoif".to_vec();
+    out.extend_from_slice(&width.to_be_bytes());
+    out.extend_from_slice(&height.to_be_bytes());
+    out.extend_from_slice(&[channels, 0]);
+    out.extend_from_slice(body);
+    out.extend_from_slice(&END_MARKER);
+    out
+}
+
+///QOI files written chunk by chunk, for cases our encoder never produces
+fn handmade_qoi() -> Vec<(String, Vec<u8>)> {
+    #[rustfmt::skip]
+    let every_op = [
+        0xFE, 10, 20, 30, // RGB
+        0xFF, 10, 20, 30, 40, // RGBA
+        0b01_111111, // DIFF +1 +1 +1
+        0b10_100000, 0x88, // LUMA dg=0, dr-dg=0, db-dg=0
+        0b00_101000, // INDEX 40
+        0b11_000001, // RUN 2
+    ];
+    let files = [
+        ("every-op", qoi_file(7, 1, 4, &every_op)),
+        ("empty-0x0", qoi_file(0, 0, 4, &[])),
+        // RUN 62 over a 2-pixel image: decoders must stop at the pixel count
+        ("run-past-end", qoi_file(2, 1, 3, &[0b11_111101])),
+        // A 3-channel header over pixels that still carry alpha
+        (
+            "rgb-header-with-alpha",
+            qoi_file(1, 1, 3, &[0xFF, 1, 2, 3, 4]),
+        ),
+        ("invalid-huge-header", qoi_file(u32::MAX, u32::MAX, 4, &[])),
+        (
+            "invalid-truncated",
+            qoi_file(4, 4, 4, &[0xFE, 1, 2])[..17].to_vec(),
+        ),
+        ("invalid-channels", qoi_file(1, 1, 5, &[0xFE, 1, 2, 3])),
+        ("invalid-magic", {
+            let mut file = qoi_file(1, 1, 4, &[0xFE, 1, 2, 3]);
+            file[0] = b'Q';
+            file
+        }),
+    ];
+    files
+        .into_iter()
+        .map(|(name, data)| (format!("{}.qoi", name), data))
+        .collect()
+}
+
+///PPM header variations the spec allows
+fn handmade_ppm() -> Vec<(String, Vec<u8>)> {
+    let pixels = [255, 0, 0, 0, 255, 0];
+    let headers: [(&str, &[u8]); 3] = [
+        ("comments", b"P6\n# made by gen-corpus\n2 # width\n1\n255\n"),
+        ("crlf", b"P6\r\n2 1\r\n255\r\n"),
+        ("tab-separated", b"P6\t2\t1\t255\t"),
+    ];
+    headers
+        .into_iter()
+        .map(|(name, header)| (format!("{}.ppm", name), [header, &pixels].concat()))
+        .collect()
+}
@@ -0,0 +1,62 @@
+//! Finding repeated images: exact duplicates by hashing pixels, so the same image saved
+//! in two formats still matches, and near duplicates by their perceptual hashes (see
+//! [`phash`](crate::phash)), which survive rescaling, recompression and small edits.
+
+use crate::img::RawImage;
+use crate::phash;
+use std::collections::HashMap;
+use std::hash::{DefaultHasher, Hash, Hasher};
+
+///Hash of the dimensions and RGBA8 pixels. `DefaultHasher::new` has fixed keys, so it's
+///stable within a build, which is all grouping needs; it isn't meant to be stored.
+pub fn pixel_hash(img: &RawImage) -> u64 {
+    let mut rgba = vec![];
+    img.rgba8_into(&mut rgba);
+    let mut hasher = DefaultHasher::new();
+    (img.0, img.1).hash(&mut hasher);
+    rgba.hash(&mut hasher);
+    hasher.finish()
+}
+
+///Indices of the items sharing a key, for every key with more than one, in order of
+///first appearance
+pub fn exact_groups(hashes: &[u64]) -> Vec<Vec<usize>> {
+    let mut by_hash: HashMap<u64, Vec<usize>> = HashMap::new();
+    for (i, &hash) in hashes.iter().enumerate() {
+        by_hash.entry(hash).or_default().push(i);
+    }
+    let mut groups: Vec<Vec<usize>> = by_hash.into_values().filter(|g| g.len() > 1).collect();
+    groups.sort();
+    groups
+}
+
+///Items whose perceptual hashes are within `distance` bits of each other, chained: if a is close
+///to b and b to c, all three are in one group even when a and c are further apart.
+///`skip` marks items to leave out, e.g. exact duplicates already reported.
+pub fn near_groups(hashes: &[u64], distance: u32, skip: &[bool]) -> Vec<Vec<usize>> {
+    let mut parent: Vec<usize> = (0..hashes.len()).collect();
+    fn root(parent: &mut [usize], mut i: usize) -> usize {
+        while parent[i] != i {
+            parent[i] = parent[parent[i]];
+            i = parent[i];
+        }
+        i
+    }
+    for i in 0..hashes.len() {
+        for j in i + 1..hashes.len() {
+            if !skip[i] && !skip[j] && phash::distance(hashes[i], hashes[j]) <= distance {
+                let (a, b) = (root(&mut parent, i), root(&mut parent, j));
+                parent[a.max(b)] = a.min(b);
+            }
+        }
+    }
+    let mut groups: HashMap<usize, Vec<usize>> = HashMap::new();
+    for (i, &skipped) in skip.iter().enumerate() {
+        if !skipped {
+            groups.entry(root(&mut parent, i)).or_default().push(i);
+        }
+    }
+    let mut groups: Vec<Vec<usize>> = groups.into_values().filter(|g| g.len() > 1).collect();
+    groups.sort();
+    groups
+}
@@ -0,0 +1,203 @@
+//! Worked pipelines for `examples` and the `--help` of the commands they use. Every
+//! snippet is run by `examples --check`, so they can't drift from what the CLI accepts.
+
+use crate::img::{ColorSpace, PixelFormat, RawImage};
+use crate::{png, qoi};
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+pub struct Example {
+    pub title: &'static str,
+    ///The subcommand whose `--help` lists this example
+    pub command: &'static str,
+    ///Shell lines, run in order with `set -e`
+    pub lines: &'static [&'static str],
+    ///External programs the example calls besides qoi; it is skipped when one is missing
+    pub needs: &'static [&'static str],
+}
+
+pub const EXAMPLES: &[Example] = &[
+    Example {
+        title: "Capture raw frames from another program",
+        command: "write",
+        lines: &[
+            "# Frames are a big-endian u32 width and height, then width * height RGBA pixels",
+            "mkdir -p capture",
+            r"printf '\000\000\000\002\000\000\000\001\377\000\000\377\000\000\377\377' | qoi write capture/frame.qoi",
+            "qoi info capture/frame00001.qoi",
+        ],
+        needs: &[],
+    },
+    Example {
+        title: "Convert a single file, shrinking it to fit a budget",
+        command: "convert",
+        lines: &["qoi convert photo.png -o photo.qoi --target-size 8KB"],
+        needs: &[],
+    },
+    Example {
+        title: "Convert from a pipe, where there's no extension to go by",
+        command: "convert",
+        lines: &[
+            "cat photo.png | qoi convert - --from png -o piped.qoi",
+            "qoi info piped.qoi",
+        ],
+        needs: &[],
+    },
+    Example {
+        title: "Batch convert a directory of screenshots",
+        command: "convert",
+        lines: &["qoi convert shots/*.png -t qoi", "qoi info shots"],
+        needs: &[],
+    },
+    Example {
+        title: "Remove passers-by from a static shot",
+        command: "reduce",
+        lines: &["qoi reduce frames/*.qoi --op median -o clean.png"],
+        needs: &[],
+    },
+    Example {
+        title: "Round-trip an image sequence through a video",
+        command: "export-video",
+        lines: &[
+            "qoi export-video frames/*.qoi -o clip.mp4 --fps 30",
+            "qoi import-video clip.mp4 -o back/%05d.qoi",
+        ],
+        needs: &["ffmpeg"],
+    },
+    Example {
+        title: "Check assets in CI, keeping diffable text copies",
+        command: "lint",
+        lines: &[
+            "qoi lint assets",
+            "qoi convert assets/logo.qoi -o logo.ppm --plain",
+            "grep -c . logo.ppm",
+        ],
+        needs: &[],
+    },
+    Example {
+        title: "Set aside screenshots that repeat, even re-encoded",
+        command: "dedupe",
+        lines: &[
+            "qoi convert shots/a.png -o shots/a-again.qoi",
+            "qoi dedupe shots --near 5 --move-to duplicates",
+            "ls duplicates",
+        ],
+        needs: &[],
+    },
+    Example {
+        title: "Tag a PNG with its author and read it back",
+        command: "meta",
+        lines: &[
+            "qoi meta set photo.png Author \"A. Photographer\"",
+            "qoi meta get photo.png Author",
+        ],
+        needs: &[],
+    },
+    Example {
+        title: "Make sure every writable format gives the image back unchanged",
+        command: "roundtrip",
+        lines: &["qoi roundtrip photo.png"],
+        needs: &[],
+    },
+];
+
+///The examples for one subcommand, formatted for the end of its `--help`
+pub fn help_for(command: &str) -> Option<String> {
+    let mut out = String::new();
+    for example in EXAMPLES.iter().filter(|e| e.command == command) {
+        out.push_str(&format!("\n  {}:\n", example.title));
+        for line in example.lines {
+            out.push_str(&format!("    $ {}\n", line));
+        }
+    }
+    (!out.is_empty()).then(|| format!("Examples:{}", out))
+}
+
+pub fn print() {
+    for example in EXAMPLES {
+        println!("# {}", example.title);
+        for line in example.lines {
+            println!("{}", line);
+        }
+        println!();
+    }
+}
+
+///Run every example in its own scratch directory, seeded with the files they refer to,
+///with `qoi` standing for this executable. Returns how many failed.
+pub fn check() -> Result<usize, String> {
+    let exe = std::env::current_exe().map_err(|e| e.to_string())?;
+    let root = std::env::temp_dir().join(format!("qoi-examples-{}", std::process::id()));
+    let mut failed = 0;
+    for (n, example) in EXAMPLES.iter().enumerate() {
+        if let Some(missing) = example.needs.iter().find(|tool| !on_path(tool)) {
+            println!("SKIP {} ({} not found)", example.title, missing);
+            continue;
+        }
+        let dir = root.join(n.to_string());
+        seed_fixtures(&dir)?;
+        let script = format!(
+            "qoi() {{ \"$QOI_EXE\" \"$@\"; }}\nset -e\n{}\n",
+            example.lines.join("\n")
+        );
+        let output = Command::new("sh")
+            .args(["-c", &script])
+            .env("QOI_EXE", &exe)
+            .current_dir(&dir)
+            .output()
+            .map_err(|e| format!("Couldn't run sh: {}", e))?;
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        if output.status.success() {
+            println!("ok   {}", example.title);
+        } else {
+            failed += 1;
+            println!("FAIL {}", example.title);
+            print!("{}{}", stdout, String::from_utf8_lossy(&output.stderr));
+        }
+    }
+    fs::remove_dir_all(&root).ok();
+    Ok(failed)
+}
+
+fn on_path(tool: &str) -> bool {
+    Command::new("sh")
+        .args(["-c", &format!("command -v {} >/dev/null", tool)])
+        .status()
+        .is_ok_and(|status| status.success())
+}
+
+///The files the examples work on: a photo, a few screenshots, a short sequence with
+///something moving through it, and an assets directory
+fn seed_fixtures(dir: &Path) -> Result<(), String> {
+    let scene = |t: u32| {
+        let (width, height) = (64, 48);
+        let mut rgb = Vec::with_capacity(width as usize * height as usize * 3);
+        for y in 0..height {
+            for x in 0..width {
+                let moving = (x / 8) == t && (16..32).contains(&y);
+                rgb.extend(match moving {
+                    true => [255, 255, 255],
+                    false => [(x * 4) as u8, (y * 5) as u8, 96],
+                });
+            }
+        }
+        RawImage(width, height, rgb, PixelFormat::Rgb8, ColorSpace::Srgb)
+    };
+    let mut files = vec![
+        ("photo.png".to_string(), png::encode_img(scene(0))?),
+        ("shots/a.png".to_string(), png::encode_img(scene(1))?),
+        ("shots/b.png".to_string(), png::encode_img(scene(2))?),
+        ("shots/c.png".to_string(), png::encode_img(scene(3))?),
+        ("assets/logo.qoi".to_string(), qoi::encode_img(scene(4))),
+    ];
+    for t in 1..=5 {
+        files.push((format!("frames/{:05}.qoi", t), qoi::encode_img(scene(t))));
+    }
+    for (name, data) in files {
+        let path = dir.join(name);
+        fs::create_dir_all(path.parent().unwrap_or(dir)).map_err(|e| e.to_string())?;
+        fs::write(&path, data).map_err(|e| format!("{}: {}", path.display(), e))?;
+    }
+    Ok(())
+}
@@ -0,0 +1,57 @@
+//! suckless farbfeld: the magic "farbfeld", width and height as big-endian u32, then
+//! every pixel as 16-bit big-endian RGBA. Images decode to Rgba16, which `convert`
+//! narrows to 8 bits; 8-bit images are widened by repeating each byte (v * 257).
+
+use crate::img::{ColorSpace, Dimensions, Header, PixelFormat, RawImage};
+use crate::stream::{TakeArray, TakeVec};
+
+const MAGIC: [u8; 8] = *b"farbfeld";
+
+///Read just the 16-byte header
+pub fn read_header(data: &[u8]) -> Result<Header, String> {
+    let (width, height) = parse_header(&mut data.iter().copied())?;
+    Ok(Header {
+        width,
+        height,
+        channels: 4,
+        bit_depth: 16,
+        colorspace: None,
+    })
+}
+
+fn parse_header(stream: &mut impl Iterator<Item = u8>) -> Result<(u32, u32), String> {
+    if stream.take_array() != Some(MAGIC) {
+        return Err("not a farbfeld file (bad magic bytes)".into());
+    }
+    let short = || "farbfeld header is shorter than 16 bytes".to_string();
+    let width = u32::from_be_bytes(stream.take_array().ok_or_else(short)?);
+    let height = u32::from_be_bytes(stream.take_array().ok_or_else(short)?);
+    Ok((width, height))
+}
+
+#[tracing::instrument(name = "farbfeld::decode", skip_all)]
+pub fn parse_img(data: impl Iterator<Item = u8>) -> Result<RawImage, String> {
+    let mut stream = data;
+    let (width, height) = parse_header(&mut stream)?;
+    let size = Dimensions::new(width, height).map_err(|e| format!("farbfeld {}", e))?;
+    let pixels = stream
+        .take_vec_exact(size.byte_len(PixelFormat::Rgba16))
+        .ok_or("farbfeld data ends before the last pixel")?;
+    Ok(RawImage(
+        width,
+        height,
+        pixels,
+        PixelFormat::Rgba16,
+        ColorSpace::Srgb,
+    ))
+}
+
+#[tracing::instrument(name = "farbfeld::encode", skip_all, fields(width = img.0, height = img.1))]
+pub fn encode_img(img: RawImage) -> Vec<u8> {
+    let RawImage(width, height, pixels, ..) = img.convert(PixelFormat::Rgba16);
+    let mut out = MAGIC.to_vec();
+    out.extend_from_slice(&width.to_be_bytes());
+    out.extend_from_slice(&height.to_be_bytes());
+    out.extend(pixels);
+    out
+}
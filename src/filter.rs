@@ -0,0 +1,424 @@
+//! Per-pixel expressions for `convert --filter`, e.g. `out = vec4(in.b, in.g, in.r, in.a)`.
+//!
+//! `in` is the source pixel as a vec4 of floats in 0..=1, with channels picked out by
+//! `.r`, `.g`, `.b`, `.a` or reordered by a four-letter swizzle like `.bgra`. `x`, `y`,
+//! `width` and `height` are in pixels. Arithmetic works on numbers and vec4s alike, with
+//! numbers broadcast across all four channels. The functions are vec4, min, max, clamp,
+//! mix, abs, floor, sqrt and pow. The result is clamped to 0..=1 and must be a vec4.
+
+use crate::img::{PixelFormat, RawImage};
+use std::str::FromStr;
+
+///A parsed `--filter` expression
+#[derive(Debug, Clone)]
+pub struct Filter {
+    expr: Expr,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    Number,
+    Vec4,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Var {
+    In,
+    X,
+    Y,
+    Width,
+    Height,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Op {
+    Add,
+    Sub,
+    Mul,
+    Div,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Func {
+    Vec4,
+    Min,
+    Max,
+    Clamp,
+    Mix,
+    Abs,
+    Floor,
+    Sqrt,
+    Pow,
+}
+
+#[derive(Debug, Clone)]
+enum Expr {
+    Number(f32),
+    Var(Var),
+    Neg(Box<Expr>),
+    Binary(Op, Box<Expr>, Box<Expr>),
+    Call(Func, Vec<Expr>),
+    ///One channel (as a number) or a four-channel reorder
+    Swizzle(Box<Expr>, Vec<usize>),
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Value {
+    Number(f32),
+    Vec4([f32; 4]),
+}
+
+///Where the pixel being filtered sits
+struct Pixel {
+    rgba: [f32; 4],
+    x: f32,
+    y: f32,
+    width: f32,
+    height: f32,
+}
+
+impl Value {
+    fn channels(self) -> [f32; 4] {
+        match self {
+            Value::Number(n) => [n; 4],
+            Value::Vec4(v) => v,
+        }
+    }
+
+    ///Apply `f` channel by channel, broadcasting numbers. The result is only a vec4 if an
+    ///argument was.
+    fn zip<const N: usize>(args: [Value; N], f: impl Fn([f32; N]) -> f32) -> Value {
+        if args.iter().all(|arg| matches!(arg, Value::Number(_))) {
+            return Value::Number(f(args.map(|arg| arg.channels()[0])));
+        }
+        let channels = args.map(Value::channels);
+        Value::Vec4(std::array::from_fn(|c| f(channels.map(|v| v[c]))))
+    }
+}
+
+impl Expr {
+    fn eval(&self, pixel: &Pixel) -> Value {
+        match self {
+            Expr::Number(n) => Value::Number(*n),
+            Expr::Var(Var::In) => Value::Vec4(pixel.rgba),
+            Expr::Var(Var::X) => Value::Number(pixel.x),
+            Expr::Var(Var::Y) => Value::Number(pixel.y),
+            Expr::Var(Var::Width) => Value::Number(pixel.width),
+            Expr::Var(Var::Height) => Value::Number(pixel.height),
+            Expr::Neg(expr) => Value::zip([expr.eval(pixel)], |[v]| -v),
+            Expr::Binary(op, lhs, rhs) => {
+                let args = [lhs.eval(pixel), rhs.eval(pixel)];
+                match op {
+                    Op::Add => Value::zip(args, |[a, b]| a + b),
+                    Op::Sub => Value::zip(args, |[a, b]| a - b),
+                    Op::Mul => Value::zip(args, |[a, b]| a * b),
+                    Op::Div => Value::zip(args, |[a, b]| a / b),
+                }
+            }
+            Expr::Call(func, args) => {
+                // No call takes more than four arguments, so skip allocating per pixel
+                let mut values = [Value::Number(0.0); 4];
+                for (value, arg) in values.iter_mut().zip(args) {
+                    *value = arg.eval(pixel);
+                }
+                match (func, &values[..args.len()]) {
+                    (Func::Vec4, &[v]) => Value::Vec4(v.channels()),
+                    (Func::Vec4, &[r, g, b, a]) => {
+                        Value::Vec4([r, g, b, a].map(|v| v.channels()[0]))
+                    }
+                    (Func::Min, &[a, b]) => Value::zip([a, b], |[a, b]| a.min(b)),
+                    (Func::Max, &[a, b]) => Value::zip([a, b], |[a, b]| a.max(b)),
+                    (Func::Clamp, &[v, lo, hi]) => {
+                        Value::zip([v, lo, hi], |[v, lo, hi]| v.max(lo).min(hi))
+                    }
+                    (Func::Mix, &[a, b, t]) => Value::zip([a, b, t], |[a, b, t]| a + (b - a) * t),
+                    (Func::Abs, &[v]) => Value::zip([v], |[v]| v.abs()),
+                    (Func::Floor, &[v]) => Value::zip([v], |[v]| v.floor()),
+                    (Func::Sqrt, &[v]) => Value::zip([v], |[v]| v.sqrt()),
+                    (Func::Pow, &[a, b]) => Value::zip([a, b], |[a, b]| a.powf(b)),
+                    _ => unreachable!("argument counts are checked when parsing"),
+                }
+            }
+            Expr::Swizzle(expr, picks) => {
+                let channels = expr.eval(pixel).channels();
+                match picks[..] {
+                    [c] => Value::Number(channels[c]),
+                    _ => Value::Vec4(std::array::from_fn(|i| channels[picks[i]])),
+                }
+            }
+        }
+    }
+}
+
+impl Filter {
+    ///Run the expression over every pixel. The result is always RGBA8, since the
+    ///expression is free to change alpha.
+    pub fn apply(&self, img: RawImage) -> RawImage {
+        let RawImage(width, height, mut rgba, _, space) = img.convert(PixelFormat::Rgba8);
+        for (i, pixel) in rgba.chunks_exact_mut(4).enumerate() {
+            let input = Pixel {
+                rgba: [0, 1, 2, 3].map(|c| pixel[c] as f32 / 255.0),
+                x: (i % width as usize) as f32,
+                y: (i / width as usize) as f32,
+                width: width as f32,
+                height: height as f32,
+            };
+            let out = self.expr.eval(&input).channels();
+            for (dst, v) in pixel.iter_mut().zip(out) {
+                *dst = (v.clamp(0.0, 1.0) * 255.0).round() as u8;
+            }
+        }
+        RawImage(width, height, rgba, PixelFormat::Rgba8, space)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(f32),
+    Ident(String),
+    Punct(char),
+}
+
+fn tokenize(s: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = vec![];
+    let mut chars = s.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c.is_ascii_digit() || c == '.' && tokens.last().is_none_or(is_operand_start) {
+            let mut number = String::new();
+            while let Some(d) = chars.next_if(|d| d.is_ascii_digit() || *d == '.') {
+                number.push(d);
+            }
+            let value = number
+                .parse()
+                .map_err(|_| format!("'{}' is not a number", number))?;
+            tokens.push(Token::Number(value));
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            let mut ident = String::new();
+            while let Some(d) = chars.next_if(|d| d.is_ascii_alphanumeric() || *d == '_') {
+                ident.push(d);
+            }
+            tokens.push(Token::Ident(ident));
+        } else if "+-*/(),.=".contains(c) {
+            chars.next();
+            tokens.push(Token::Punct(c));
+        } else {
+            return Err(format!("unexpected '{}'", c));
+        }
+    }
+    Ok(tokens)
+}
+
+///Whether a number can start after this token, so `.5` is a number but `in.r` a swizzle
+fn is_operand_start(token: &Token) -> bool {
+    matches!(token, Token::Punct(c) if *c != ')')
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        if self.peek() == Some(&Token::Punct(c)) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), String> {
+        if self.eat(c) {
+            Ok(())
+        } else {
+            Err(format!("expected '{}'", c))
+        }
+    }
+
+    ///expr = term (('+' | '-') term)*
+    fn expr(&mut self) -> Result<(Expr, Kind), String> {
+        let mut lhs = self.term()?;
+        loop {
+            let op = if self.eat('+') {
+                Op::Add
+            } else if self.eat('-') {
+                Op::Sub
+            } else {
+                return Ok(lhs);
+            };
+            lhs = binary(op, lhs, self.term()?);
+        }
+    }
+
+    ///term = unary (('*' | '/') unary)*
+    fn term(&mut self) -> Result<(Expr, Kind), String> {
+        let mut lhs = self.unary()?;
+        loop {
+            let op = if self.eat('*') {
+                Op::Mul
+            } else if self.eat('/') {
+                Op::Div
+            } else {
+                return Ok(lhs);
+            };
+            lhs = binary(op, lhs, self.unary()?);
+        }
+    }
+
+    ///unary = '-' unary | primary ('.' swizzle)*
+    fn unary(&mut self) -> Result<(Expr, Kind), String> {
+        if self.eat('-') {
+            let (expr, kind) = self.unary()?;
+            return Ok((Expr::Neg(Box::new(expr)), kind));
+        }
+        let (mut expr, mut kind) = self.primary()?;
+        while self.eat('.') {
+            let Some(Token::Ident(letters)) = self.peek().cloned() else {
+                return Err("expected channel letters after '.'".into());
+            };
+            self.pos += 1;
+            if kind != Kind::Vec4 {
+                return Err(format!("'.{}' needs a vec4, not a number", letters));
+            }
+            let picks = letters
+                .chars()
+                .map(|c| "rgba".find(c).or_else(|| "xyzw".find(c)))
+                .collect::<Option<Vec<usize>>>()
+                .filter(|picks| picks.len() == 1 || picks.len() == 4)
+                .ok_or_else(|| format!("'.{}' should pick one or four of r, g, b, a", letters))?;
+            kind = if picks.len() == 1 {
+                Kind::Number
+            } else {
+                Kind::Vec4
+            };
+            expr = Expr::Swizzle(Box::new(expr), picks);
+        }
+        Ok((expr, kind))
+    }
+
+    ///primary = number | variable | function '(' args ')' | '(' expr ')'
+    fn primary(&mut self) -> Result<(Expr, Kind), String> {
+        match self.peek().cloned() {
+            Some(Token::Number(n)) => {
+                self.pos += 1;
+                Ok((Expr::Number(n), Kind::Number))
+            }
+            Some(Token::Punct('(')) => {
+                self.pos += 1;
+                let inner = self.expr()?;
+                self.expect(')')?;
+                Ok(inner)
+            }
+            Some(Token::Ident(name)) => {
+                self.pos += 1;
+                if self.eat('(') {
+                    return self.call(&name);
+                }
+                let var = match name.as_str() {
+                    "in" => return Ok((Expr::Var(Var::In), Kind::Vec4)),
+                    "x" => Var::X,
+                    "y" => Var::Y,
+                    "width" => Var::Width,
+                    "height" => Var::Height,
+                    _ => return Err(format!("unknown variable '{}'", name)),
+                };
+                Ok((Expr::Var(var), Kind::Number))
+            }
+            Some(Token::Punct(c)) => Err(format!("unexpected '{}'", c)),
+            None => Err("expression ends too early".into()),
+        }
+    }
+
+    ///The arguments of a call whose name and '(' have been consumed
+    fn call(&mut self, name: &str) -> Result<(Expr, Kind), String> {
+        let mut args = vec![];
+        if !self.eat(')') {
+            loop {
+                args.push(self.expr()?);
+                if self.eat(')') {
+                    break;
+                }
+                self.expect(',')?;
+            }
+        }
+        let (func, arities): (Func, &[usize]) = match name {
+            "vec4" => (Func::Vec4, &[1, 4]),
+            "min" => (Func::Min, &[2]),
+            "max" => (Func::Max, &[2]),
+            "clamp" => (Func::Clamp, &[3]),
+            "mix" => (Func::Mix, &[3]),
+            "abs" => (Func::Abs, &[1]),
+            "floor" => (Func::Floor, &[1]),
+            "sqrt" => (Func::Sqrt, &[1]),
+            "pow" => (Func::Pow, &[2]),
+            _ => return Err(format!("unknown function '{}'", name)),
+        };
+        if !arities.contains(&args.len()) {
+            let arities: Vec<String> = arities.iter().map(usize::to_string).collect();
+            return Err(format!(
+                "{} takes {} arguments, not {}",
+                name,
+                arities.join(" or "),
+                args.len()
+            ));
+        }
+        let kind = match func {
+            Func::Vec4 if args.len() == 4 && args.iter().any(|(_, kind)| *kind == Kind::Vec4) => {
+                return Err("vec4 of four values needs four numbers".into());
+            }
+            Func::Vec4 => Kind::Vec4,
+            _ if args.iter().any(|(_, kind)| *kind == Kind::Vec4) => Kind::Vec4,
+            _ => Kind::Number,
+        };
+        let args = args.into_iter().map(|(expr, _)| expr).collect();
+        Ok((Expr::Call(func, args), kind))
+    }
+}
+
+fn binary(op: Op, (lhs, lhs_kind): (Expr, Kind), (rhs, rhs_kind): (Expr, Kind)) -> (Expr, Kind) {
+    let kind = if lhs_kind == Kind::Vec4 || rhs_kind == Kind::Vec4 {
+        Kind::Vec4
+    } else {
+        Kind::Number
+    };
+    (Expr::Binary(op, Box::new(lhs), Box::new(rhs)), kind)
+}
+
+impl FromStr for Filter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = |reason: String| format!("Invalid filter '{}': {}", s, reason);
+        let mut tokens = tokenize(s).map_err(invalid)?;
+        // `out =` is optional
+        if tokens.starts_with(&[Token::Ident("out".into()), Token::Punct('=')]) {
+            tokens.drain(..2);
+        }
+        let mut parser = Parser { tokens, pos: 0 };
+        let (expr, kind) = parser.expr().map_err(invalid)?;
+        if let Some(token) = parser.peek() {
+            let token = match token {
+                Token::Number(n) => n.to_string(),
+                Token::Ident(name) => name.clone(),
+                Token::Punct(c) => c.to_string(),
+            };
+            return Err(invalid(format!(
+                "unexpected '{}' after the expression",
+                token
+            )));
+        }
+        if kind != Kind::Vec4 {
+            return Err(invalid(
+                "the result must be a vec4, e.g. vec4(v, v, v, in.a)".into(),
+            ));
+        }
+        Ok(Filter { expr })
+    }
+}
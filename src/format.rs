@@ -0,0 +1,228 @@
+//! The registry of image formats that `convert`, `open`, `info` and the other commands
+//! look files up in by extension. Every built-in codec is registered here. Other crates
+//! can implement [`ImageFormat`] for their own formats and [`register`] them, which also
+//! lets them replace a built-in. The binary registers GIF this way. Formats that are
+//! costly to find, like the binary's `qoi-format-<ext>` helper programs on PATH, can be
+//! left to [`discover_with`] instead.
+
+use crate::img::{Header, RawImage};
+use crate::{farbfeld, jpeg, pam, png, ppm, qoi, tiff};
+use std::sync::{Arc, LazyLock, Once, OnceLock, RwLock};
+
+pub trait ImageFormat: Send + Sync {
+    ///Short description for messages, e.g. "PNG"
+    fn name(&self) -> &str;
+
+    ///Lowercase file extensions without the dot
+    fn extensions(&self) -> Vec<&str>;
+
+    fn decode(&self, data: Vec<u8>) -> Result<RawImage, String>;
+
+    ///Just the header. Formats that can't read it on its own decode the whole image.
+    fn read_header(&self, data: &[u8]) -> Result<Header, String> {
+        let img = self.decode(data.to_vec())?;
+        let format = img.format();
+        Ok(Header {
+            width: img.0,
+            height: img.1,
+            channels: format.channels() as u8,
+            bit_depth: (format.bytes_per_pixel() / format.channels() * 8) as u8,
+            colorspace: None,
+        })
+    }
+
+    ///Whether `encode` works, so batch conversion knows which formats are targets
+    fn can_encode(&self) -> bool {
+        false
+    }
+
+    fn encode(&self, img: RawImage) -> Result<Vec<u8>, String> {
+        Err(format!("{} can only be read, not written", self.name()))
+    }
+}
+
+type Encode = fn(RawImage) -> Result<Vec<u8>, String>;
+
+///A built-in codec, wired up from its module's functions
+struct Builtin {
+    name: &'static str,
+    extensions: &'static [&'static str],
+    decode: fn(Vec<u8>) -> Result<RawImage, String>,
+    read_header: fn(&[u8]) -> Result<Header, String>,
+    encode: Option<Encode>,
+}
+
+impl ImageFormat for Builtin {
+    fn name(&self) -> &str {
+        self.name
+    }
+
+    fn extensions(&self) -> Vec<&str> {
+        self.extensions.to_vec()
+    }
+
+    fn decode(&self, data: Vec<u8>) -> Result<RawImage, String> {
+        (self.decode)(data)
+    }
+
+    fn read_header(&self, data: &[u8]) -> Result<Header, String> {
+        (self.read_header)(data)
+    }
+
+    fn can_encode(&self) -> bool {
+        self.encode.is_some()
+    }
+
+    fn encode(&self, img: RawImage) -> Result<Vec<u8>, String> {
+        match self.encode {
+            Some(encode) => encode(img),
+            None => Err(format!("{} can only be read, not written", self.name)),
+        }
+    }
+}
+
+fn builtins() -> Vec<Arc<dyn ImageFormat>> {
+    let formats = [
+        Builtin {
+            name: "QOI",
+            extensions: &["qoi"],
+            decode: |data| qoi::decode(&data).map_err(|e| e.to_string()),
+            read_header: |data| qoi::read_header(data).map_err(|e| e.to_string()),
+            encode: Some(|img| Ok(qoi::encode_img(img))),
+        },
+        Builtin {
+            name: "PPM",
+            extensions: &["ppm"],
+            decode: |data| ppm::parse_img(data.into_iter()),
+            read_header: ppm::read_header,
+            encode: Some(|img| Ok(ppm::encode_img(img))),
+        },
+        Builtin {
+            name: "PGM/PBM",
+            extensions: &["pgm", "pbm"],
+            decode: |data| ppm::parse_gray(data.into_iter()),
+            read_header: ppm::read_header,
+            encode: None,
+        },
+        Builtin {
+            name: "PAM",
+            extensions: &["pam"],
+            decode: |data| pam::parse_img(data.into_iter()),
+            read_header: pam::read_header,
+            encode: Some(|img| Ok(pam::encode_img(img))),
+        },
+        Builtin {
+            name: "PNG",
+            extensions: &["png"],
+            decode: |data| png::parse_img(data.into_iter()),
+            read_header: png::read_header,
+            encode: Some(png::encode_img),
+        },
+        Builtin {
+            name: "farbfeld",
+            extensions: &["ff"],
+            decode: |data| farbfeld::parse_img(data.into_iter()),
+            read_header: farbfeld::read_header,
+            encode: Some(|img| Ok(farbfeld::encode_img(img))),
+        },
+        #[cfg(feature = "jpeg")]
+        Builtin {
+            name: "JPEG",
+            extensions: &["jpg", "jpeg"],
+            decode: |data| jpeg::parse_img(&data),
+            read_header: jpeg::read_header,
+            encode: None,
+        },
+        Builtin {
+            name: "TIFF",
+            extensions: &["tif", "tiff"],
+            decode: |data| tiff::parse_img(&data),
+            read_header: tiff::read_header,
+            encode: None,
+        },
+    ];
+    formats
+        .into_iter()
+        .map(|format| Arc::new(format) as Arc<dyn ImageFormat>)
+        .collect()
+}
+
+static REGISTRY: LazyLock<RwLock<Vec<Arc<dyn ImageFormat>>>> =
+    LazyLock::new(|| RwLock::new(builtins()));
+
+///Add a format. It takes precedence over anything registered earlier for the same
+///extensions, built-ins included.
+pub fn register(format: Arc<dyn ImageFormat>) {
+    REGISTRY.write().unwrap().push(format);
+}
+
+type Discover = fn() -> Vec<Arc<dyn ImageFormat>>;
+
+static DISCOVER: OnceLock<Discover> = OnceLock::new();
+static DISCOVERED: Once = Once::new();
+
+///Find more formats with `discover` the first time a lookup misses or every extension is
+///listed, rather than up front. What it finds is added after everything registered, but
+///only for extensions nothing handles yet, so it can't replace a built-in. Where two
+///found formats share an extension, the first one wins.
+pub fn discover_with(discover: Discover) {
+    let _ = DISCOVER.set(discover);
+}
+
+fn discover() {
+    let Some(discover) = DISCOVER.get() else {
+        return;
+    };
+    DISCOVERED.call_once(|| {
+        for format in discover() {
+            if format.extensions().iter().all(|e| find(e).is_none()) {
+                register(format);
+            }
+        }
+    });
+}
+
+///The registered format for a lowercase extension, without discovering any
+fn find(extension: &str) -> Option<Arc<dyn ImageFormat>> {
+    let formats = REGISTRY.read().unwrap();
+    formats
+        .iter()
+        .rev()
+        .find(|format| format.extensions().contains(&extension))
+        .cloned()
+}
+
+///The format that handles files with this extension (in any case), if any
+pub fn lookup(extension: &str) -> Option<Arc<dyn ImageFormat>> {
+    let extension = extension.to_ascii_lowercase();
+    find(&extension).or_else(|| {
+        discover();
+        find(&extension)
+    })
+}
+
+///Every registered extension, in registration order, for messages like "Only .qoi, .png
+///... are supported"
+pub fn extensions() -> Vec<String> {
+    discover();
+    let formats = REGISTRY.read().unwrap();
+    let mut all: Vec<String> = vec![];
+    for format in formats.iter() {
+        for extension in format.extensions() {
+            if !all.iter().any(|e| e == extension) {
+                all.push(extension.to_string());
+            }
+        }
+    }
+    all
+}
+
+///`.qoi, .ppm, ... or .jpg`, for error messages
+pub fn extension_list(conjunction: &str) -> String {
+    let mut all: Vec<String> = extensions().iter().map(|e| format!(".{}", e)).collect();
+    match all.pop() {
+        Some(last) if !all.is_empty() => format!("{} {} {}", all.join(", "), conjunction, last),
+        Some(last) => last,
+        None => String::new(),
+    }
+}
@@ -1,50 +1,177 @@
-use pixels::{Pixels, SurfaceTexture};
+use crate::img::{ColorSpace, Dimensions, PixelFormat};
+use crate::term::label;
+#[cfg(feature = "gui")]
+use pixels::{Pixels, PixelsBuilder, SurfaceTexture, wgpu::TextureFormat};
 use std::cmp::min;
+use std::str::FromStr;
+#[cfg(feature = "gui")]
 use winit::{
     dpi::PhysicalSize,
     event_loop::EventLoop,
     window::{Window, WindowBuilder},
 };
 
+///Zoom level (in whole multiples) from which the pixel grid is drawn
+const GRID_MIN_SCALE: u32 = 8;
+///Side length of a checkerboard square, in buffer pixels
+const CHECKER_SIZE: usize = 8;
+
+///Longest window title or label, in chars, before the middle is cut out
+const LABEL_MAX_CHARS: usize = 120;
+
+///What to show behind transparent pixels
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Background {
+    Solid([u8; 3]),
+    Checker,
+}
+
+impl FromStr for Background {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "checker" {
+            return Ok(Background::Checker);
+        }
+        let hex = s.trim_start_matches('#');
+        let value = u32::from_str_radix(hex, 16).map_err(|_| {
+            format!(
+                "Invalid background '{}': expected 'checker' or a hex color like #202020",
+                s
+            )
+        })?;
+        if hex.len() != 6 {
+            return Err(format!(
+                "Invalid background '{}': hex colors need 6 digits",
+                s
+            ));
+        }
+        let [_, r, g, b] = value.to_be_bytes();
+        Ok(Background::Solid([r, g, b]))
+    }
+}
+
+#[derive(Debug, Clone, clap::Args)]
+pub struct ViewOptions {
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = ColorSpace::Srgb,
+        help = "How to interpret the pixel values when displaying them"
+    )]
+    pub colorspace: ColorSpace,
+    #[arg(
+        long,
+        help = "Show transparent areas over 'checker' or a hex color like #202020"
+    )]
+    pub background: Option<Background>,
+    #[arg(long, help = "Outline every pixel when zoomed in to 800% or more")]
+    pub grid: bool,
+}
+
+impl Default for ViewOptions {
+    fn default() -> Self {
+        ViewOptions {
+            colorspace: ColorSpace::Srgb,
+            background: None,
+            grid: false,
+        }
+    }
+}
+
+#[cfg(feature = "gui")]
 pub struct Gfx {
     pub window: Window,
     pixels: Pixels,
-    pub width: u32,
-    pub height: u32,
+    size: Dimensions,
+    options: ViewOptions,
+    ///How many buffer pixels make up one image pixel. Only above 1 when drawing the grid,
+    ///which needs a buffer at screen resolution.
+    upscale: u32,
 }
 
+#[cfg(feature = "gui")]
 impl Gfx {
-    pub fn new(width: u32, height: u32, title: &str) -> (Self, EventLoop<()>) {
-        let pixel_scale = min(1000 / height, 1500 / width).max(1);
+    ///Open a window sized to fit the image. Fails (rather than panicking inside winit)
+    ///when there is no display to open it on.
+    ///`options.colorspace` says how the bytes passed to `display` are encoded. pixels always
+    ///renders to an sRGB surface, so sRGB data passes straight through and linear data gets
+    ///encoded on the way out.
+    pub fn new(
+        width: u32,
+        height: u32,
+        title: &str,
+        options: ViewOptions,
+    ) -> Result<(Self, EventLoop<()>), String> {
+        if !display_available() {
+            return Err("No display available (neither DISPLAY nor WAYLAND_DISPLAY is set)".into());
+        }
+        let size = Dimensions::new(width, height)?;
+        let pixel_scale = fit_scale(width, height);
         let event_loop = EventLoop::new();
         // physical window size = virtual size × scale
         let physical_size = PhysicalSize::new(width * pixel_scale, height * pixel_scale);
 
         let window = WindowBuilder::new()
-            .with_title(title)
+            .with_title(label(title, LABEL_MAX_CHARS))
             .with_inner_size(physical_size)
             .with_resizable(false)
             .build(&event_loop)
-            .unwrap();
+            .map_err(|e| e.to_string())?;
 
         // SurfaceTexture uses the physical (window) pixels,
         // but the 'logical' pixel buffer stays at width×height
+        // (unless the grid is drawn, which needs one buffer pixel per screen pixel)
         let surface_texture =
             SurfaceTexture::new(physical_size.width, physical_size.height, &window);
 
-        let pixels = Pixels::new(width, height, surface_texture).unwrap();
+        let upscale = grid_upscale(&options, pixel_scale);
+        let texture_format = match options.colorspace {
+            ColorSpace::Srgb => TextureFormat::Rgba8UnormSrgb,
+            ColorSpace::Linear => TextureFormat::Rgba8Unorm,
+        };
+        let pixels = PixelsBuilder::new(width * upscale, height * upscale, surface_texture)
+            .texture_format(texture_format)
+            .build()
+            .map_err(|e| e.to_string())?;
 
-        (
+        Ok((
             Gfx {
                 window,
                 pixels,
-                width,
-                height,
+                size,
+                options,
+                upscale,
             },
             event_loop,
-        )
+        ))
+    }
+
+    ///Switch to an image of a different size, resizing the window to fit it
+    pub fn resize(&mut self, width: u32, height: u32) -> Result<(), String> {
+        let size = Dimensions::new(width, height)?;
+        if size == self.size {
+            return Ok(());
+        }
+        let pixel_scale = fit_scale(width, height);
+        let physical_size = PhysicalSize::new(width * pixel_scale, height * pixel_scale);
+        self.upscale = grid_upscale(&self.options, pixel_scale);
+        self.window.set_inner_size(physical_size);
+        self.pixels
+            .resize_surface(physical_size.width, physical_size.height)
+            .map_err(|e| e.to_string())?;
+        self.pixels
+            .resize_buffer(width * self.upscale, height * self.upscale)
+            .map_err(|e| e.to_string())?;
+        self.size = size;
+        Ok(())
+    }
+
+    pub fn set_title(&mut self, title: &str) {
+        self.window.set_title(&label(title, LABEL_MAX_CHARS));
     }
 
+    #[tracing::instrument(name = "gfx::render", skip_all)]
     pub fn render(&mut self) {
         self.pixels.render().unwrap();
     }
@@ -53,36 +180,103 @@ impl Gfx {
         self.window.request_redraw();
     }
 
+    #[tracing::instrument(name = "gfx::display", skip_all)]
     pub fn display(&mut self, bitmap: &[u8]) {
-        if bitmap.len() > (self.width * self.height * 4) as usize {
-            println!(
-                "Had to truncate: {} > {}",
-                bitmap.len(),
-                self.width * self.height * 4
-            );
+        let width = self.size.width() as usize;
+        let expected_len = self.size.byte_len(PixelFormat::Rgba8);
+        let mut fitted = vec![0; expected_len];
+        if bitmap.len() > expected_len {
+            println!("Had to truncate: {} > {}", bitmap.len(), expected_len);
+            fitted.copy_from_slice(&bitmap[0..expected_len]);
+        } else if bitmap.len() < expected_len {
+            println!("Had to pad: {} < {}", bitmap.len(), expected_len);
+            fitted[0..bitmap.len()].copy_from_slice(bitmap);
+        } else {
+            fitted.copy_from_slice(bitmap);
+        }
 
-            self.pixels
-                .frame_mut()
-                .copy_from_slice(&bitmap[0..(self.width * self.height * 4) as usize]);
-        } else if bitmap.len() < (self.width * self.height * 4) as usize {
-            println!(
-                "Had to pad: {} < {}",
-                bitmap.len(),
-                self.width * self.height * 4
+        if let Some(background) = self.options.background {
+            composite(&mut fitted, width, background, self.upscale);
+        }
+
+        if self.upscale > 1 {
+            draw_grid(
+                &fitted,
+                width,
+                self.upscale as usize,
+                self.pixels.frame_mut(),
             );
+        } else {
+            self.pixels.frame_mut().copy_from_slice(&fitted);
+        }
+    }
+}
 
-            let mut padded = vec![0; (self.width * self.height * 4) as usize];
-            padded[0..bitmap.len()].copy_from_slice(bitmap);
+///Largest whole-number zoom that keeps the window within 1500x1000
+fn fit_scale(width: u32, height: u32) -> u32 {
+    min(1000 / height, 1500 / width).max(1)
+}
 
-            self.pixels.frame_mut().copy_from_slice(&padded);
-        } else {
-            self.pixels
-                .frame_mut()
-                .copy_from_slice(&bitmap[0..(self.width * self.height * 4) as usize]);
+fn grid_upscale(options: &ViewOptions, pixel_scale: u32) -> u32 {
+    if options.grid && pixel_scale >= GRID_MIN_SCALE {
+        pixel_scale
+    } else {
+        1
+    }
+}
+
+///Blend every pixel over the background, leaving the buffer fully opaque
+fn composite(rgba: &mut [u8], width: usize, background: Background, upscale: u32) {
+    // Keep checker squares the same size on screen whether or not the buffer is upscaled
+    let checker = (CHECKER_SIZE / upscale as usize).max(1);
+    for (i, pixel) in rgba.chunks_exact_mut(4).enumerate() {
+        let behind = match background {
+            Background::Solid(color) => color,
+            Background::Checker => {
+                let (x, y) = (i % width / checker, i / width / checker);
+                if (x + y) % 2 == 0 {
+                    [0xCC; 3]
+                } else {
+                    [0x99; 3]
+                }
+            }
+        };
+        let alpha = pixel[3] as u16;
+        for c in 0..3 {
+            pixel[c] = ((pixel[c] as u16 * alpha + behind[c] as u16 * (255 - alpha)) / 255) as u8;
         }
+        pixel[3] = 255;
     }
 }
 
+///Nearest-neighbour upscale into `frame`, darkening the first row and column of every
+///image pixel to outline it
+fn draw_grid(rgba: &[u8], width: usize, scale: usize, frame: &mut [u8]) {
+    let frame_width = width * scale;
+    for (i, out) in frame.chunks_exact_mut(4).enumerate() {
+        let (fx, fy) = (i % frame_width, i / frame_width);
+        let src = ((fy / scale) * width + fx / scale) * 4;
+        out.copy_from_slice(&rgba[src..src + 4]);
+        if fx % scale == 0 || fy % scale == 0 {
+            for c in &mut out[0..3] {
+                *c = (*c as u16 * 3 / 4 + 0x20) as u8;
+            }
+            out[3] = 255;
+        }
+    }
+}
+
+///winit panics (rather than erroring) when it can't reach a display server, so check first.
+#[cfg(all(unix, not(target_os = "macos")))]
+fn display_available() -> bool {
+    std::env::var_os("DISPLAY").is_some() || std::env::var_os("WAYLAND_DISPLAY").is_some()
+}
+
+#[cfg(not(all(unix, not(target_os = "macos"))))]
+fn display_available() -> bool {
+    true
+}
+
 fn _rst(frame: &mut [u8]) {
     let black = [0, 0, 0, 255].repeat(frame.len() / 4);
     frame.copy_from_slice(&black)
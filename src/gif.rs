@@ -0,0 +1,274 @@
+use crate::format::ImageFormat;
+use crate::img::{ColorSpace, Header, PixelFormat, RawImage};
+use color_quant::NeuQuant;
+use gif::{ColorOutput, DecodeOptions, DisposalMethod, Encoder, Frame, Repeat};
+use std::time::Duration;
+
+///Roughly how many pixels to feed NeuQuant when building a global palette
+const PALETTE_SAMPLES: usize = 1 << 20;
+
+///How colors are reduced to GIF's 256-entry palettes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum PaletteMode {
+    ///Quantize every frame on its own: best colors, larger file, may flicker
+    PerFrame,
+    ///One palette trained on pixels sampled from every frame
+    Global,
+}
+
+///How many pixels to skip between palette samples for a sequence of this size
+pub fn sample_stride(frames: usize, width: u32, height: u32) -> usize {
+    (frames * width as usize * height as usize / PALETTE_SAMPLES).max(1)
+}
+
+///Build a palette from RGBA8 samples gathered across a whole sequence
+pub fn train_palette(samples: &[u8]) -> NeuQuant {
+    NeuQuant::new(10, 256, samples)
+}
+
+///GIF input for the format registry: the first frame, or every frame when `open` plays
+///an animation. Writing goes through `concat` instead.
+pub struct Gif;
+
+impl ImageFormat for Gif {
+    fn name(&self) -> &str {
+        "GIF"
+    }
+
+    fn extensions(&self) -> Vec<&str> {
+        vec!["gif"]
+    }
+
+    fn decode(&self, data: Vec<u8>) -> Result<RawImage, String> {
+        parse_img(&data)
+    }
+
+    fn read_header(&self, data: &[u8]) -> Result<Header, String> {
+        read_header(data)
+    }
+}
+
+///Read just the logical screen size
+pub fn read_header(data: &[u8]) -> Result<Header, String> {
+    let decoder = DecodeOptions::new()
+        .read_info(data)
+        .map_err(|e| e.to_string())?;
+    Ok(Header {
+        width: decoder.width() as u32,
+        height: decoder.height() as u32,
+        channels: 4,
+        bit_depth: 8,
+        colorspace: None,
+    })
+}
+
+///Decode the first frame to RGBA8 at the full logical screen size. Whatever the frame
+///doesn't cover is left transparent.
+#[tracing::instrument(name = "gif::decode", skip_all)]
+pub fn parse_img(data: &[u8]) -> Result<RawImage, String> {
+    let (img, _) = parse_frames(data, 1)?
+        .into_iter()
+        .next()
+        .ok_or("GIF has no frames")?;
+    Ok(img)
+}
+
+///Decode up to `limit` frames as they would be shown: each one drawn over what its
+///predecessors left according to their disposal methods, at the full logical screen size.
+///Each comes with its delay, where 0 and 10 ms mean 100 ms as in browsers.
+#[tracing::instrument(name = "gif::decode_frames", skip_all)]
+pub fn parse_frames(data: &[u8], limit: usize) -> Result<Vec<(RawImage, Duration)>, String> {
+    let mut options = DecodeOptions::new();
+    options.set_color_output(ColorOutput::RGBA);
+    let mut decoder = options.read_info(data).map_err(|e| e.to_string())?;
+    let (width, height) = (decoder.width() as usize, decoder.height() as usize);
+    let mut canvas = vec![0; width * height * 4];
+    let mut frames = vec![];
+    while frames.len() < limit {
+        let Some(frame) = decoder.read_next_frame().map_err(|e| e.to_string())? else {
+            break;
+        };
+        // The part of the frame that lies on the screen, as canvas byte ranges per row. A
+        // frame entirely off it, or empty, draws nothing but still takes its delay.
+        let (left, top) = (frame.left as usize, frame.top as usize);
+        let on_screen = left < width && top < height && frame.width > 0;
+        let visible = (frame.width as usize).min(width.saturating_sub(left));
+        let rows = match on_screen {
+            true => (frame.height as usize).min(height - top),
+            false => 0,
+        };
+        let span = |y: usize| {
+            let start = ((top + y) * width + left) * 4;
+            start..start + visible * 4
+        };
+        let previous = (frame.dispose == DisposalMethod::Previous).then(|| canvas.clone());
+
+        for (y, row) in frame
+            .buffer
+            .chunks_exact((frame.width as usize * 4).max(1))
+            .take(rows)
+            .enumerate()
+        {
+            for (pixel, new) in canvas[span(y)].chunks_exact_mut(4).zip(row.chunks_exact(4)) {
+                if new[3] != 0 {
+                    pixel.copy_from_slice(new);
+                }
+            }
+        }
+        let delay = match frame.delay {
+            0 | 1 => Duration::from_millis(100),
+            delay => Duration::from_millis(delay as u64 * 10),
+        };
+        frames.push((
+            RawImage(
+                width as u32,
+                height as u32,
+                canvas.clone(),
+                PixelFormat::Rgba8,
+                ColorSpace::Srgb,
+            ),
+            delay,
+        ));
+
+        match (frame.dispose, previous) {
+            (DisposalMethod::Previous, Some(previous)) => canvas = previous,
+            (DisposalMethod::Background, _) => {
+                for y in 0..rows {
+                    canvas[span(y)].fill(0);
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(frames)
+}
+
+///Writes frames of the same size into a looping animated GIF
+pub struct AnimationEncoder {
+    encoder: Encoder<Vec<u8>>,
+    width: u16,
+    height: u16,
+    palette: Option<NeuQuant>,
+    transparent: Option<u8>,
+    ///Per-frame delay in hundredths of a second
+    delay: u16,
+}
+
+impl AnimationEncoder {
+    pub fn new(
+        width: u32,
+        height: u32,
+        palette: Option<NeuQuant>,
+        delay: u16,
+    ) -> Result<Self, String> {
+        let (Ok(width), Ok(height)) = (u16::try_from(width), u16::try_from(height)) else {
+            return Err("GIF frames are limited to 65535x65535".into());
+        };
+        let global_palette = palette
+            .as_ref()
+            .map(NeuQuant::color_map_rgb)
+            .unwrap_or_default();
+        let mut encoder =
+            Encoder::new(vec![], width, height, &global_palette).map_err(|e| e.to_string())?;
+        encoder
+            .set_repeat(Repeat::Infinite)
+            .map_err(|e| e.to_string())?;
+
+        // NeuQuant trains on alpha too, so fully transparent areas end up on the
+        // lowest-alpha entry. Use that as the transparent index if it is mostly clear.
+        let transparent = palette.as_ref().and_then(|nq| {
+            (0..256)
+                .filter_map(|i| Some((i as u8, nq.lookup(i)?[3])))
+                .min_by_key(|&(_, alpha)| alpha)
+                .filter(|&(_, alpha)| alpha < 128)
+                .map(|(i, _)| i)
+        });
+
+        Ok(AnimationEncoder {
+            encoder,
+            width,
+            height,
+            palette,
+            transparent,
+            delay,
+        })
+    }
+
+    #[tracing::instrument(name = "gif::add_frame", skip_all)]
+    pub fn add_frame(&mut self, img: RawImage) -> Result<(), String> {
+        if (img.0, img.1) != (self.width as u32, self.height as u32) {
+            return Err(format!(
+                "Frame is {}x{}, but the animation is {}x{}",
+                img.0, img.1, self.width, self.height
+            ));
+        }
+        let RawImage(_, _, mut rgba, ..) = img.convert(PixelFormat::Rgba8);
+        let mut frame = match &self.palette {
+            Some(nq) => {
+                let indices: Vec<u8> = rgba
+                    .chunks_exact(4)
+                    .map(|pixel| nq.index_of(pixel) as u8)
+                    .collect();
+                Frame::from_indexed_pixels(self.width, self.height, indices, self.transparent)
+            }
+            None => Frame::from_rgba_speed(self.width, self.height, &mut rgba, 10),
+        };
+        frame.delay = self.delay;
+        self.encoder.write_frame(&frame).map_err(|e| e.to_string())
+    }
+
+    pub fn finish(self) -> Result<Vec<u8>, String> {
+        self.encoder.into_inner().map_err(|e| e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    ///A 4x4 GIF with one frame of the given size and position
+    fn gif_with_frame(left: u16, top: u16, width: u16, height: u16) -> Vec<u8> {
+        let mut data = vec![];
+        let mut encoder = Encoder::new(&mut data, 4, 4, &[0, 0, 0, 255, 255, 255]).unwrap();
+        let frame = Frame {
+            left,
+            top,
+            width,
+            height,
+            buffer: vec![1; width as usize * height as usize].into(),
+            ..Frame::default()
+        };
+        encoder.write_frame(&frame).unwrap();
+        drop(encoder);
+        data
+    }
+
+    #[test]
+    fn frame_is_drawn_at_its_position() {
+        let (img, _) = parse_frames(&gif_with_frame(2, 1, 2, 2), 1)
+            .unwrap()
+            .remove(0);
+        assert_eq!((img.0, img.1), (4, 4));
+        let white = |x: usize, y: usize| img.2[(y * 4 + x) * 4..][..4] == [255; 4];
+        assert!(white(2, 1) && white(3, 2));
+        assert!(!white(1, 1) && !white(2, 3));
+    }
+
+    #[test]
+    fn frame_is_clipped_to_the_screen() {
+        let (img, _) = parse_frames(&gif_with_frame(3, 3, 4, 4), 1)
+            .unwrap()
+            .remove(0);
+        assert_eq!(img.2[(3 * 4 + 3) * 4..], [255; 4]);
+        assert_eq!(img.2.iter().filter(|&&b| b == 255).count(), 4);
+    }
+
+    #[test]
+    fn frame_off_the_screen_draws_nothing() {
+        for (left, top) in [(4, 0), (10, 0), (0, 4), (20, 20)] {
+            let frames = parse_frames(&gif_with_frame(left, top, 2, 2), 1).unwrap();
+            assert_eq!(frames.len(), 1);
+            assert!(frames[0].0.2.iter().all(|&b| b == 0));
+        }
+    }
+}
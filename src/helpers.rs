@@ -0,0 +1,145 @@
+//! Formats supplied by external programs, so in-house formats can go through convert,
+//! open and info without a fork of this crate. An executable named `qoi-format-<ext>` on
+//! PATH handles `.<ext>` files. `qoi-format-<ext> decode` reads the file on stdin and
+//! writes a PAM (P7) image to stdout, and `qoi-format-<ext> encode` does the reverse.
+//! A non-zero exit status is an error, and whatever the helper wrote to stderr is the
+//! message.
+//!
+//! Helpers can't replace a format the binary handles itself, so a stray `qoi-format-png`
+//! is ignored, unless its extension is listed in `QOI_HELPERS_OVERRIDE`, e.g. `png,qoi`.
+
+use crate::format::{self, ImageFormat};
+use crate::img::RawImage;
+use crate::pam;
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::Arc;
+
+const PREFIX: &str = "qoi-format-";
+
+///Extensions whose helpers may replace the built-in format, separated by commas
+const OVERRIDE: &str = "QOI_HELPERS_OVERRIDE";
+
+struct Helper {
+    extension: String,
+    program: PathBuf,
+}
+
+impl Helper {
+    ///Run `program <verb>`, feeding it `input` and collecting what it prints
+    fn run(&self, verb: &str, input: &[u8]) -> Result<Vec<u8>, String> {
+        let mut child = Command::new(&self.program)
+            .arg(verb)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| format!("{}: {}", self.program.display(), e))?;
+        let mut stdin = child.stdin.take().ok_or("helper has no stdin")?;
+        // Written from another thread so a helper that streams its output while reading
+        // can't deadlock against a full pipe
+        let output = std::thread::scope(|scope| {
+            scope.spawn(move || stdin.write_all(input));
+            child.wait_with_output()
+        })
+        .map_err(|e| e.to_string())?;
+        if !output.status.success() {
+            let message = String::from_utf8_lossy(&output.stderr);
+            return Err(format!(
+                "{} {} failed ({}): {}",
+                self.program.display(),
+                verb,
+                output.status,
+                message.trim()
+            ));
+        }
+        Ok(output.stdout)
+    }
+}
+
+impl ImageFormat for Helper {
+    fn name(&self) -> &str {
+        self.program
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or(PREFIX)
+    }
+
+    fn extensions(&self) -> Vec<&str> {
+        vec![&self.extension]
+    }
+
+    fn decode(&self, data: Vec<u8>) -> Result<RawImage, String> {
+        let pam = self.run("decode", &data)?;
+        pam::parse_img(pam.into_iter()).map_err(|e| format!("{}: {}", self.name(), e))
+    }
+
+    fn can_encode(&self) -> bool {
+        true
+    }
+
+    fn encode(&self, img: RawImage) -> Result<Vec<u8>, String> {
+        self.run("encode", &pam::encode_img(img))
+    }
+}
+
+///Every executable `qoi-format-*` program on PATH, in PATH order, for
+///[`format::discover_with`], which keeps the first for each extension as a shell would
+pub fn discover() -> Vec<Arc<dyn ImageFormat>> {
+    let Some(path) = env::var_os("PATH") else {
+        return vec![];
+    };
+    let mut helpers: Vec<Arc<dyn ImageFormat>> = vec![];
+    for dir in env::split_paths(&path) {
+        let Ok(entries) = fs::read_dir(&dir) else {
+            continue;
+        };
+        for entry in entries.flatten() {
+            let name = entry.file_name();
+            let Some(extension) = name.to_str().and_then(|name| name.strip_prefix(PREFIX)) else {
+                continue;
+            };
+            let extension = extension.trim_end_matches(".exe").to_ascii_lowercase();
+            if !extension.is_empty() && is_executable(&entry.path()) {
+                helpers.push(Arc::new(Helper {
+                    extension,
+                    program: entry.path(),
+                }));
+            }
+        }
+    }
+    helpers
+}
+
+///Register the helpers for the extensions listed in [`OVERRIDE`], replacing the built-in
+///formats. Only the directories on PATH are checked for those names, so this is cheap
+///enough to run at startup, and does nothing when the variable isn't set.
+pub fn register_overrides() {
+    let (Ok(overrides), Some(path)) = (env::var(OVERRIDE), env::var_os("PATH")) else {
+        return;
+    };
+    let extensions = overrides.split(',').map(|e| e.trim().to_ascii_lowercase());
+    for extension in extensions.filter(|e| !e.is_empty()) {
+        let name = format!("{}{}", PREFIX, extension);
+        let program = env::split_paths(&path)
+            .flat_map(|dir| [dir.join(&name), dir.join(format!("{}.exe", name))])
+            .find(|program| is_executable(program));
+        if let Some(program) = program {
+            format::register(Arc::new(Helper { extension, program }));
+        }
+    }
+}
+
+#[cfg(unix)]
+fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    fs::metadata(path).is_ok_and(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+}
+
+#[cfg(not(unix))]
+fn is_executable(path: &Path) -> bool {
+    path.is_file()
+}
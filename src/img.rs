@@ -1,4 +1,117 @@
-pub struct RawImage(pub u32, pub u32, pub Vec<u8>);
+use crate::stream::{ChunkExact, GRAY_ALPHA_TO_RGBA, GRAY_TO_RGB, Reorderable, Spaceable};
+use std::str::FromStr;
+
+///Memory layout of the pixel buffer in a RawImage
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PixelFormat {
+    Rgba8,
+    Rgb8,
+    Gray8,
+    GrayAlpha8,
+    ///16 bits per channel, big-endian (the way PNG stores it)
+    Rgba16,
+}
+
+impl PixelFormat {
+    pub fn channels(self) -> usize {
+        match self {
+            PixelFormat::Rgba8 | PixelFormat::Rgba16 => 4,
+            PixelFormat::Rgb8 => 3,
+            PixelFormat::GrayAlpha8 => 2,
+            PixelFormat::Gray8 => 1,
+        }
+    }
+
+    pub fn bytes_per_pixel(self) -> usize {
+        match self {
+            PixelFormat::Rgba16 => 8,
+            format => format.channels(),
+        }
+    }
+
+    pub fn has_alpha(self) -> bool {
+        matches!(
+            self,
+            PixelFormat::Rgba8 | PixelFormat::GrayAlpha8 | PixelFormat::Rgba16
+        )
+    }
+}
+
+///Width and height of an image that has pixels and whose buffer fits in memory even at
+///8 bytes a pixel, so sizes worked out from it can't overflow
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Dimensions {
+    width: u32,
+    height: u32,
+}
+
+impl Dimensions {
+    pub fn new(width: u32, height: u32) -> Result<Self, String> {
+        if width == 0 || height == 0 {
+            return Err(format!(
+                "image is {}x{}, which has no pixels",
+                width, height
+            ));
+        }
+        let widest = PixelFormat::Rgba16.bytes_per_pixel() as u64;
+        let len = (width as u64 * height as u64).checked_mul(widest);
+        if len.is_none_or(|len| len > isize::MAX as u64) {
+            return Err(format!(
+                "image dimensions {}x{} are too large",
+                width, height
+            ));
+        }
+        Ok(Dimensions { width, height })
+    }
+
+    pub fn width(self) -> u32 {
+        self.width
+    }
+
+    pub fn height(self) -> u32 {
+        self.height
+    }
+
+    pub fn pixels(self) -> usize {
+        self.width as usize * self.height as usize
+    }
+
+    ///Size of the pixel buffer in `format`
+    pub fn byte_len(self, format: PixelFormat) -> usize {
+        self.pixels() * format.bytes_per_pixel()
+    }
+}
+
+///Transfer function of the pixel values, matching the QOI header's colorspace byte
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum ColorSpace {
+    ///Gamma-encoded sRGB (with linear alpha)
+    #[default]
+    Srgb,
+    ///All channels linear
+    Linear,
+}
+
+///An image's header fields, read without decoding any pixels
+#[derive(Debug, Clone, Copy)]
+pub struct Header {
+    pub width: u32,
+    pub height: u32,
+    pub channels: u8,
+    pub bit_depth: u8,
+    ///None when the file doesn't say
+    pub colorspace: Option<ColorSpace>,
+}
+
+///Width, height, pixels, their layout, and how their values map to light
+#[derive(Clone)]
+pub struct RawImage(
+    pub u32,
+    pub u32,
+    pub Vec<u8>,
+    pub PixelFormat,
+    pub ColorSpace,
+);
 
 impl RawImage {
     fn to_bytes(&self) -> Vec<u8> {
@@ -13,12 +126,517 @@ impl RawImage {
         if bytes.len() < 8 {
             return Err("Invalid image data".into());
         }
-        let width = u32::from_be_bytes(bytes[0..4].try_into().unwrap());
-        let height = u32::from_be_bytes(bytes[4..8].try_into().unwrap());
-        if bytes.len() < width as usize * height as usize * 4 + 8 {
-            return Err("Image dimensions conflict with byte stream length".into());
+        let size = Dimensions::new(
+            u32::from_be_bytes(bytes[0..4].try_into().unwrap()),
+            u32::from_be_bytes(bytes[4..8].try_into().unwrap()),
+        )?;
+        let data = bytes[8..]
+            .get(..size.byte_len(PixelFormat::Rgba8))
+            .ok_or("Image dimensions conflict with byte stream length")?
+            .to_vec();
+        Ok(RawImage(
+            size.width(),
+            size.height(),
+            data,
+            PixelFormat::Rgba8,
+            ColorSpace::Srgb,
+        ))
+    }
+
+    pub fn format(&self) -> PixelFormat {
+        self.3
+    }
+
+    pub fn colorspace(&self) -> ColorSpace {
+        self.4
+    }
+
+    ///Convert the pixel buffer to another layout, going through RGBA8.
+    ///Narrowing to 8 bits keeps the high byte; dropping color uses Rec. 601 luma.
+    pub fn convert(self, format: PixelFormat) -> RawImage {
+        if self.3 == format {
+            return self;
+        }
+        let RawImage(width, height, data, from, space) = self;
+        let rgba = to_rgba8(data, from);
+        RawImage(width, height, from_rgba8(rgba, format), format, space)
+    }
+
+    ///Reduce each color channel to its top `8 - bits` bits, landing in the middle of each
+    ///band. Alpha is left alone. Neighbours become equal more often, which QOI rewards.
+    pub fn posterize(self, bits: u32) -> RawImage {
+        let RawImage(width, height, mut rgba, _, space) = self.convert(PixelFormat::Rgba8);
+        if bits > 0 {
+            let mask = (1u8 << bits.min(7)) - 1;
+            let middle = 1 << (bits.min(7) - 1);
+            for pixel in rgba.chunks_exact_mut(4) {
+                for channel in &mut pixel[..3] {
+                    *channel = (*channel & !mask) | middle;
+                }
+            }
+        }
+        RawImage(width, height, rgba, PixelFormat::Rgba8, space)
+    }
+
+    ///Re-encode the color channels of an image stored as `sample = light ^ exponent` (the
+    ///way PNG's gAMA chunk describes it) with the sRGB curve instead, and label it sRGB.
+    ///Alpha is left alone.
+    pub fn gamma_to_srgb(self, exponent: f64) -> RawImage {
+        let RawImage(width, height, mut data, format, _) = self;
+        let channels = format.channels();
+        let colors = channels - format.has_alpha() as usize;
+        let curve = |sample: f64| {
+            let light = sample.powf(1.0 / exponent);
+            match light <= 0.0031308 {
+                true => light * 12.92,
+                false => 1.055 * light.powf(1.0 / 2.4) - 0.055,
+            }
+        };
+        if format == PixelFormat::Rgba16 {
+            let table: Vec<u16> = (0..=u16::MAX)
+                .map(|v| (curve(v as f64 / 65535.0) * 65535.0).round() as u16)
+                .collect();
+            for pixel in data.chunks_exact_mut(8) {
+                for sample in pixel[..6].chunks_exact_mut(2) {
+                    let v = u16::from_be_bytes([sample[0], sample[1]]);
+                    sample.copy_from_slice(&table[v as usize].to_be_bytes());
+                }
+            }
+        } else {
+            let table: Vec<u8> = (0..=u8::MAX)
+                .map(|v| (curve(v as f64 / 255.0) * 255.0).round() as u8)
+                .collect();
+            for pixel in data.chunks_exact_mut(channels) {
+                for sample in &mut pixel[..colors] {
+                    *sample = table[*sample as usize];
+                }
+            }
+        }
+        RawImage(width, height, data, format, ColorSpace::Srgb)
+    }
+
+    ///Narrow 16-bit channels to 8 with Floyd-Steinberg error diffusion, so smooth gradients
+    ///keep their in-between shades as fine grain instead of banding. Alpha is rounded, not
+    ///dithered. Images that aren't 16-bit are returned as they are.
+    pub fn dither_to_rgba8(self) -> RawImage {
+        let RawImage(width, height, data, format, space) = self;
+        if format != PixelFormat::Rgba16 {
+            return RawImage(width, height, data, format, space);
+        }
+        if width == 0 {
+            return RawImage(width, height, vec![], PixelFormat::Rgba8, space);
+        }
+        let columns = width as usize;
+        let mut out = Vec::with_capacity(data.len() / 2);
+        // Sixteenths of the error pushed onto this row and the next, per channel, with a
+        // spare pixel at either end
+        let mut this_row = vec![0i32; (columns + 2) * 4];
+        let mut next_row = this_row.clone();
+        for row in data.chunks_exact(columns * 8) {
+            for (x, pixel) in row.chunks_exact(8).enumerate() {
+                for c in 0..4 {
+                    let value = u16::from_be_bytes([pixel[c * 2], pixel[c * 2 + 1]]) as i32;
+                    let at = (x + 1) * 4 + c;
+                    let wanted = match c {
+                        3 => value,
+                        _ => (value + this_row[at] / 16).clamp(0, 65535),
+                    };
+                    let narrowed = (wanted * 255 + 32767) / 65535;
+                    out.push(narrowed as u8);
+                    if c < 3 {
+                        let error = wanted - narrowed * 257;
+                        this_row[at + 4] += error * 7;
+                        next_row[at - 4] += error * 3;
+                        next_row[at] += error * 5;
+                        next_row[at + 4] += error;
+                    }
+                }
+            }
+            std::mem::swap(&mut this_row, &mut next_row);
+            next_row.fill(0);
+        }
+        RawImage(width, height, out, PixelFormat::Rgba8, space)
+    }
+
+    ///Halve both dimensions (rounding up) by averaging 2x2 blocks
+    pub fn half_size(self) -> RawImage {
+        let RawImage(width, height, rgba, _, space) = self.convert(PixelFormat::Rgba8);
+        let (width, height) = (width as usize, height as usize);
+        let (half_w, half_h) = (width.div_ceil(2), height.div_ceil(2));
+        let mut out = Vec::with_capacity(half_w * half_h * 4);
+        for y in 0..half_h {
+            let rows = [2 * y, (2 * y + 1).min(height - 1)];
+            for x in 0..half_w {
+                let cols = [2 * x, (2 * x + 1).min(width - 1)];
+                for c in 0..4 {
+                    let sum: u32 = rows
+                        .iter()
+                        .flat_map(|&row| cols.map(|col| rgba[(row * width + col) * 4 + c] as u32))
+                        .sum();
+                    out.push(((sum + 2) / 4) as u8);
+                }
+            }
+        }
+        RawImage(half_w as u32, half_h as u32, out, PixelFormat::Rgba8, space)
+    }
+
+    ///Overwrite a region for good. The rest of the image and its pixel format are kept.
+    ///Returns false if the region lies entirely outside the image.
+    pub fn redact(&mut self, redaction: &Redaction) -> bool {
+        let (format, space) = (self.3, self.4);
+        let (width, height) = (self.0 as usize, self.1 as usize);
+        let x0 = (redaction.x as usize).min(width);
+        let y0 = (redaction.y as usize).min(height);
+        let x1 = (x0 + redaction.width as usize).min(width);
+        let y1 = (y0 + redaction.height as usize).min(height);
+        if x0 == x1 || y0 == y1 {
+            return false;
+        }
+
+        let img = std::mem::replace(self, RawImage(0, 0, vec![], format, space));
+        let RawImage(_, _, mut rgba, ..) = img.convert(PixelFormat::Rgba8);
+        let at = |x: usize, y: usize| (y * width + x) * 4;
+        match redaction.style {
+            RedactStyle::Fill(color) => {
+                for y in y0..y1 {
+                    rgba[at(x0, y)..at(x1, y)]
+                        .chunks_exact_mut(4)
+                        .for_each(|pixel| pixel.copy_from_slice(&color));
+                }
+            }
+            RedactStyle::Blur => {
+                // Average coarse blocks first, so no detail finer than a block survives
+                // for the smoothing pass (or anyone) to work back from
+                let block = ((x1 - x0).max(y1 - y0) / 6).max(8);
+                for by in (y0..y1).step_by(block) {
+                    for bx in (x0..x1).step_by(block) {
+                        let (bx1, by1) = ((bx + block).min(x1), (by + block).min(y1));
+                        let mut sum = [0usize; 4];
+                        for y in by..by1 {
+                            for pixel in rgba[at(bx, y)..at(bx1, y)].chunks_exact(4) {
+                                sum.iter_mut()
+                                    .zip(pixel)
+                                    .for_each(|(s, &v)| *s += v as usize);
+                            }
+                        }
+                        let count = (bx1 - bx) * (by1 - by);
+                        let mean = sum.map(|s| (s / count) as u8);
+                        for y in by..by1 {
+                            rgba[at(bx, y)..at(bx1, y)]
+                                .chunks_exact_mut(4)
+                                .for_each(|pixel| pixel.copy_from_slice(&mean));
+                        }
+                    }
+                }
+                // Then soften the block edges with a box blur over the region
+                let radius = block / 2;
+                let blocky = rgba.clone();
+                for y in y0..y1 {
+                    for x in x0..x1 {
+                        let (ys, ye) = (y.saturating_sub(radius).max(y0), (y + radius + 1).min(y1));
+                        let (xs, xe) = (x.saturating_sub(radius).max(x0), (x + radius + 1).min(x1));
+                        let mut sum = [0usize; 4];
+                        for yy in ys..ye {
+                            for pixel in blocky[at(xs, yy)..at(xe, yy)].chunks_exact(4) {
+                                sum.iter_mut()
+                                    .zip(pixel)
+                                    .for_each(|(s, &v)| *s += v as usize);
+                            }
+                        }
+                        let count = (xe - xs) * (ye - ys);
+                        let i = at(x, y);
+                        for c in 0..4 {
+                            rgba[i + c] = (sum[c] / count) as u8;
+                        }
+                    }
+                }
+            }
+        }
+        *self =
+            RawImage(width as u32, height as u32, rgba, PixelFormat::Rgba8, space).convert(format);
+        true
+    }
+
+    ///The bounding box (x, y, width, height) of everything that differs from the top-left
+    ///pixel by more than `tolerance` in any channel. None if nothing does.
+    pub fn content_bounds(&self, tolerance: u8) -> Option<(u32, u32, u32, u32)> {
+        let (width, height) = (self.0 as usize, self.1 as usize);
+        if width == 0 || height == 0 {
+            return None;
+        }
+        let mut rgba = vec![];
+        self.rgba8_into(&mut rgba);
+        let border: [u8; 4] = rgba[..4].try_into().unwrap();
+        let differs = |pixel: &[u8]| {
+            pixel
+                .iter()
+                .zip(border)
+                .any(|(&v, b)| v.abs_diff(b) > tolerance)
+        };
+
+        let (mut left, mut right) = (width, 0);
+        let (mut top, mut bottom) = (None, 0);
+        for (y, row) in rgba.chunks_exact(width * 4).enumerate() {
+            let Some(first) = row.chunks_exact(4).position(differs) else {
+                continue;
+            };
+            let last = row.chunks_exact(4).rposition(differs).unwrap();
+            top.get_or_insert(y);
+            bottom = y;
+            left = left.min(first);
+            right = right.max(last);
+        }
+        let top = top?;
+        Some((
+            left as u32,
+            top as u32,
+            (right - left + 1) as u32,
+            (bottom - top + 1) as u32,
+        ))
+    }
+
+    ///Cut out a rectangle, keeping the pixel format. The rectangle must lie inside the image.
+    pub fn crop(self, x: u32, y: u32, width: u32, height: u32) -> Result<RawImage, String> {
+        let size = Dimensions::new(width, height)?;
+        let RawImage(full_width, _, data, format, space) = self;
+        let bpp = format.bytes_per_pixel();
+        let row_len = full_width as usize * bpp;
+        let mut out = Vec::with_capacity(size.byte_len(format));
+        for row in data
+            .chunks_exact(row_len)
+            .skip(y as usize)
+            .take(height as usize)
+        {
+            out.extend_from_slice(&row[x as usize * bpp..(x + width) as usize * bpp]);
+        }
+        Ok(RawImage(width, height, out, format, space))
+    }
+
+    ///Grow the canvas to `width` x `height`, keeping the image in the top-left corner.
+    ///The pixel format is kept unless a see-through fill needs an alpha channel.
+    pub fn pad(self, width: u32, height: u32, fill: PadFill) -> Result<RawImage, String> {
+        let size = Dimensions::new(width, height)?;
+        let format = match fill {
+            PadFill::Color([.., a]) if a < 255 && !self.3.has_alpha() => PixelFormat::Rgba8,
+            _ => self.3,
+        };
+        let RawImage(old_width, old_height, rgba, _, space) = self.convert(PixelFormat::Rgba8);
+        let (old_width, old_height) = (old_width as usize, old_height as usize);
+        let (width, height) = (width as usize, height as usize);
+        let mut out = Vec::with_capacity(size.byte_len(PixelFormat::Rgba8));
+        for y in 0..height {
+            let source_row = match fill {
+                PadFill::Edge if old_height > 0 => y.min(old_height - 1),
+                _ => y,
+            };
+            if source_row < old_height && old_width > 0 {
+                let row = &rgba[source_row * old_width * 4..][..old_width * 4];
+                out.extend_from_slice(row);
+                let extra = match fill {
+                    PadFill::Edge => row[row.len() - 4..].try_into().unwrap(),
+                    PadFill::Color(color) => color,
+                };
+                for _ in old_width..width {
+                    out.extend_from_slice(&extra);
+                }
+            } else {
+                let color = match fill {
+                    PadFill::Color(color) => color,
+                    PadFill::Edge => [0, 0, 0, 0],
+                };
+                for _ in 0..width {
+                    out.extend_from_slice(&color);
+                }
+            }
+        }
+        Ok(RawImage(width as u32, height as u32, out, PixelFormat::Rgba8, space).convert(format))
+    }
+
+    ///Write the pixels as RGBA8 into a caller-owned buffer (cleared first), leaving self intact.
+    pub fn rgba8_into(&self, out: &mut Vec<u8>) {
+        out.clear();
+        extend_rgba8(&self.2, self.3, out);
+    }
+}
+
+///A rectangle to destroy before encoding, written `WxH+X+Y[,blur|fill=#rrggbb]`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Redaction {
+    pub width: u32,
+    pub height: u32,
+    pub x: u32,
+    pub y: u32,
+    pub style: RedactStyle,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RedactStyle {
+    ///Pixelate into coarse blocks, then smooth the block edges
+    Blur,
+    Fill([u8; 4]),
+}
+
+impl FromStr for Redaction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let usage = || format!("Invalid region '{}': expected WxH+X+Y[,blur|fill=#000]", s);
+        let (geometry, style) = s.split_once(',').unwrap_or((s, "fill=#000"));
+        let (size, offset) = geometry.split_once('+').ok_or_else(usage)?;
+        let (width, height) = size.split_once('x').ok_or_else(usage)?;
+        let (x, y) = offset.split_once('+').ok_or_else(usage)?;
+        let number = |n: &str| n.trim().parse::<u32>().map_err(|_| usage());
+        let style = match style.trim() {
+            "blur" => RedactStyle::Blur,
+            "fill" => RedactStyle::Fill([0, 0, 0, 255]),
+            fill => {
+                let hex = fill.strip_prefix("fill=").ok_or_else(usage)?;
+                RedactStyle::Fill(parse_hex_color(hex).ok_or_else(usage)?)
+            }
+        };
+        Ok(Redaction {
+            width: number(width)?,
+            height: number(height)?,
+            x: number(x)?,
+            y: number(y)?,
+            style,
+        })
+    }
+}
+
+///`#rgb`, `#rrggbb` or `#rrggbbaa`, with or without the `#`
+pub fn parse_hex_color(hex: &str) -> Option<[u8; 4]> {
+    let digits: Vec<u8> = hex
+        .trim_start_matches('#')
+        .chars()
+        .map(|c| c.to_digit(16).map(|d| d as u8))
+        .collect::<Option<_>>()?;
+    let byte = |pair: &[u8]| pair[0] << 4 | pair[1];
+    match digits[..] {
+        [r, g, b] => Some([r * 17, g * 17, b * 17, 255]),
+        [_, _, _, _, _, _] => Some([
+            byte(&digits[0..2]),
+            byte(&digits[2..4]),
+            byte(&digits[4..6]),
+            255,
+        ]),
+        [_, _, _, _, _, _, _, _] => Some([0, 2, 4, 6].map(|i| byte(&digits[i..i + 2]))),
+        _ => None,
+    }
+}
+
+///Canvas sizes `--pad-to` rounds up to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PadTo {
+    ///The next power of two in each direction
+    Pow2,
+    ///The next multiple of N in each direction
+    Multiple(u32),
+}
+
+impl PadTo {
+    ///The padded size of a width x height image
+    pub fn size(self, width: u32, height: u32) -> (u32, u32) {
+        match self {
+            PadTo::Pow2 => (width.next_power_of_two(), height.next_power_of_two()),
+            PadTo::Multiple(n) => (width.div_ceil(n) * n, height.div_ceil(n) * n),
+        }
+    }
+}
+
+impl FromStr for PadTo {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let usage = || format!("Invalid size rule '{}': expected pow2 or multiple:N", s);
+        match s.trim() {
+            "pow2" => Ok(PadTo::Pow2),
+            rule => {
+                let n: u32 = rule
+                    .strip_prefix("multiple:")
+                    .and_then(|n| n.trim().parse().ok())
+                    .ok_or_else(usage)?;
+                if n == 0 {
+                    return Err(usage());
+                }
+                Ok(PadTo::Multiple(n))
+            }
+        }
+    }
+}
+
+///What fills the space `pad` adds
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PadFill {
+    ///Repeat the outermost row and column outwards, so texture filtering at the old
+    ///edge doesn't bleed in a foreign color
+    Edge,
+    Color([u8; 4]),
+}
+
+impl Default for PadFill {
+    fn default() -> Self {
+        PadFill::Color([0, 0, 0, 0])
+    }
+}
+
+impl FromStr for PadFill {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "edge" => Ok(PadFill::Edge),
+            "transparent" => Ok(PadFill::Color([0, 0, 0, 0])),
+            hex => parse_hex_color(hex).map(PadFill::Color).ok_or_else(|| {
+                format!(
+                    "Invalid fill '{}': expected edge, transparent or a color like #rrggbb",
+                    s
+                )
+            }),
         }
-        let data = bytes[8..width as usize * height as usize * 4 + 8].to_vec();
-        Ok(RawImage(width, height, data))
     }
 }
+
+fn to_rgba8(data: Vec<u8>, from: PixelFormat) -> Vec<u8> {
+    if from == PixelFormat::Rgba8 {
+        return data;
+    }
+    let mut rgba = Vec::with_capacity(data.len() / from.bytes_per_pixel() * 4);
+    extend_rgba8(&data, from, &mut rgba);
+    rgba
+}
+
+fn extend_rgba8(data: &[u8], from: PixelFormat, out: &mut Vec<u8>) {
+    let data = data.iter().copied();
+    match from {
+        PixelFormat::Rgba8 => out.extend(data),
+        PixelFormat::Rgb8 => out.extend(data.space_n(255, 3)),
+        PixelFormat::Gray8 => out.extend(
+            data.chunked_exact::<1>()
+                .reorder(GRAY_TO_RGB)
+                .flatten()
+                .space_n(255, 3),
+        ),
+        PixelFormat::GrayAlpha8 => out.extend(
+            data.chunked_exact::<2>()
+                .reorder(GRAY_ALPHA_TO_RGBA)
+                .flatten(),
+        ),
+        PixelFormat::Rgba16 => out.extend(data.step_by(2)),
+    }
+}
+
+fn from_rgba8(rgba: Vec<u8>, to: PixelFormat) -> Vec<u8> {
+    let pixels = rgba.into_iter().chunked_exact::<4>();
+    match to {
+        PixelFormat::Rgba8 => pixels.flatten().collect(),
+        PixelFormat::Rgb8 => pixels.reorder([0, 1, 2]).flatten().collect(),
+        PixelFormat::Gray8 => pixels.map(|[r, g, b, _]| luma(r, g, b)).collect(),
+        PixelFormat::GrayAlpha8 => pixels.flat_map(|[r, g, b, a]| [luma(r, g, b), a]).collect(),
+        PixelFormat::Rgba16 => pixels.flatten().flat_map(|v| [v, v]).collect(),
+    }
+}
+
+fn luma(r: u8, g: u8, b: u8) -> u8 {
+    ((r as u32 * 299 + g as u32 * 587 + b as u32 * 114 + 500) / 1000) as u8
+}
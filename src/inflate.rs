@@ -0,0 +1,333 @@
+//! zlib/DEFLATE decompression (RFC 1950/1951) as a byte iterator, so PNG image data can
+//! be unpacked as it is read, one scanline at a time, the same way QOI is decoded. The
+//! Adler-32 trailer isn't checked: every PNG chunk already carries a CRC.
+
+///Output of the last 32 KiB, which back-references copy from
+const WINDOW: usize = 1 << 15;
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+///The order code length code lengths are stored in, in a dynamic block header
+const CODE_LENGTH_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+///A canonical Huffman code as a lookup table indexed by the next `bits` bits of input
+///(least significant first, the order DEFLATE packs them). Each entry is (symbol, code
+///length), with a length of 0 marking bit patterns no code starts with.
+struct Huffman {
+    bits: u32,
+    table: Vec<(u16, u8)>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Result<Self, String> {
+        let bits = lengths.iter().copied().max().unwrap_or(0) as u32;
+        let mut count = [0u16; 16];
+        for &len in lengths {
+            count[len as usize] += 1;
+        }
+        count[0] = 0;
+        let mut next_code = [0u32; 16];
+        let mut code = 0;
+        for len in 1..16 {
+            code = (code + count[len - 1] as u32) << 1;
+            next_code[len] = code;
+        }
+        let mut table = vec![(0, 0); 1 << bits];
+        for (symbol, &len) in lengths.iter().enumerate() {
+            if len == 0 {
+                continue;
+            }
+            let code = next_code[len as usize];
+            next_code[len as usize] += 1;
+            if code >= 1 << len {
+                return Err("deflate stream has an over-subscribed Huffman code".into());
+            }
+            // Codes are stored most significant bit first, so flip them to index by the
+            // bits as they come off the stream, then fill every entry they're a prefix of
+            let reversed = code.reverse_bits() >> (32 - len as u32);
+            for fill in (reversed as usize..table.len()).step_by(1 << len) {
+                table[fill] = (symbol as u16, len);
+            }
+        }
+        Ok(Huffman { bits, table })
+    }
+}
+
+///Bits off a byte stream, least significant first
+struct Bits<I: Iterator<Item = u8>> {
+    bytes: I,
+    buffer: u64,
+    count: u32,
+    ///Bits handed out that the stream never had
+    overrun: u32,
+}
+
+impl<I: Iterator<Item = u8>> Bits<I> {
+    fn fill(&mut self, want: u32) {
+        while self.count < want {
+            match self.bytes.next() {
+                Some(byte) => self.buffer |= (byte as u64) << self.count,
+                None => self.overrun += 8,
+            }
+            self.count += 8;
+        }
+    }
+
+    fn take(&mut self, n: u32) -> Result<u32, String> {
+        self.fill(n);
+        let value = (self.buffer & ((1 << n) - 1)) as u32;
+        self.consume(n)?;
+        Ok(value)
+    }
+
+    fn consume(&mut self, n: u32) -> Result<(), String> {
+        self.buffer >>= n;
+        self.count -= n;
+        match self.overrun > self.count {
+            true => Err("deflate stream ends early".into()),
+            false => Ok(()),
+        }
+    }
+
+    fn decode(&mut self, code: &Huffman) -> Result<u16, String> {
+        self.fill(code.bits);
+        let (symbol, len) = code.table[(self.buffer & ((1 << code.bits) - 1)) as usize];
+        if len == 0 {
+            return Err("deflate stream has an invalid Huffman code".into());
+        }
+        self.consume(len as u32)?;
+        Ok(symbol)
+    }
+
+    ///Drop what's left of the current byte
+    fn align(&mut self) -> Result<(), String> {
+        self.consume(self.count % 8)
+    }
+}
+
+enum Block {
+    ///Before the zlib header
+    Start,
+    ///The next thing in the stream is a block header
+    Between,
+    ///This many more bytes to copy through uncompressed
+    Stored(u16),
+    Compressed {
+        literals: Huffman,
+        distances: Huffman,
+    },
+    Done,
+}
+
+///Decompress a zlib stream, one byte per `next`. `next` returns None at the end of the
+///stream and on corrupt input, which `error` then describes.
+pub struct Inflater<I: Iterator<Item = u8>> {
+    bits: Bits<I>,
+    block: Block,
+    ///Whether the block being read is the final one
+    last: bool,
+    window: Vec<u8>,
+    position: usize,
+    ///A back-reference still being copied out: (bytes left, distance back)
+    copy: (u16, usize),
+    error: Option<String>,
+}
+
+impl<I: Iterator<Item = u8>> Inflater<I> {
+    pub fn new(bytes: I) -> Self {
+        Inflater {
+            bits: Bits {
+                bytes,
+                buffer: 0,
+                count: 0,
+                overrun: 0,
+            },
+            block: Block::Start,
+            last: false,
+            window: vec![0; WINDOW],
+            position: 0,
+            copy: (0, 0),
+            error: None,
+        }
+    }
+
+    ///What went wrong, if the stream stopped because of an error rather than its end
+    pub fn error(&self) -> Option<&str> {
+        self.error.as_deref()
+    }
+
+    ///The compressed bytes still to be read
+    pub fn get_ref(&self) -> &I {
+        &self.bits.bytes
+    }
+
+    fn emit(&mut self, byte: u8) -> u8 {
+        self.window[self.position % WINDOW] = byte;
+        self.position += 1;
+        byte
+    }
+
+    fn step(&mut self) -> Result<Option<u8>, String> {
+        loop {
+            if self.copy.0 > 0 {
+                self.copy.0 -= 1;
+                let byte = self.window[(self.position - self.copy.1) % WINDOW];
+                return Ok(Some(self.emit(byte)));
+            }
+            match &mut self.block {
+                Block::Start => {
+                    self.read_zlib_header()?;
+                    self.block = Block::Between;
+                }
+                Block::Between if self.last => self.block = Block::Done,
+                Block::Between => {
+                    self.last = self.bits.take(1)? == 1;
+                    self.block = self.read_block_header()?;
+                }
+                Block::Stored(0) => self.block = Block::Between,
+                Block::Stored(left) => {
+                    *left -= 1;
+                    let byte = self.bits.take(8)? as u8;
+                    return Ok(Some(self.emit(byte)));
+                }
+                Block::Compressed {
+                    literals,
+                    distances,
+                } => {
+                    let symbol = self.bits.decode(literals)? as usize;
+                    if symbol < 256 {
+                        return Ok(Some(self.emit(symbol as u8)));
+                    }
+                    if symbol == 256 {
+                        self.block = Block::Between;
+                        continue;
+                    }
+                    let i = symbol - 257;
+                    if i >= LENGTH_BASE.len() {
+                        return Err(format!("deflate stream has invalid length code {}", symbol));
+                    }
+                    let length = LENGTH_BASE[i] + self.bits.take(LENGTH_EXTRA[i] as u32)? as u16;
+                    let i = self.bits.decode(distances)? as usize;
+                    if i >= DISTANCE_BASE.len() {
+                        return Err(format!("deflate stream has invalid distance code {}", i));
+                    }
+                    let distance = DISTANCE_BASE[i] as usize
+                        + self.bits.take(DISTANCE_EXTRA[i] as u32)? as usize;
+                    if distance > self.position {
+                        return Err("deflate stream refers back before its start".into());
+                    }
+                    self.copy = (length, distance);
+                }
+                Block::Done => return Ok(None),
+            }
+        }
+    }
+
+    fn read_zlib_header(&mut self) -> Result<(), String> {
+        let (method, flags) = (self.bits.take(8)?, self.bits.take(8)?);
+        if method & 0x0f != 8 || (method << 8 | flags) % 31 != 0 {
+            return Err("image data isn't a zlib stream".into());
+        }
+        if flags & 0x20 != 0 {
+            return Err("zlib preset dictionaries aren't supported".into());
+        }
+        Ok(())
+    }
+
+    ///Everything after the final-block bit
+    fn read_block_header(&mut self) -> Result<Block, String> {
+        match self.bits.take(2)? {
+            0 => {
+                self.bits.align()?;
+                let (len, inverse) = (self.bits.take(16)?, self.bits.take(16)?);
+                if len != !inverse & 0xffff {
+                    return Err("deflate stored block length doesn't match its complement".into());
+                }
+                Ok(Block::Stored(len as u16))
+            }
+            1 => {
+                let mut lengths = [0u8; 288];
+                lengths[..144].fill(8);
+                lengths[144..256].fill(9);
+                lengths[256..280].fill(7);
+                lengths[280..].fill(8);
+                Ok(Block::Compressed {
+                    literals: Huffman::new(&lengths)?,
+                    distances: Huffman::new(&[5; 30])?,
+                })
+            }
+            2 => self.read_dynamic_codes(),
+            _ => Err("deflate stream has a block of reserved type 3".into()),
+        }
+    }
+
+    ///A dynamic block starts with its two codes, themselves Huffman coded
+    fn read_dynamic_codes(&mut self) -> Result<Block, String> {
+        let literal_count = self.bits.take(5)? as usize + 257;
+        let distance_count = self.bits.take(5)? as usize + 1;
+        let length_count = self.bits.take(4)? as usize + 4;
+        let mut code_lengths = [0u8; 19];
+        for &i in &CODE_LENGTH_ORDER[..length_count] {
+            code_lengths[i] = self.bits.take(3)? as u8;
+        }
+        let code_lengths = Huffman::new(&code_lengths)?;
+
+        let mut lengths = vec![0u8; literal_count + distance_count];
+        let mut i = 0;
+        while i < lengths.len() {
+            let (len, repeat) = match self.bits.decode(&code_lengths)? {
+                len @ 0..=15 => (len as u8, 1),
+                16 => {
+                    let previous = lengths[..i]
+                        .last()
+                        .ok_or("deflate code lengths repeat before the first one")?;
+                    (*previous, 3 + self.bits.take(2)? as usize)
+                }
+                17 => (0, 3 + self.bits.take(3)? as usize),
+                _ => (0, 11 + self.bits.take(7)? as usize),
+            };
+            let run = lengths
+                .get_mut(i..i + repeat)
+                .ok_or("deflate code lengths run past the end")?;
+            run.fill(len);
+            i += repeat;
+        }
+        if lengths[256] == 0 {
+            return Err("deflate block has no end-of-block code".into());
+        }
+        Ok(Block::Compressed {
+            literals: Huffman::new(&lengths[..literal_count])?,
+            distances: Huffman::new(&lengths[literal_count..])?,
+        })
+    }
+}
+
+impl<I: Iterator<Item = u8>> Iterator for Inflater<I> {
+    type Item = u8;
+    fn next(&mut self) -> Option<u8> {
+        match self.step() {
+            Ok(byte) => byte,
+            Err(e) => {
+                self.error = Some(e);
+                self.block = Block::Done;
+                self.copy.0 = 0;
+                None
+            }
+        }
+    }
+}
@@ -0,0 +1,106 @@
+//! JPEG input through the `jpeg-decoder` crate, behind the `jpeg` feature (on by default).
+//! There is no encoder: JPEGs are a source to archive losslessly, not a target.
+
+use crate::img::{ColorSpace, Header, PixelFormat, RawImage};
+
+#[cfg(feature = "jpeg")]
+use jpeg_decoder::{Decoder, ImageInfo, PixelFormat as JpegFormat};
+
+#[cfg(feature = "jpeg")]
+fn read_info(data: &[u8]) -> Result<ImageInfo, String> {
+    let mut decoder = Decoder::new(data);
+    decoder.read_info().map_err(|e| e.to_string())?;
+    decoder
+        .info()
+        .ok_or_else(|| "JPEG has no frame header".into())
+}
+
+///Read just the header. CMYK is reported as the 3 channels it decodes to.
+#[cfg(feature = "jpeg")]
+pub fn read_header(data: &[u8]) -> Result<Header, String> {
+    let info = read_info(data)?;
+    let (channels, bit_depth) = match info.pixel_format {
+        JpegFormat::L8 => (1, 8),
+        JpegFormat::L16 => (1, 16),
+        JpegFormat::RGB24 | JpegFormat::CMYK32 => (3, 8),
+    };
+    Ok(Header {
+        width: info.width as u32,
+        height: info.height as u32,
+        channels,
+        bit_depth,
+        colorspace: None,
+    })
+}
+
+///Decode to Gray8 or Rgb8. CMYK is taken as Adobe-style inverted values and multiplied
+///out to RGB. 16-bit lossless grayscale is scaled down from the smallest precision its
+///samples fit in, since the decoder doesn't report the declared one.
+#[cfg(feature = "jpeg")]
+#[tracing::instrument(name = "jpeg::decode", skip_all)]
+pub fn parse_img(data: &[u8]) -> Result<RawImage, String> {
+    let mut decoder = Decoder::new(data);
+    let pixels = decoder.decode().map_err(|e| e.to_string())?;
+    let info = decoder.info().ok_or("JPEG has no frame header")?;
+    let (width, height) = (info.width as u32, info.height as u32);
+    Ok(match info.pixel_format {
+        JpegFormat::L8 => RawImage(width, height, pixels, PixelFormat::Gray8, ColorSpace::Srgb),
+        JpegFormat::RGB24 => RawImage(width, height, pixels, PixelFormat::Rgb8, ColorSpace::Srgb),
+        JpegFormat::CMYK32 => {
+            let rgb = pixels
+                .chunks_exact(4)
+                .flat_map(|cmyk| {
+                    let k = cmyk[3] as u16;
+                    [0, 1, 2].map(|c| (cmyk[c] as u16 * k / 255) as u8)
+                })
+                .collect();
+            RawImage(width, height, rgb, PixelFormat::Rgb8, ColorSpace::Srgb)
+        }
+        JpegFormat::L16 => {
+            let samples: Vec<u16> = pixels
+                .chunks_exact(2)
+                .map(|v| u16::from_ne_bytes([v[0], v[1]]))
+                .collect();
+            let bits = (16 - samples.iter().max().unwrap_or(&0).leading_zeros()).max(9);
+            let max = (1u32 << bits) - 1;
+            let gray = samples
+                .iter()
+                .map(|&v| ((v as u32 * 255 + max / 2) / max) as u8)
+                .collect();
+            RawImage(width, height, gray, PixelFormat::Gray8, ColorSpace::Srgb)
+        }
+    })
+}
+
+///The EXIF block from the APP1 segment, which doesn't need the decoder
+pub fn exif(data: &[u8]) -> Option<&[u8]> {
+    let mut at = 2; // After the start-of-image marker
+    while let [0xff, marker, high, low, ..] = *data.get(at..)? {
+        // Image data follows start-of-scan, and metadata comes before it
+        if marker == 0xda {
+            return None;
+        }
+        let len = u16::from_be_bytes([high, low]) as usize;
+        let segment = data.get(at + 4..at + 2 + len)?;
+        if marker == 0xe1
+            && let Some(exif) = segment.strip_prefix(b"Exif\0\0")
+        {
+            return Some(exif);
+        }
+        at += 2 + len;
+    }
+    None
+}
+
+#[cfg(not(feature = "jpeg"))]
+const NO_SUPPORT: &str = "this build has no JPEG support (rebuild with the jpeg feature)";
+
+#[cfg(not(feature = "jpeg"))]
+pub fn read_header(_data: &[u8]) -> Result<Header, String> {
+    Err(NO_SUPPORT.into())
+}
+
+#[cfg(not(feature = "jpeg"))]
+pub fn parse_img(_data: &[u8]) -> Result<RawImage, String> {
+    Err(NO_SUPPORT.into())
+}
@@ -0,0 +1,27 @@
+//! QOI, PPM and PNG codecs behind a single in-memory image type.
+//!
+//! Every codec turns file bytes into a [`RawImage`] (width, height, interleaved pixels and
+//! their [`PixelFormat`]) and back. The crate root re-exports the QOI pair, so the common
+//! case is `qoi::decode(&bytes)` followed by `qoi::encode(image)`. The other formats live
+//! in their own modules with the same `parse_img`/`encode_img` shape.
+//!
+//! Nothing here touches a window: the viewer and the command line live in the binary.
+
+#![allow(unused)]
+
+pub mod farbfeld;
+pub mod filter;
+pub mod format;
+pub mod img;
+pub mod inflate;
+pub mod jpeg;
+pub mod pam;
+pub mod phash;
+pub mod png;
+pub mod ppm;
+pub mod qoi;
+pub mod stream;
+pub mod tiff;
+
+pub use img::{ColorSpace, PixelFormat, RawImage};
+pub use qoi::{QoiChannels, QoiError, decode, decode_into, decode_strict, encode_img as encode};
@@ -0,0 +1,202 @@
+//! Checks for QOI files that decode, but that a careful encoder would not have
+//! written. Meant for triaging files from other encoders.
+
+use crate::img::{ColorSpace, Dimensions, PixelFormat, RawImage};
+use crate::qoi::{END_MARKER, START_INDEX, START_PIXEL, hash};
+
+///Something suspicious, with the byte offset of the chunk (or header field) responsible
+pub struct Finding {
+    pub offset: usize,
+    pub message: String,
+}
+
+pub struct Report<'a> {
+    pub findings: Vec<Finding>,
+    ///Whatever could be decoded, with pixels missing at the end filled in from the
+    ///last one. None if the header is too broken to say what the image is, it has no
+    ///pixels, or the data is far too short to have held them.
+    pub image: Option<RawImage>,
+    pub colorspace: u8,
+    ///Bytes after the end marker, which some encoders use for metadata
+    pub trailer: &'a [u8],
+}
+
+///Walk a .qoi file chunk by chunk, decoding as leniently as possible and noting
+///everything that is out of spec or needlessly wasteful
+pub fn lint(data: &[u8]) -> Report<'_> {
+    let mut findings = vec![];
+    let mut flag = |offset: usize, message: String| findings.push(Finding { offset, message });
+
+    if data.len() < 14 {
+        flag(
+            0,
+            format!("only {} bytes, too short for a header", data.len()),
+        );
+        return Report::broken(findings);
+    }
+    if &data[..4] != b"qoif" {
+        flag(0, "magic is not 'qoif'".into());
+        return Report::broken(findings);
+    }
+    let width = u32::from_be_bytes(data[4..8].try_into().unwrap());
+    let height = u32::from_be_bytes(data[8..12].try_into().unwrap());
+    let (channels, colorspace) = (data[12], data[13]);
+    if let Err(e) = Dimensions::new(width, height) {
+        flag(4, format!("declared size: {}", e));
+        // An empty image can still be checked for its end marker
+        if width != 0 && height != 0 {
+            return Report::broken(findings);
+        }
+    }
+    let pix_count = width as u64 * height as u64;
+    if channels != 3 && channels != 4 {
+        flag(12, format!("channels is {}, not 3 or 4", channels));
+    }
+    if colorspace > 1 {
+        flag(13, format!("colorspace is {}, not 0 or 1", colorspace));
+    }
+
+    let mut pixel = START_PIXEL;
+    let mut seen = START_INDEX;
+    let mut written = [false; 64];
+    let mut has_alpha = false;
+    let mut decoded = 0u64;
+    let mut pixels = vec![];
+    let mut offset = 14;
+    while decoded < pix_count {
+        let Some(&byte) = data.get(offset) else {
+            flag(
+                offset,
+                format!("data ends after {} of {} pixels", decoded, pix_count),
+            );
+            break;
+        };
+        let len = match byte {
+            0b1111_1110 => 4,
+            0b1111_1111 => 5,
+            _ if byte >> 6 == 0b10 => 2,
+            _ => 1,
+        };
+        let Some(chunk) = data.get(offset..offset + len) else {
+            flag(offset, "chunk is cut off by the end of the file".into());
+            break;
+        };
+
+        let [r, g, b, a] = pixel;
+        let mut run = 1;
+        match byte {
+            0b1111_1110 => pixel = [chunk[1], chunk[2], chunk[3], a],
+            0b1111_1111 => {
+                if chunk[4] == a {
+                    flag(offset, "RGBA op with unchanged alpha; RGB would do".into());
+                }
+                pixel = [chunk[1], chunk[2], chunk[3], chunk[4]];
+            }
+            _ => match byte >> 6 {
+                0b00 => {
+                    let slot = byte as usize;
+                    // The reference encoder reads slot 0 before writing it for transparent black
+                    if !written[slot] && slot != 0 {
+                        flag(
+                            offset,
+                            format!("INDEX {} refers to a slot never written", slot),
+                        );
+                    }
+                    pixel = seen[slot];
+                }
+                0b01 => {
+                    pixel = [
+                        r.wrapping_add((byte >> 4) & 0b11).wrapping_sub(2),
+                        g.wrapping_add((byte >> 2) & 0b11).wrapping_sub(2),
+                        b.wrapping_add(byte & 0b11).wrapping_sub(2),
+                        a,
+                    ];
+                }
+                0b10 => {
+                    let dg = (byte & 0b0011_1111).wrapping_sub(32);
+                    pixel = [
+                        r.wrapping_add(dg)
+                            .wrapping_add(chunk[1] >> 4)
+                            .wrapping_sub(8),
+                        g.wrapping_add(dg),
+                        b.wrapping_add(dg)
+                            .wrapping_add(chunk[1] & 0b1111)
+                            .wrapping_sub(8),
+                        a,
+                    ];
+                }
+                _ => {
+                    run = (byte & 0b0011_1111) as u64 + 1;
+                    if decoded + run > pix_count {
+                        flag(
+                            offset,
+                            format!(
+                                "run of {} crosses the end of the image by {} pixels",
+                                run,
+                                decoded + run - pix_count
+                            ),
+                        );
+                    }
+                }
+            },
+        }
+        let slot = hash(pixel);
+        seen[slot] = pixel;
+        written[slot] = true;
+        has_alpha |= pixel[3] != 255;
+        for _ in 0..run.min(pix_count - decoded) {
+            pixels.extend_from_slice(&pixel);
+        }
+        decoded += run;
+        offset += len;
+    }
+
+    if channels == 3 && has_alpha {
+        flag(
+            12,
+            "header says 3 channels, but pixels are not all opaque".into(),
+        );
+    }
+    let rest = &data[offset..];
+    let mut trailer: &[u8] = &[];
+    if decoded < pix_count {
+        // Already reported as truncated
+    } else if !rest.starts_with(&END_MARKER) {
+        flag(offset, "missing end marker".into());
+    } else if rest.len() > END_MARKER.len() {
+        trailer = &rest[END_MARKER.len()..];
+        flag(
+            offset + END_MARKER.len(),
+            format!("{} bytes after the end marker", trailer.len()),
+        );
+    }
+    // A run op holds at most 62 pixels, so a header asking for more than that can't be
+    // taken at its word: filling it in would make a huge image out of a few bytes
+    let fillable = pix_count > 0 && pix_count <= data.len() as u64 * 62;
+    while fillable && pixels.len() < pix_count as usize * 4 {
+        pixels.extend_from_slice(&pixel);
+    }
+    Report {
+        findings,
+        image: fillable.then_some(RawImage(
+            width,
+            height,
+            pixels,
+            PixelFormat::Rgba8,
+            ColorSpace::Srgb,
+        )),
+        colorspace,
+        trailer,
+    }
+}
+
+impl Report<'_> {
+    fn broken(findings: Vec<Finding>) -> Self {
+        Report {
+            findings,
+            image: None,
+            colorspace: 0,
+            trailer: &[],
+        }
+    }
+}
@@ -1,18 +1,39 @@
 #![allow(unused)]
 
+#[cfg(feature = "alloc-stats")]
+mod alloc;
+mod audit;
+mod bins;
+mod cancel;
 mod cli;
 mod commands;
+mod corpus;
+mod dedupe;
+mod examples;
 mod gfx;
-mod img;
-mod png;
-mod ppm;
-mod qoi;
-mod util;
+mod gif;
+mod helpers;
+mod lint;
+mod motion;
+mod palette;
+mod progress;
+mod reduce;
+mod rename;
+mod shell;
+mod slice;
+mod term;
+mod timings;
+use ::qoi::{farbfeld, filter, format, img, jpeg, pam, phash, png, ppm, qoi, stream, tiff};
 use crate::cli::Cli;
 use clap::Parser;
 use std::fs;
+use std::process::ExitCode;
+use std::sync::Arc;
 
-fn main() {
-    let command = Cli::parse();
-    command.run();
+fn main() -> ExitCode {
+    format::register(Arc::new(gif::Gif));
+    helpers::register_overrides();
+    format::discover_with(helpers::discover);
+    let command = Cli::parse_with_examples();
+    command.run()
 }
@@ -0,0 +1,75 @@
+//! Background subtraction over a frame sequence, for `motion`
+
+use crate::img::{ColorSpace, PixelFormat, RawImage};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum MotionOutput {
+    ///Grayscale masks: white where something moved, black elsewhere
+    Mask,
+    ///The frames themselves, with static pixels set to transparent black so they
+    ///compress to long runs
+    Zeroed,
+}
+
+///A running average of every pixel, which slowly absorbs anything that stops moving
+pub struct Background {
+    width: u32,
+    height: u32,
+    mean: Vec<f32>,
+    ///How far each frame pulls the average towards itself, in 0..=1
+    learning_rate: f32,
+    ///How far any of R, G or B must be from the average to count as motion
+    threshold: u8,
+}
+
+impl Background {
+    ///Start from the first frame, so nothing in it counts as motion
+    pub fn new(first: &RawImage, learning_rate: f32, threshold: u8) -> Self {
+        let RawImage(width, height, rgba, ..) = first.clone().convert(PixelFormat::Rgba8);
+        Background {
+            width,
+            height,
+            mean: rgba.iter().map(|&v| v as f32).collect(),
+            learning_rate,
+            threshold,
+        }
+    }
+
+    ///Compare a frame against the model and fold it in. Returns the frame in the requested
+    ///form and how many of its pixels moved.
+    pub fn apply(
+        &mut self,
+        frame: RawImage,
+        output: MotionOutput,
+    ) -> Result<(RawImage, usize), String> {
+        if (frame.0, frame.1) != (self.width, self.height) {
+            return Err(format!(
+                "{}x{} doesn't match the first frame's {}x{}",
+                frame.0, frame.1, self.width, self.height
+            ));
+        }
+        let RawImage(width, height, mut rgba, _, space) = frame.convert(PixelFormat::Rgba8);
+        let mut mask = Vec::with_capacity(rgba.len() / 4);
+        for (pixel, mean) in rgba.chunks_exact_mut(4).zip(self.mean.chunks_exact_mut(4)) {
+            let moved = pixel[..3]
+                .iter()
+                .zip(&mean[..3])
+                .any(|(&v, &m)| (v as f32 - m).abs() > self.threshold as f32);
+            for (m, &v) in mean.iter_mut().zip(pixel.iter()) {
+                *m += (v as f32 - *m) * self.learning_rate;
+            }
+            if !moved && output == MotionOutput::Zeroed {
+                pixel.fill(0);
+            }
+            mask.push(if moved { 255 } else { 0 });
+        }
+        let moving = mask.iter().filter(|&&m| m == 255).count();
+        let out = match output {
+            MotionOutput::Mask => {
+                RawImage(width, height, mask, PixelFormat::Gray8, ColorSpace::Srgb)
+            }
+            MotionOutput::Zeroed => RawImage(width, height, rgba, PixelFormat::Rgba8, space),
+        };
+        Ok((out, moving))
+    }
+}
@@ -0,0 +1,113 @@
+//! Palettes pulled out of an image for `palette-extract`, in formats paint programs import
+
+use crate::gif::{sample_stride, train_palette};
+use crate::img::{ColorSpace, PixelFormat, RawImage};
+use std::collections::HashMap;
+
+///The `colors` most representative colors of an image, most used first. Images that
+///already use no more than that many colors get exactly those back. Fully transparent
+///pixels are ignored.
+pub fn extract(img: RawImage, colors: usize) -> Vec<[u8; 3]> {
+    let RawImage(width, height, rgba, ..) = img.convert(PixelFormat::Rgba8);
+    let mut counts: HashMap<[u8; 3], usize> = HashMap::new();
+    for pixel in rgba.chunks_exact(4).filter(|pixel| pixel[3] != 0) {
+        *counts.entry([pixel[0], pixel[1], pixel[2]]).or_default() += 1;
+    }
+    if counts.len() > colors {
+        counts = quantize(&rgba, width, height, colors);
+    }
+    let mut palette: Vec<([u8; 3], usize)> = counts.into_iter().collect();
+    palette.sort_unstable_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    palette.into_iter().map(|(color, _)| color).collect()
+}
+
+///Train the usual 256-entry NeuQuant palette, then merge the closest pair of entries
+///(weighted by how many pixels each stands for) until only `colors` are left. NeuQuant
+///itself gets unreliable below 64 entries.
+fn quantize(rgba: &[u8], width: u32, height: u32, colors: usize) -> HashMap<[u8; 3], usize> {
+    let stride = sample_stride(1, width, height);
+    let samples: Vec<u8> = rgba
+        .chunks_exact(4)
+        .filter(|pixel| pixel[3] != 0)
+        .step_by(stride)
+        .flat_map(|pixel| [pixel[0], pixel[1], pixel[2], 255])
+        .collect();
+    let nq = train_palette(&samples);
+
+    let mut weights = vec![0usize; 256];
+    for pixel in samples.chunks_exact(4) {
+        weights[nq.index_of(pixel)] += 1;
+    }
+    // (mean color, pixel count) for every entry that some pixel actually maps to
+    let mut clusters: Vec<([f64; 3], usize)> = nq
+        .color_map_rgb()
+        .chunks_exact(3)
+        .zip(weights)
+        .filter(|&(_, weight)| weight > 0)
+        .map(|(rgb, weight)| ([rgb[0], rgb[1], rgb[2]].map(f64::from), weight))
+        .collect();
+
+    while clusters.len() > colors {
+        // Ward's criterion: merging the pair that adds the least squared error
+        let cost = |(a, wa): &([f64; 3], usize), (b, wb): &([f64; 3], usize)| {
+            let distance: f64 = a.iter().zip(b).map(|(x, y)| (x - y) * (x - y)).sum();
+            distance * (wa * wb) as f64 / (wa + wb) as f64
+        };
+        let mut best = (f64::INFINITY, 0, 1);
+        for i in 0..clusters.len() {
+            for j in i + 1..clusters.len() {
+                let c = cost(&clusters[i], &clusters[j]);
+                if c < best.0 {
+                    best = (c, i, j);
+                }
+            }
+        }
+        let (_, i, j) = best;
+        let (b, wb) = clusters.swap_remove(j);
+        let (a, wa) = clusters[i];
+        let total = wa + wb;
+        let mean = std::array::from_fn(|c| (a[c] * wa as f64 + b[c] * wb as f64) / total as f64);
+        clusters[i] = (mean, total);
+    }
+
+    let mut counts = HashMap::new();
+    for (mean, weight) in clusters {
+        *counts.entry(mean.map(|c| c.round() as u8)).or_default() += weight;
+    }
+    counts
+}
+
+///GIMP palette (.gpl), which Krita, Inkscape and Aseprite read too
+pub fn encode_gpl(colors: &[[u8; 3]], name: &str) -> Vec<u8> {
+    let mut out = format!("GIMP Palette\nName: {}\nColumns: 8\n#\n", name);
+    for [r, g, b] in colors {
+        out.push_str(&format!(
+            "{:>3} {:>3} {:>3}\t#{:02x}{:02x}{:02x}\n",
+            r, g, b, r, g, b
+        ));
+    }
+    out.into_bytes()
+}
+
+///Adobe Color Table (.act): 256 RGB triplets, then the number of colors in use and
+///0xFFFF for "no transparent index"
+pub fn encode_act(colors: &[[u8; 3]]) -> Vec<u8> {
+    let mut out = vec![0; 256 * 3];
+    for (slot, color) in out.chunks_exact_mut(3).zip(colors) {
+        slot.copy_from_slice(color);
+    }
+    out.extend_from_slice(&(colors.len() as u16).to_be_bytes());
+    out.extend_from_slice(&[0xFF, 0xFF]);
+    out
+}
+
+///One pixel per color in a single row, the layout palette sites like Lospec use
+pub fn to_image(colors: &[[u8; 3]]) -> RawImage {
+    RawImage(
+        colors.len() as u32,
+        1,
+        colors.concat(),
+        PixelFormat::Rgb8,
+        ColorSpace::Srgb,
+    )
+}
@@ -0,0 +1,146 @@
+//! Netpbm PAM (P7): a header of `KEY value` lines closed by `ENDHDR`, then samples with
+//! one or two bytes each depending on MAXVAL. Unlike P6 it can carry alpha, so this is
+//! the netpbm format to use when transparency has to survive.
+
+use crate::img::{ColorSpace, Dimensions, Header, PixelFormat, RawImage};
+use crate::stream::TakeVec;
+
+///The header fields that matter for decoding
+struct PamHeader {
+    width: u32,
+    height: u32,
+    depth: u32,
+    maxval: u32,
+}
+
+///Read header lines up to and including ENDHDR. TUPLTYPE is optional and only
+///informational: DEPTH alone decides the layout.
+fn parse_header(stream: &mut impl Iterator<Item = u8>) -> Result<PamHeader, String> {
+    let mut lines = std::iter::from_fn(|| {
+        let mut line = vec![];
+        loop {
+            match stream.next() {
+                None if line.is_empty() => return None,
+                None | Some(b'\n') => break,
+                Some(b) => line.push(b),
+            }
+        }
+        Some(String::from_utf8_lossy(&line).trim().to_string())
+    });
+    if lines.next().as_deref() != Some("P7") {
+        return Err("not a PAM file (no P7 magic)".into());
+    }
+    let (mut width, mut height, mut depth, mut maxval) = (None, None, None, None);
+    loop {
+        let line = lines.next().ok_or("PAM header ends before ENDHDR")?;
+        let (key, value) = line.split_once(char::is_whitespace).unwrap_or((&line, ""));
+        let number = || {
+            value
+                .trim()
+                .parse::<u32>()
+                .map_err(|_| format!("PAM {} isn't a number: '{}'", key, value.trim()))
+        };
+        match key {
+            "ENDHDR" => break,
+            "WIDTH" => width = Some(number()?),
+            "HEIGHT" => height = Some(number()?),
+            "DEPTH" => depth = Some(number()?),
+            "MAXVAL" => maxval = Some(number()?),
+            _ => {} // TUPLTYPE, comments and empty lines
+        }
+    }
+    let header = PamHeader {
+        width: width.ok_or("PAM header has no WIDTH")?,
+        height: height.ok_or("PAM header has no HEIGHT")?,
+        depth: depth.ok_or("PAM header has no DEPTH")?,
+        maxval: maxval.ok_or("PAM header has no MAXVAL")?,
+    };
+    if !(1..=4).contains(&header.depth) {
+        return Err(format!("PAM DEPTH {} isn't 1 to 4", header.depth));
+    }
+    if !(1..=65535).contains(&header.maxval) {
+        return Err(format!("PAM MAXVAL {} isn't 1 to 65535", header.maxval));
+    }
+    Ok(header)
+}
+
+fn format_for_depth(depth: u32) -> PixelFormat {
+    match depth {
+        1 => PixelFormat::Gray8,
+        2 => PixelFormat::GrayAlpha8,
+        3 => PixelFormat::Rgb8,
+        _ => PixelFormat::Rgba8,
+    }
+}
+
+///Read just the header
+pub fn read_header(data: &[u8]) -> Result<Header, String> {
+    let header = parse_header(&mut data.iter().copied())?;
+    Ok(Header {
+        width: header.width,
+        height: header.height,
+        channels: header.depth as u8,
+        bit_depth: if header.maxval < 256 { 8 } else { 16 },
+        colorspace: None,
+    })
+}
+
+///Decode to 8 bits per channel, scaling samples from 0..=MAXVAL. Full 16-bit RGBA
+///(MAXVAL 65535, DEPTH 4) is kept as Rgba16.
+#[tracing::instrument(name = "pam::decode", skip_all)]
+pub fn parse_img(data: impl Iterator<Item = u8>) -> Result<RawImage, String> {
+    let mut stream = data;
+    let header = parse_header(&mut stream)?;
+    let bytes_per_sample = if header.maxval < 256 { 1 } else { 2 };
+    let size = Dimensions::new(header.width, header.height).map_err(|e| format!("PAM {}", e))?;
+    // At most 4 samples of 2 bytes, which Dimensions allows for
+    let samples = stream
+        .take_vec_exact(size.pixels() * header.depth as usize * bytes_per_sample)
+        .ok_or("PAM data ends before the last pixel")?;
+
+    if header.depth == 4 && header.maxval == 65535 {
+        return Ok(RawImage(
+            header.width,
+            header.height,
+            samples,
+            PixelFormat::Rgba16,
+            ColorSpace::Srgb,
+        ));
+    }
+    let maxval = header.maxval;
+    let scale = |v: u32| ((v.min(maxval) * 255 + maxval / 2) / maxval) as u8;
+    let pixels = match bytes_per_sample {
+        1 if maxval == 255 => samples,
+        1 => samples.iter().map(|&v| scale(v as u32)).collect(),
+        _ => samples
+            .chunks_exact(2)
+            .map(|v| scale(u16::from_be_bytes([v[0], v[1]]) as u32))
+            .collect(),
+    };
+    Ok(RawImage(
+        header.width,
+        header.height,
+        pixels,
+        format_for_depth(header.depth),
+        ColorSpace::Srgb,
+    ))
+}
+
+#[tracing::instrument(name = "pam::encode", skip_all, fields(width = img.0, height = img.1))]
+pub fn encode_img(img: RawImage) -> Vec<u8> {
+    let (depth, maxval, tupltype) = match img.format() {
+        PixelFormat::Gray8 => (1, 255, "GRAYSCALE"),
+        PixelFormat::GrayAlpha8 => (2, 255, "GRAYSCALE_ALPHA"),
+        PixelFormat::Rgb8 => (3, 255, "RGB"),
+        PixelFormat::Rgba8 => (4, 255, "RGB_ALPHA"),
+        PixelFormat::Rgba16 => (4, 65535, "RGB_ALPHA"),
+    };
+    let RawImage(width, height, pixels, ..) = img;
+    let mut out = format!(
+        "P7\nWIDTH {}\nHEIGHT {}\nDEPTH {}\nMAXVAL {}\nTUPLTYPE {}\nENDHDR\n",
+        width, height, depth, maxval, tupltype
+    )
+    .into_bytes();
+    out.extend(pixels);
+    out
+}
@@ -0,0 +1,60 @@
+//! A 64-bit perceptual hash for finding images that look alike. Unlike a hash of the
+//! bytes, it barely moves when an image is rescaled, recompressed or slightly edited, so
+//! the number of bits two hashes differ in ([`distance`]) says how similar they are:
+//! 0 to about 5 is the same picture, and unrelated images differ in around 32.
+//!
+//! The algorithm is a difference hash (dHash), fixed as follows so that hashes can be
+//! stored and compared across runs, machines and versions of this crate:
+//! 1. Convert to RGBA8 and ignore alpha.
+//! 2. Split the image into a grid 9 cells wide and 8 high. Cell `c` of `n` along a side
+//!    of `len` pixels covers `c * len / n` up to, not including, `(c + 1) * len / n`, or
+//!    just its first pixel if that range is empty. Divisions round down.
+//! 3. Give each cell the mean of `299 R + 587 G + 114 B` over its pixels, rounded down.
+//! 4. Going through the rows top to bottom and each row left to right, compare every
+//!    cell with the one to its right: 1 if the left one is brighter, 0 otherwise. That
+//!    gives 8 bits per row, and the first comparison is the most significant bit.
+//!
+//! An empty image hashes to 0. Hashes are usually printed as 16 hex digits.
+
+use crate::img::RawImage;
+
+///The perceptual hash of an image, as described in the module docs
+#[tracing::instrument(name = "phash::hash", skip_all)]
+pub fn hash(img: &RawImage) -> u64 {
+    let (width, height) = (img.0 as usize, img.1 as usize);
+    if width == 0 || height == 0 {
+        return 0;
+    }
+    let mut rgba = vec![];
+    img.rgba8_into(&mut rgba);
+    let span = |cell: usize, cells: usize, len: usize| {
+        let start = cell * len / cells;
+        start..((cell + 1) * len / cells).max(start + 1)
+    };
+    let mut thumb = [[0u64; 9]; 8];
+    for (cy, row) in thumb.iter_mut().enumerate() {
+        for (cx, cell) in row.iter_mut().enumerate() {
+            let (mut sum, mut count) = (0, 0);
+            for y in span(cy, 8, height) {
+                for x in span(cx, 9, width) {
+                    let p = &rgba[(y * width + x) * 4..];
+                    sum += p[0] as u64 * 299 + p[1] as u64 * 587 + p[2] as u64 * 114;
+                    count += 1;
+                }
+            }
+            *cell = sum / count;
+        }
+    }
+    let mut hash = 0;
+    for row in thumb {
+        for pair in row.windows(2) {
+            hash = hash << 1 | (pair[0] > pair[1]) as u64;
+        }
+    }
+    hash
+}
+
+///How many bits two hashes differ in
+pub fn distance(a: u64, b: u64) -> u32 {
+    (a ^ b).count_ones()
+}
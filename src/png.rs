@@ -1,11 +1,11 @@
-use crate::img::RawImage;
-use crate::util::{TakeArray, TakeVec};
-use std::iter::Peekable;
+use crate::img::{ColorSpace, Dimensions, Header, PixelFormat, RawImage};
+use crate::inflate::Inflater;
+use crate::qoi;
+use crate::stream::{TakeArray, TakeVec};
 
 use core::convert::TryInto;
 use crc::{CRC_32_ISO_HDLC, Crc};
 use std::fmt::{Debug, Display};
-use std::slice::Chunks;
 use std::{
     fmt,
     str::{FromStr, Utf8Error, from_utf8},
@@ -91,7 +91,7 @@ fn is_valid_chunk_type(data: [u8; 4]) -> bool {
             return false;
         }
     }
-    return true;
+    true
 }
 
 impl Chunk {
@@ -156,104 +156,840 @@ pub fn as_bytes(chunks: Vec<Chunk>) -> Vec<u8> {
         .collect()
 }
 
-pub fn parse_img(data: impl Iterator<Item = u8>) -> RawImage {
-    use std::io::Cursor;
-    
-    let bytes: Vec<u8> = data.collect();
-    let cursor = Cursor::new(bytes);
-    
-    let decoder = png::Decoder::new(cursor);
-    let mut reader = decoder.read_info().unwrap();
-    
-    let mut buf = vec![0; reader.output_buffer_size()];
-    let info = reader.next_frame(&mut buf).unwrap();
-    
-    let width = info.width;
-    let height = info.height;
-    
-    let rgba_buf = match info.color_type {
-        png::ColorType::Rgba => buf,
-        png::ColorType::Rgb => {
-            let mut rgba_buf = Vec::with_capacity(buf.len() * 4 / 3);
-            for chunk in buf.chunks(3) {
-                rgba_buf.extend_from_slice(chunk);
-                rgba_buf.push(255);
-            }
-            rgba_buf
-        }
-        png::ColorType::Grayscale => {
-            let mut rgba_buf = Vec::with_capacity(buf.len() * 4);
-            for &gray in &buf {
-                rgba_buf.extend_from_slice(&[gray, gray, gray, 255]);
+///Decode a PNG with the streaming pipeline: bytes are parsed into chunks, and the image
+///data in them inflated and unfiltered a scanline at a time
+#[tracing::instrument(name = "png::decode", skip_all)]
+pub fn parse_img(data: impl Iterator<Item = u8>) -> Result<RawImage, String> {
+    let mut stream = data;
+    if stream.take_array() != Some(STANDARD_HEADER) {
+        return Err("not a PNG file (bad signature)".into());
+    }
+    let mut rows = stream.parse().interpret()?;
+    let header = rows.header;
+    let format = rows.format()?;
+    let space = rows.transfer.as_ref().and_then(Transfer::colorspace);
+    let channels = header.channels();
+    let widen = header.bit_depth == 16 && channels != 4;
+    let width = header.size.width() as usize;
+    // Grown row by row rather than sized from IHDR, which can claim far more than the data holds
+    let mut pixels = vec![];
+    let palette = std::mem::take(&mut rows.palette);
+    for row in rows.by_ref() {
+        match header.color_type {
+            3 => {
+                for index in samples(&row, header.bit_depth).take(width) {
+                    let color = palette.get(index as usize).ok_or_else(|| {
+                        format!(
+                            "PNG pixel uses palette entry {} of {}",
+                            index,
+                            palette.len()
+                        )
+                    })?;
+                    pixels.extend_from_slice(&color[..format.bytes_per_pixel()]);
+                }
             }
-            rgba_buf
-        }
-        png::ColorType::GrayscaleAlpha => {
-            let mut rgba_buf = Vec::with_capacity(buf.len() * 2);
-            for chunk in buf.chunks(2) {
-                let gray = chunk[0];
-                let alpha = chunk[1];
-                rgba_buf.extend_from_slice(&[gray, gray, gray, alpha]);
+            _ if header.bit_depth < 8 => {
+                let scale = 255 / ((1 << header.bit_depth) - 1);
+                pixels.extend(
+                    samples(&row, header.bit_depth)
+                        .take(width)
+                        .map(|v| v * scale),
+                );
             }
-            rgba_buf
+            _ if widen => pixels.extend(widen_to_rgba16(&row, channels)),
+            _ => pixels.extend_from_slice(&row),
         }
-        _ => panic!("Unsupported PNG color type"),
-    };
-    
-    RawImage(width, height, rgba_buf)
+    }
+    if let Some(e) = rows.error {
+        return Err(e);
+    }
+    Ok(RawImage(
+        header.size.width(),
+        header.size.height(),
+        pixels,
+        format,
+        space.unwrap_or_default(),
+    ))
 }
 
-pub fn encode_img(img: RawImage) -> Vec<u8> {
+#[tracing::instrument(name = "png::encode", skip_all, fields(width = img.0, height = img.1))]
+pub fn encode_img(img: RawImage) -> Result<Vec<u8>, String> {
     use std::io::Cursor;
     
-    let RawImage(width, height, mut rgba_data) = img;
+    let RawImage(width, height, pixel_data, format, _) = img;
+    // PNG has no way to store an empty image
+    let size = Dimensions::new(width, height).map_err(|e| format!("can't write a PNG: {}", e))?;
     
-    let expected_len = (width * height * 4) as usize;
-    if rgba_data.len() != expected_len {
-        println!("Warning: RGBA data length mismatch. Expected: {}, Actual: {}", expected_len, rgba_data.len());
-        if rgba_data.len() < expected_len {
-            rgba_data.resize(expected_len, 255);
-        } else {
-            rgba_data.truncate(expected_len);
-        }
+    let expected_len = size.byte_len(format);
+    if pixel_data.len() != expected_len {
+        return Err(format!(
+            "can't write a PNG: a {}x{} image needs {} bytes of pixels, not {}",
+            width, height, expected_len, pixel_data.len()
+        ));
     }
     
     let mut buf = Vec::new();
     let cursor = Cursor::new(&mut buf);
     
     let mut encoder = png::Encoder::new(cursor, width, height);
-    encoder.set_color(png::ColorType::Rgba);
-    encoder.set_depth(png::BitDepth::Eight);
+    let (color, depth) = match format {
+        PixelFormat::Rgba8 => (png::ColorType::Rgba, png::BitDepth::Eight),
+        PixelFormat::Rgb8 => (png::ColorType::Rgb, png::BitDepth::Eight),
+        PixelFormat::Gray8 => (png::ColorType::Grayscale, png::BitDepth::Eight),
+        PixelFormat::GrayAlpha8 => (png::ColorType::GrayscaleAlpha, png::BitDepth::Eight),
+        PixelFormat::Rgba16 => (png::ColorType::Rgba, png::BitDepth::Sixteen),
+    };
+    encoder.set_color(color);
+    encoder.set_depth(depth);
     
-    let mut writer = encoder.write_header().unwrap();
-    writer.write_image_data(&rgba_data).unwrap();
-    writer.finish().unwrap();
+    let mut writer = encoder.write_header().map_err(|e| e.to_string())?;
+    writer.write_image_data(&pixel_data).map_err(|e| e.to_string())?;
+    writer.finish().map_err(|e| e.to_string())?;
     
-    buf
+    Ok(buf)
+}
+
+///The chunks that carry something other than pixels: text, timestamps, color profiles
+///and anything else not critical to decoding
+pub fn ancillary_chunks(data: &[u8]) -> Result<Vec<Chunk>, String> {
+    let mut chunks = data.iter().copied().skip(STANDARD_HEADER.len()).parse();
+    let ancillary = chunks
+        .by_ref()
+        .filter(|chunk| !chunk.chunk_type().is_critical())
+        .collect();
+    chunks.checked(ancillary)
+}
+
+///Ancillary chunks that stay true when the pixels are re-encoded, or edited: text,
+///physical pixel size, modification time and [`COLOR_CHUNKS`]
+pub const CARRIED_CHUNKS: [&[u8; 4]; 9] = [
+    b"tEXt", b"zTXt", b"iTXt", b"pHYs", b"tIME", b"gAMA", b"cHRM", b"sRGB", b"iCCP",
+];
+
+///The chunks saying how samples map to light, which stop being true if pixels are
+///converted to another color space
+pub const COLOR_CHUNKS: [&[u8; 4]; 4] = [b"gAMA", b"cHRM", b"sRGB", b"iCCP"];
+
+///The chunks of `data` worth carrying into a re-encoded copy, see [`CARRIED_CHUNKS`]
+pub fn carried_chunks(data: &[u8]) -> Result<Vec<Chunk>, String> {
+    Ok(ancillary_chunks(data)?
+        .into_iter()
+        .filter(|chunk| CARRIED_CHUNKS.contains(&&chunk.chunk_type().bytes()))
+        .collect())
+}
+
+///How a PNG's samples relate to light
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Transfer {
+    Srgb,
+    ///An embedded ICC profile, by its name
+    Icc(String),
+    ///gAMA's value: samples are light raised to this power, times 100000
+    Gamma(u32),
+}
+
+impl Transfer {
+    ///The nearest QOI colorspace. A gamma of about 1/2.2 counts as sRGB, which it
+    ///approximates, and so does an ICC profile with sRGB in its name.
+    pub fn colorspace(&self) -> Option<ColorSpace> {
+        match self {
+            Transfer::Srgb => Some(ColorSpace::Srgb),
+            Transfer::Gamma(100_000) => Some(ColorSpace::Linear),
+            Transfer::Gamma(45_000..=46_000) => Some(ColorSpace::Srgb),
+            Transfer::Icc(name) if name.contains("sRGB") => Some(ColorSpace::Srgb),
+            _ => None,
+        }
+    }
+}
+
+///What the sRGB, iCCP or gAMA chunk says, in the order the PNG spec ranks them, or None
+///if the file has none of them
+pub fn transfer(data: &[u8]) -> Result<Option<Transfer>, String> {
+    // They all have to come before the image data
+    let mut chunks = data.iter().copied().skip(STANDARD_HEADER.len()).parse();
+    let header_chunks: Vec<Chunk> = chunks
+        .by_ref()
+        .take_while(|chunk| chunk.chunk_type().bytes() != *b"IDAT")
+        .collect();
+    chunks.checked(transfer_in(&header_chunks))
+}
+
+///[`transfer`] from the chunks before the image data
+fn transfer_in(chunks: &[Chunk]) -> Option<Transfer> {
+    let find = |kind: &[u8; 4]| chunks.iter().find(|c| c.chunk_type().bytes() == *kind);
+    if find(b"sRGB").is_some() {
+        return Some(Transfer::Srgb);
+    }
+    if let Some(chunk) = find(b"iCCP") {
+        return Some(Transfer::Icc(latin1(split_at_nul(chunk.data()).0)));
+    }
+    let gamma: [u8; 4] = find(b"gAMA")?.data().try_into().ok()?;
+    let gamma = u32::from_be_bytes(gamma);
+    (gamma > 0).then_some(Transfer::Gamma(gamma))
+}
+
+///When the image was taken or last changed: the date in an eXIf chunk, or else tIME's,
+///as `YYYY:MM:DD HH:MM:SS` (tIME is UTC, EXIF dates the camera's local time)
+pub fn date(data: &[u8]) -> Option<String> {
+    let chunks = ancillary_chunks(data).ok()?;
+    let find = |kind: &[u8; 4]| chunks.iter().find(|c| c.chunk_type().bytes() == *kind);
+    if let Some(date) = find(b"eXIf").and_then(|chunk| crate::tiff::exif_date(chunk.data())) {
+        return Some(date);
+    }
+    let time = find(b"tIME")?.data();
+    let [year_high, year_low, month, day, hour, minute, second] = *time else {
+        return None;
+    };
+    Some(format!(
+        "{:04}:{:02}:{:02} {:02}:{:02}:{:02}",
+        u16::from_be_bytes([year_high, year_low]),
+        month,
+        day,
+        hour,
+        minute,
+        second
+    ))
+}
+
+///An sRGB chunk, with the perceptual rendering intent
+pub fn srgb_chunk() -> Chunk {
+    Chunk::new(ChunkType::from_str("sRGB").unwrap(), vec![0])
+}
+
+///Add a tEXt chunk right after IHDR
+pub fn insert_text(data: &[u8], keyword: &str, text: &str) -> Vec<u8> {
+    let chunk = Chunk::new(
+        ChunkType::from_str("tEXt").unwrap(),
+        [keyword.as_bytes(), &[0], text.as_bytes()].concat(),
+    );
+    insert_chunks(data, &[chunk])
+}
+
+///Add chunks right after IHDR, which is early enough for any ancillary chunk
+pub fn insert_chunks(data: &[u8], chunks: &[Chunk]) -> Vec<u8> {
+    // Signature, then IHDR's length, type, 13 bytes of data and CRC
+    let ihdr_end = STANDARD_HEADER.len() + 4 + 4 + 13 + 4;
+    let inserted = chunks.iter().flat_map(|chunk| chunk.as_bytes());
+    data[..ihdr_end]
+        .iter()
+        .copied()
+        .chain(inserted)
+        .chain(data[ihdr_end..].iter().copied())
+        .collect()
+}
+
+const TEXT_CHUNKS: [&[u8; 4]; 3] = [b"tEXt", b"zTXt", b"iTXt"];
+
+///Every text chunk's keyword and text, in file order. Compressed text is inflated.
+pub fn text_entries(data: &[u8]) -> Result<Vec<(String, String)>, String> {
+    let mut entries = vec![];
+    for chunk in ancillary_chunks(data)? {
+        let (keyword, rest) = split_at_nul(chunk.data());
+        let text = match &chunk.chunk_type().bytes() {
+            b"tEXt" => latin1(rest),
+            // The first byte is the compression method, which can only be zlib
+            b"zTXt" => latin1(&inflate_text(rest.get(1..).unwrap_or_default())?),
+            b"iTXt" => {
+                let [compressed, _method, rest @ ..] = rest else {
+                    return Err("PNG iTXt chunk is truncated".into());
+                };
+                // Then a language tag and the keyword translated into it
+                let (_language, rest) = split_at_nul(rest);
+                let (_translated, text) = split_at_nul(rest);
+                let text = match compressed {
+                    0 => text.to_vec(),
+                    _ => inflate_text(text)?,
+                };
+                String::from_utf8(text).map_err(|_| "PNG iTXt text isn't UTF-8")?
+            }
+            _ => continue,
+        };
+        entries.push((latin1(keyword), text));
+    }
+    Ok(entries)
+}
+
+///Replace the text chunks with this keyword by one holding `text`, or just remove them if
+///it's None. Text goes in a tEXt chunk if it's Latin-1, which tEXt requires, and in an
+///uncompressed iTXt chunk otherwise.
+pub fn set_text(data: &[u8], keyword: &str, text: Option<&str>) -> Result<Vec<u8>, String> {
+    let key = to_latin1(keyword)
+        .filter(|key| {
+            (1..=79).contains(&key.len())
+                && key.iter().all(|&b| matches!(b, 32..=126 | 161..=255))
+                && !key.starts_with(b" ")
+                && !key.ends_with(b" ")
+        })
+        .ok_or_else(|| {
+            format!(
+                "Invalid PNG keyword '{}': use 1 to 79 printable Latin-1 characters, not starting or ending with a space",
+                keyword
+            )
+        })?;
+    if !data.starts_with(&STANDARD_HEADER) {
+        return Err("not a PNG file (bad signature)".into());
+    }
+    let mut parser = data.iter().copied().skip(STANDARD_HEADER.len()).parse();
+    let chunks: Vec<Chunk> = parser
+        .by_ref()
+        .filter(|chunk| {
+            !TEXT_CHUNKS.contains(&&chunk.chunk_type().bytes())
+                || split_at_nul(chunk.data()).0 != key
+        })
+        .collect();
+    let chunks = parser.checked(chunks)?;
+    // Rewriting a file the parser gave up on partway would cut off the rest of it
+    if chunks.last().map(|chunk| chunk.chunk_type().bytes()) != Some(*b"IEND") {
+        return Err("PNG is damaged or truncated, so it wasn't rewritten".into());
+    }
+    let rewritten = as_bytes(chunks);
+    let Some(text) = text else {
+        return Ok(rewritten);
+    };
+    if text.contains('\0') {
+        return Err("PNG text can't contain NUL characters".into());
+    }
+    let chunk = match to_latin1(text) {
+        Some(latin1) => Chunk::new(
+            ChunkType::from_str("tEXt").unwrap(),
+            [&key[..], &[0], &latin1].concat(),
+        ),
+        // Not compressed, no language tag and no translated keyword
+        None => Chunk::new(
+            ChunkType::from_str("iTXt").unwrap(),
+            [&key[..], &[0, 0, 0, 0, 0], text.as_bytes()].concat(),
+        ),
+    };
+    Ok(insert_chunks(&rewritten, &[chunk]))
+}
+
+///The bytes before the first NUL and those after it
+fn split_at_nul(data: &[u8]) -> (&[u8], &[u8]) {
+    match data.iter().position(|&b| b == 0) {
+        Some(at) => (&data[..at], &data[at + 1..]),
+        None => (data, &[]),
+    }
+}
+
+fn latin1(bytes: &[u8]) -> String {
+    bytes.iter().map(|&b| b as char).collect()
+}
+
+fn to_latin1(s: &str) -> Option<Vec<u8>> {
+    s.chars().map(|c| u8::try_from(c).ok()).collect()
+}
+
+fn inflate_text(compressed: &[u8]) -> Result<Vec<u8>, String> {
+    let mut inflater = Inflater::new(compressed.iter().copied());
+    let text: Vec<u8> = inflater.by_ref().collect();
+    match inflater.error() {
+        Some(e) => Err(format!("PNG compressed text: {}", e)),
+        None => Ok(text),
+    }
+}
+
+///Read just the header chunks of a PNG, with the colorspace from [`Transfer::colorspace`]
+pub fn read_header(data: &[u8]) -> Result<Header, String> {
+    let reader = png::Decoder::new(data)
+        .read_info()
+        .map_err(|e| e.to_string())?;
+    let info = reader.info();
+    Ok(Header {
+        width: info.width,
+        height: info.height,
+        channels: info.color_type.samples() as u8,
+        bit_depth: info.bit_depth as u8,
+        colorspace: transfer(data)?.and_then(|transfer| transfer.colorspace()),
+    })
+}
+
+///Stream a .qoi file into a PNG one row at a time, so only the compressed input and a
+///single row of pixels are ever in memory
+pub fn transcode_qoi(data: &[u8], out: impl std::io::Write) -> Result<(), String> {
+    transcode_qoi_with_progress(data, out, |_, _| {})
+}
+
+///`transcode_qoi`, calling `progress(rows_done, rows)` after every row
+pub fn transcode_qoi_with_progress(
+    data: &[u8],
+    out: impl std::io::Write,
+    mut progress: impl FnMut(u32, u32),
+) -> Result<(), String> {
+    use std::io::Write;
+
+    let mut rows = qoi::RowDecoder::new(data).map_err(|e| e.to_string())?;
+    let mut encoder = png::Encoder::new(out, rows.width, rows.height);
+    encoder.set_color(if rows.channels == 3 {
+        png::ColorType::Rgb
+    } else {
+        png::ColorType::Rgba
+    });
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header().map_err(|e| e.to_string())?;
+    let mut stream = writer.stream_writer().map_err(|e| e.to_string())?;
+    let mut row = vec![];
+    for y in 0..rows.height {
+        rows.read_row(&mut row).map_err(|e| e.to_string())?;
+        stream.write_all(&row).map_err(|e| e.to_string())?;
+        progress(y + 1, rows.height);
+    }
+    stream.finish().map_err(|e| e.to_string())?;
+    writer.finish().map_err(|e| e.to_string())
+}
+
+///When to write palette-based (indexed) PNGs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum PngPalette {
+    ///Indexed if the image has at most 256 colors and that comes out smaller
+    Auto,
+    ///Always indexed, reducing the image to 256 colors if it has more
+    Force,
+    ///Never indexed
+    #[default]
+    Off,
+}
+
+///Encode as PNG, using a palette as `palette` says
+pub fn encode_img_with(img: RawImage, palette: PngPalette) -> Result<Vec<u8>, String> {
+    if palette == PngPalette::Off || img.0 == 0 || img.1 == 0 {
+        return encode_img(img);
+    }
+    let RawImage(width, height, rgba, ..) = img.clone().convert(PixelFormat::Rgba8);
+    match (palette, exact_palette(&rgba)) {
+        (PngPalette::Auto, Some(colors)) => {
+            let indexed = encode_indexed(width, height, &rgba, colors)?;
+            let native = encode_img(img)?;
+            if indexed.len() < native.len() {
+                Ok(indexed)
+            } else {
+                Ok(native)
+            }
+        }
+        (PngPalette::Auto, None) => encode_img(img),
+        (_, Some(colors)) => encode_indexed(width, height, &rgba, colors),
+        (_, None) => {
+            let quant = color_quant::NeuQuant::new(10, 256, &rgba);
+            let mut reduced = rgba;
+            for pixel in reduced.chunks_exact_mut(4) {
+                let index = quant.index_of(pixel);
+                pixel.copy_from_slice(&quant.lookup(index).unwrap());
+            }
+            let colors = exact_palette(&reduced).unwrap();
+            encode_indexed(width, height, &reduced, colors)
+        }
+    }
+}
+
+///The distinct colors in an RGBA8 buffer, or None if there are more than 256.
+///Translucent colors come first so the tRNS chunk can stop at the last of them.
+fn exact_palette(rgba: &[u8]) -> Option<Vec<[u8; 4]>> {
+    let mut seen = std::collections::HashSet::new();
+    for pixel in rgba.chunks_exact(4) {
+        if seen.insert([pixel[0], pixel[1], pixel[2], pixel[3]]) && seen.len() > 256 {
+            return None;
+        }
+    }
+    let mut colors: Vec<[u8; 4]> = seen.into_iter().collect();
+    colors.sort_unstable_by_key(|&[r, g, b, a]| (a == 255, [r, g, b, a]));
+    Some(colors)
+}
+
+///Write an indexed PNG at the smallest bit depth the palette allows
+fn encode_indexed(
+    width: u32,
+    height: u32,
+    rgba: &[u8],
+    colors: Vec<[u8; 4]>,
+) -> Result<Vec<u8>, String> {
+    use std::collections::HashMap;
+    use std::io::Cursor;
+
+    let depth = match colors.len() {
+        0..=2 => png::BitDepth::One,
+        3..=4 => png::BitDepth::Two,
+        5..=16 => png::BitDepth::Four,
+        _ => png::BitDepth::Eight,
+    };
+    let bits = depth as usize;
+    let index: HashMap<[u8; 4], u8> = colors
+        .iter()
+        .enumerate()
+        .map(|(i, &color)| (color, i as u8))
+        .collect();
+
+    // Rows are packed most significant bit first and padded out to a whole byte
+    let row_bytes = (width as usize * bits).div_ceil(8);
+    let mut packed = vec![0u8; row_bytes * height as usize];
+    for (row, pixels) in packed
+        .chunks_exact_mut(row_bytes)
+        .zip(rgba.chunks_exact(width as usize * 4))
+    {
+        for (x, pixel) in pixels.chunks_exact(4).enumerate() {
+            let i = index[&[pixel[0], pixel[1], pixel[2], pixel[3]]];
+            let bit = x * bits;
+            row[bit / 8] |= i << (8 - bits - bit % 8);
+        }
+    }
+
+    let palette: Vec<u8> = colors.iter().flat_map(|c| [c[0], c[1], c[2]]).collect();
+    let alphas: Vec<u8> = colors
+        .iter()
+        .map(|c| c[3])
+        .take_while(|&a| a != 255)
+        .collect();
+
+    let mut buf = Vec::new();
+    let mut encoder = png::Encoder::new(Cursor::new(&mut buf), width, height);
+    encoder.set_color(png::ColorType::Indexed);
+    encoder.set_depth(depth);
+    encoder.set_palette(palette);
+    if !alphas.is_empty() {
+        encoder.set_trns(alphas);
+    }
+    let mut writer = encoder.write_header().map_err(|e| e.to_string())?;
+    writer.write_image_data(&packed).map_err(|e| e.to_string())?;
+    writer.finish().map_err(|e| e.to_string())?;
+    Ok(buf)
 }
 
 ///Parse file data into a stream of chunks
 impl<I: Iterator<Item = u8>> Iterator for Parser<I> {
     type Item = Chunk;
     fn next(&mut self) -> Option<Self::Item> {
+        if self.done || self.error.is_some() {
+            return None;
+        }
         let data_length: usize = u32::from_be_bytes(self.byte_stream.take_array()?) as usize; // Length of data + 4 bytes for type, CRC and length
-        let chunk_type = ChunkType::try_from(self.byte_stream.take_array()?).ok()?;
-        let chunk_data = self.byte_stream.take_vec(data_length);
+        let chunk_type = match ChunkType::try_from(self.byte_stream.take_array()?) {
+            Ok(chunk_type) => chunk_type,
+            Err(e) => {
+                self.error = Some(format!("PNG is damaged: {}", e));
+                return None;
+            }
+        };
+        let chunk_data = self.byte_stream.take_vec_exact(data_length)?;
         let chunk_crc = u32::from_be_bytes(self.byte_stream.take_array()?);
         let trial_chunk = Chunk::new(chunk_type, chunk_data);
-
-        assert_eq!(trial_chunk.crc(), chunk_crc);
-        assert_eq!(trial_chunk.length(), data_length as u32);
+        if trial_chunk.crc() != chunk_crc {
+            self.error = Some(format!(
+                "PNG is damaged: the {} chunk's CRC doesn't match its contents",
+                trial_chunk.chunk_type()
+            ));
+            return None;
+        }
+        // Whatever follows IEND isn't part of the PNG
+        self.done = trial_chunk.chunk_type().bytes() == *b"IEND";
 
         Some(trial_chunk)
     }
 }
 
-///Interpret chunks into pixel data
-impl<I: Iterator<Item = Chunk>> Iterator for Interpreter<I> {
+///Each sample in a row of 1, 2, 4 or 8-bit ones, most significant bits first. Rows are
+///padded to a whole byte, so the last byte can hold a few samples too many.
+fn samples(row: &[u8], depth: u8) -> impl Iterator<Item = u8> + '_ {
+    let mask = ((1u16 << depth) - 1) as u8;
+    row.iter()
+        .flat_map(move |&byte| (1..=8 / depth).map(move |i| byte >> (8 - depth * i) & mask))
+}
+
+///16-bit gray, gray+alpha or RGB samples as 16-bit RGBA, opaque where there's no alpha
+fn widen_to_rgba16(row: &[u8], channels: usize) -> impl Iterator<Item = u8> + '_ {
+    row.chunks_exact(channels * 2).flat_map(move |pixel| {
+        let sample = |i: usize| [pixel[i * 2], pixel[i * 2 + 1]];
+        let opaque = [0xff, 0xff];
+        let rgba = match channels {
+            1 => [sample(0), sample(0), sample(0), opaque],
+            2 => [sample(0), sample(0), sample(0), sample(1)],
+            _ => [sample(0), sample(1), sample(2), opaque],
+        };
+        rgba.into_iter().flatten()
+    })
+}
+
+///The IHDR fields decoding needs
+#[derive(Debug, Clone, Copy)]
+struct ImageHeader {
+    size: Dimensions,
+    bit_depth: u8,
+    color_type: u8,
+    interlaced: bool,
+}
+
+impl ImageHeader {
+    fn parse(data: &[u8]) -> Result<Self, String> {
+        let data: [u8; 13] = data.try_into().map_err(|_| "PNG IHDR isn't 13 bytes")?;
+        let header = ImageHeader {
+            size: Dimensions::new(
+                u32::from_be_bytes(data[0..4].try_into().unwrap()),
+                u32::from_be_bytes(data[4..8].try_into().unwrap()),
+            )
+            .map_err(|e| format!("PNG {}", e))?,
+            bit_depth: data[8],
+            color_type: data[9],
+            interlaced: match data[12] {
+                0 => false,
+                1 => true,
+                method => return Err(format!("PNG interlace method {} is unknown", method)),
+            },
+        };
+        if data[10] != 0 || data[11] != 0 {
+            return Err("PNG uses an unknown compression or filter method".into());
+        }
+        Ok(header)
+    }
+
+    fn format(&self) -> Result<PixelFormat, String> {
+        match (self.color_type, self.bit_depth) {
+            (6, 8) => Ok(PixelFormat::Rgba8),
+            (2, 8) => Ok(PixelFormat::Rgb8),
+            (0, 8) => Ok(PixelFormat::Gray8),
+            (4, 8) => Ok(PixelFormat::GrayAlpha8),
+            // Other 16-bit layouts are widened to RGBA rather than cut to 8 bits here, so
+            // convert can narrow them with or without dithering
+            (0 | 2 | 4 | 6, 16) => Ok(PixelFormat::Rgba16),
+            (0, 1 | 2 | 4) => Ok(PixelFormat::Gray8),
+            // Rgba8 instead if tRNS makes any palette entry transparent
+            (3, 1 | 2 | 4 | 8) => Ok(PixelFormat::Rgb8),
+            (color_type, depth) => Err(format!(
+                "Unsupported PNG color type {} at {} bits",
+                color_type, depth
+            )),
+        }
+    }
+
+    fn channels(&self) -> usize {
+        match self.color_type {
+            2 => 3,
+            4 => 2,
+            6 => 4,
+            _ => 1,
+        }
+    }
+
+    ///How far back filters look: one whole pixel, or a byte when pixels are smaller
+    fn filter_stride(&self) -> usize {
+        (self.pixel_bits() / 8).max(1)
+    }
+
+    fn pixel_bits(&self) -> usize {
+        self.channels() * self.bit_depth as usize
+    }
+
+    ///Bytes in a scanline `width` pixels wide, not counting its filter type
+    fn row_len(&self, width: u32) -> usize {
+        (width as usize * self.channels() * self.bit_depth as usize).div_ceil(8)
+    }
+}
+
+///The data of consecutive IDAT chunks as one stream, ending at the first other chunk
+struct ImageData<I: Iterator<Item = Chunk>> {
+    chunks: I,
+    current: std::vec::IntoIter<u8>,
+    done: bool,
+}
+
+impl<I: Iterator<Item = Chunk>> Iterator for ImageData<I> {
+    type Item = u8;
+    fn next(&mut self) -> Option<u8> {
+        loop {
+            if let Some(byte) = self.current.next() {
+                return Some(byte);
+            }
+            if self.done {
+                return None;
+            }
+            match self.chunks.next() {
+                Some(chunk) if chunk.chunk_type.bytes() == *b"IDAT" => {
+                    self.current = chunk.data.into_iter();
+                }
+                _ => self.done = true,
+            }
+        }
+    }
+}
+
+///Adam7 passes as (x, y, x step, y step)
+const ADAM7: [(u32, u32, u32, u32); 7] = [
+    (0, 0, 8, 8),
+    (4, 0, 8, 8),
+    (0, 4, 4, 8),
+    (2, 0, 4, 4),
+    (0, 2, 2, 4),
+    (1, 0, 2, 2),
+    (0, 1, 1, 2),
+];
+
+///Read the next scanline, `len` bytes long, and undo its filter. An empty `above` is the
+///row of zeros above the first one.
+fn read_scanline<I: Iterator<Item = u8>>(
+    data: &mut Inflater<I>,
+    above: &[u8],
+    len: usize,
+    stride: usize,
+) -> Result<Vec<u8>, String> {
+    let filter = data.next();
+    let mut row: Vec<u8> = data.by_ref().take(len).collect();
+    let Some(filter) = filter.filter(|_| row.len() == len) else {
+        let error = data.error().unwrap_or("PNG image data ends early");
+        return Err(error.to_string());
+    };
+    // Only made once the row has turned up, so a huge claimed width costs nothing up front
+    let zeros;
+    let above = match above.is_empty() {
+        true => {
+            zeros = vec![0; len];
+            &zeros
+        }
+        false => above,
+    };
+    match filter {
+        0 => {}
+        1 => {
+            for i in stride..row.len() {
+                row[i] = row[i].wrapping_add(row[i - stride]);
+            }
+        }
+        2 => {
+            for (value, up) in row.iter_mut().zip(above) {
+                *value = value.wrapping_add(*up);
+            }
+        }
+        3 => {
+            for i in 0..row.len() {
+                let left = if i >= stride { row[i - stride] } else { 0 };
+                row[i] = row[i].wrapping_add(((left as u16 + above[i] as u16) / 2) as u8);
+            }
+        }
+        4 => {
+            for i in 0..row.len() {
+                let (left, up_left) = match i >= stride {
+                    true => (row[i - stride], above[i - stride]),
+                    false => (0, 0),
+                };
+                row[i] = row[i].wrapping_add(paeth(left, above[i], up_left));
+            }
+        }
+        filter => return Err(format!("PNG scanline has unknown filter type {}", filter)),
+    }
+    Ok(row)
+}
+
+///Whichever neighbour is closest to left + up - up_left
+fn paeth(left: u8, up: u8, up_left: u8) -> u8 {
+    let estimate = left as i16 + up as i16 - up_left as i16;
+    let distance = |v: u8| (estimate - v as i16).abs();
+    if distance(left) <= distance(up) && distance(left) <= distance(up_left) {
+        left
+    } else if distance(up) <= distance(up_left) {
+        up
+    } else {
+        up_left
+    }
+}
+
+impl<I: Iterator<Item = u8>> Interpreter<I> {
+    ///What the rows decode to once palette entries are looked up
+    fn format(&self) -> Result<PixelFormat, String> {
+        let format = self.header.format()?;
+        match self.header.color_type {
+            3 if self.palette.is_empty() => Err("PNG has no palette (PLTE chunk)".into()),
+            3 if self.palette.iter().any(|color| color[3] < 255) => Ok(PixelFormat::Rgba8),
+            _ => Ok(format),
+        }
+    }
+
+    ///Stop decoding. A damaged chunk also cuts the compressed data short, so it's the
+    ///error to report when there is one.
+    fn fail(&mut self, e: String) {
+        let chunks = &self.data.get_ref().chunks;
+        self.error = Some(chunks.checked(()).err().unwrap_or(e));
+    }
+
+    fn next_row(&mut self) -> Result<Vec<u8>, String> {
+        let len = self.header.row_len(self.header.size.width());
+        let row = read_scanline(&mut self.data, &self.above, len, self.header.filter_stride())?;
+        self.above.clone_from(&row);
+        Ok(row)
+    }
+
+    ///Reassemble all seven passes of an interlaced image, whose rows can't come out
+    ///until the last pass is in. The image grows as rows arrive rather than being sized
+    ///from IHDR up front.
+    fn deinterlace(&mut self) -> Result<Vec<u8>, String> {
+        let header = self.header;
+        let pixel = header.filter_stride();
+        let bits = header.pixel_bits();
+        let row_len = header.row_len(header.size.width());
+        let mut image = vec![];
+        for (x0, y0, x_step, y_step) in ADAM7 {
+            let width = header.size.width().saturating_sub(x0).div_ceil(x_step);
+            let height = header.size.height().saturating_sub(y0).div_ceil(y_step);
+            if width == 0 || height == 0 {
+                continue;
+            }
+            let mut above = vec![];
+            for pass_y in 0..height {
+                above = read_scanline(&mut self.data, &above, header.row_len(width), pixel)?;
+                let y = (y0 + pass_y * y_step) as usize;
+                if image.len() < (y + 1) * row_len {
+                    image.resize((y + 1) * row_len, 0);
+                }
+                let row = &mut image[y * row_len..(y + 1) * row_len];
+                if bits < 8 {
+                    // Several pixels to a byte, so move them bits at a time
+                    let mask = ((1u16 << bits) - 1) as u8;
+                    for pass_x in 0..width as usize {
+                        let from = pass_x * bits;
+                        let value = above[from / 8] >> (8 - bits - from % 8) & mask;
+                        let to = (x0 as usize + pass_x * x_step as usize) * bits;
+                        row[to / 8] |= value << (8 - bits - to % 8);
+                    }
+                    continue;
+                }
+                for (pass_x, value) in above.chunks_exact(pixel).enumerate() {
+                    let at = (x0 as usize + pass_x * x_step as usize) * pixel;
+                    row[at..at + pixel].copy_from_slice(value);
+                }
+            }
+        }
+        Ok(image)
+    }
+}
+
+///Interpret chunks into pixel data, one row per item
+impl<I: Iterator<Item = u8>> Iterator for Interpreter<I> {
     type Item = Vec<u8>;
     fn next(&mut self) -> Option<Self::Item> {
-        todo!()
+        if self.y >= self.header.size.height() || self.error.is_some() {
+            return None;
+        }
+        let row = match self.header.interlaced {
+            false => self.next_row(),
+            true => {
+                if self.image.is_empty() {
+                    match self.deinterlace() {
+                        Ok(image) => self.image = image,
+                        Err(e) => {
+                            self.fail(e);
+                            return None;
+                        }
+                    }
+                }
+                let len = self.header.row_len(self.header.size.width());
+                let start = self.y as usize * len;
+                Ok(self.image[start..start + len].to_vec())
+            }
+        };
+        match row {
+            Ok(row) => {
+                self.y += 1;
+                Some(row)
+            }
+            Err(e) => {
+                self.fail(e);
+                None
+            }
+        }
     }
 }
 
@@ -264,30 +1000,27 @@ where
 {
     type Item = Vec<u8>;
     fn next(&mut self) -> Option<<Self as Iterator>::Item> {
-        todo!()
-    }
-}
-
-impl<'a, I> Iterator for Compresser<'a, I>
-where
-    I: Iterator<Item = &'a [u8]>,
-{
-    type Item = Chunk;
-    fn next(&mut self) -> Option<<Self as Iterator>::Item> {
-        todo!()
+        self.chunk_stream.next().map(|chunk| chunk.as_bytes())
     }
 }
 //==============BOILERPLATE====================================//
 
 struct Interpreter<I>
 where
-    I: Iterator<Item = Chunk>,
+    I: Iterator<Item = u8>,
 {
-    max_pix: usize,
-    pix_count: usize,
-    chunk_stream: I,
-    pixel: [u8; 4],
-    seen: [[u8; 4]; 64],
+    header: ImageHeader,
+    data: Inflater<ImageData<Parser<I>>>,
+    ///The previous scanline, unfiltered, or empty before the first
+    above: Vec<u8>,
+    y: u32,
+    ///The whole image, for interlaced files once all passes are decoded
+    image: Vec<u8>,
+    ///PLTE's colors as RGBA, with alpha from tRNS where it has any
+    palette: Vec<[u8; 4]>,
+    ///What the color chunks say about the samples
+    transfer: Option<Transfer>,
+    error: Option<String>,
 }
 
 struct Parser<I>
@@ -295,15 +1028,20 @@ where
     I: Iterator<Item = u8>,
 {
     byte_stream: I,
+    ///Whether IEND has been read
+    done: bool,
+    ///A damaged chunk parsing stopped at. Running out of data just ends the chunks.
+    error: Option<String>,
 }
 
-struct Compresser<'a, I>
-where
-    I: Iterator<Item = &'a [u8]>,
-{
-    pix_stream: Peekable<I>,
-    last_pix: [u8; 4],
-    seen: [[u8; 4]; 64],
+impl<I: Iterator<Item = u8>> Parser<I> {
+    ///`value`, or the error if parsing stopped at a damaged chunk
+    fn checked<T>(&self, value: T) -> Result<T, String> {
+        match &self.error {
+            Some(e) => Err(e.clone()),
+            None => Ok(value),
+        }
+    }
 }
 
 struct Assembler<I>
@@ -314,25 +1052,65 @@ where
 }
 
 ///Construct an Interpreter
-trait Interpret {
-    fn interpret(self, max_pix: usize) -> Interpreter<Self>
-    where
-        Self: Sized,
-        Self: Iterator<Item = Chunk>; // Can only call .interpret() on chunk iters
+trait Interpret<I>
+where
+    I: Iterator<Item = u8>,
+{
+    fn interpret(self) -> Result<Interpreter<I>, String>;
 }
 
-impl<I> Interpret for I
+impl<I> Interpret<I> for Parser<I>
 where
-    I: Iterator<Item = Chunk>,
+    I: Iterator<Item = u8>,
 {
-    fn interpret(self, max_len: usize) -> Interpreter<I> {
-        Interpreter {
-            max_pix: max_len,
-            pix_count: 0,
-            chunk_stream: self,
-            pixel: [0, 0, 0, 255],
-            seen: [[0; 4]; 64],
-        } //Once called, create an Interpreter with all related state
+    fn interpret(mut self) -> Result<Interpreter<I>, String> {
+        // Everything up to the first IDAT describes the image
+        let mut header = None;
+        let mut palette: Vec<[u8; 4]> = vec![];
+        let mut color = vec![];
+        let first = loop {
+            let chunk = self
+                .next()
+                .ok_or_else(|| self.error.clone().unwrap_or("PNG has no image data".into()))?;
+            match &chunk.chunk_type.bytes() {
+                b"IHDR" => header = Some(ImageHeader::parse(chunk.data())?),
+                b"PLTE" => {
+                    if chunk.data().len() % 3 != 0 || chunk.data().len() > 256 * 3 {
+                        return Err("PNG palette isn't a whole number of colors, up to 256".into());
+                    }
+                    palette = chunk
+                        .data()
+                        .chunks_exact(3)
+                        .map(|c| [c[0], c[1], c[2], 255])
+                        .collect();
+                }
+                // Gray and RGB images use tRNS for a color key, which isn't applied
+                b"tRNS" if header.is_some_and(|h: ImageHeader| h.color_type == 3) => {
+                    for (color, &alpha) in palette.iter_mut().zip(chunk.data()) {
+                        color[3] = alpha;
+                    }
+                }
+                b"IDAT" => break chunk,
+                kind if COLOR_CHUNKS.contains(&kind) => color.push(chunk),
+                _ => {}
+            }
+        };
+        let header = header.ok_or("PNG has no IHDR chunk")?;
+        let data = ImageData {
+            chunks: self,
+            current: first.data.into_iter(),
+            done: false,
+        };
+        Ok(Interpreter {
+            header,
+            data: Inflater::new(data),
+            above: vec![],
+            y: 0,
+            image: vec![],
+            palette,
+            transfer: transfer_in(&color),
+            error: None,
+        })
     }
 }
 
@@ -349,27 +1127,10 @@ where
     I: Iterator<Item = u8>,
 {
     fn parse(self) -> Parser<I> {
-        Parser { byte_stream: self } //Once called, create a Parser with byte_stream as its only field
-    }
-}
-
-trait Compress<'a, I>
-where
-    Self: Sized,
-    I: Iterator<Item = &'a [u8]>,
-{
-    fn compress(self) -> Compresser<'a, I>;
-}
-
-impl<'a, I> Compress<'a, I> for &'a [u8]
-where
-    I: Iterator<Item = &'a [u8]> + std::convert::From<std::slice::Chunks<'a, u8>>,
-{
-    fn compress(self) -> Compresser<'a, I> {
-        Compresser {
-            pix_stream: <Chunks<'_, u8> as Into<I>>::into(self.chunks(4)).peekable(),
-            last_pix: [0, 0, 0, 255],
-            seen: [[0; 4]; 64],
+        Parser {
+            byte_stream: self,
+            done: false,
+            error: None,
         }
     }
 }
@@ -391,3 +1152,76 @@ where
 }
 
 //==========END BOILERPLATE====================================//
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    ///A small PNG with a text chunk, and the same file with one byte of its image data
+    ///flipped so that IDAT's CRC no longer matches
+    fn intact_and_damaged() -> (Vec<u8>, Vec<u8>) {
+        let img = RawImage(3, 2, vec![200; 24], PixelFormat::Rgba8, ColorSpace::Srgb);
+        let intact = insert_text(&encode_img(img).unwrap(), "Title", "test");
+        let idat = intact.windows(4).position(|w| w == b"IDAT").unwrap();
+        let mut damaged = intact.clone();
+        damaged[idat + 4] ^= 0x01;
+        (intact, damaged)
+    }
+
+    #[test]
+    fn intact_png_decodes() {
+        let (intact, _) = intact_and_damaged();
+        let img = parse_img(intact.iter().copied()).unwrap();
+        assert_eq!((img.0, img.1), (3, 2));
+        assert!(read_header(&intact).is_ok());
+        assert_eq!(
+            text_entries(&intact).unwrap(),
+            [("Title".to_string(), "test".to_string())]
+        );
+    }
+
+    #[test]
+    fn empty_image_is_an_error() {
+        for (width, height) in [(0, 0), (0, 3), (3, 0)] {
+            let img = RawImage(width, height, vec![], PixelFormat::Rgba8, ColorSpace::Srgb);
+            assert!(encode_img(img.clone()).is_err());
+            for palette in [PngPalette::Off, PngPalette::Auto, PngPalette::Force] {
+                assert!(encode_img_with(img.clone(), palette).is_err());
+            }
+        }
+    }
+
+    #[test]
+    fn bad_crc_is_an_error() {
+        let (_, damaged) = intact_and_damaged();
+        let error = parse_img(damaged.iter().copied()).err().unwrap();
+        assert!(error.contains("IDAT chunk's CRC"), "{}", error);
+        assert!(read_header(&damaged).is_err());
+        assert!(text_entries(&damaged).is_err());
+        assert!(transfer(&damaged).is_err());
+    }
+
+    #[test]
+    fn oversized_header_is_an_error() {
+        // IHDR claims 60000x60000 RGBA (14 GB), but the data only holds 3x2
+        let (intact, _) = intact_and_damaged();
+        for interlace in [0, 1] {
+            let mut ihdr = intact[12..29].to_vec();
+            ihdr[4..12].copy_from_slice(&[0, 0, 0xea, 0x60, 0, 0, 0xea, 0x60]);
+            ihdr[16] = interlace;
+            let crc = Crc::<u32>::new(&CRC_32_ISO_HDLC).checksum(&ihdr);
+            let mut huge = intact.clone();
+            huge[12..29].copy_from_slice(&ihdr);
+            huge[29..33].copy_from_slice(&crc.to_be_bytes());
+            assert!(parse_img(huge.iter().copied()).is_err());
+        }
+    }
+
+    #[test]
+    fn truncated_png_is_an_error() {
+        let (intact, _) = intact_and_damaged();
+        for len in 0..intact.len() - 12 {
+            assert!(parse_img(intact[..len].iter().copied()).is_err(), "{}", len);
+        }
+    }
+}
@@ -1,134 +1,350 @@
-use crate::img::RawImage;
+use crate::img::{ColorSpace, Dimensions, Header, PixelFormat, RawImage};
+use crate::stream::TakeVec;
 use std::iter::Peekable;
+
+///Skip whitespace and `#` comments, which netpbm allows between any two header tokens.
+///Comments run to the end of the line, so CRLF line endings are handled too.
 fn consume_ascii_whitespace(stream: &mut std::iter::Peekable<impl Iterator<Item = u8>>) {
-    while stream
-        .peek()
-        .map_or(false, |&byte| byte.is_ascii_whitespace())
-    {
-        stream.next();
+    while let Some(&byte) = stream.peek() {
+        if byte == b'#' {
+            for b in stream.by_ref() {
+                if b == b'\n' {
+                    break;
+                }
+            }
+        } else if byte.is_ascii_whitespace() {
+            stream.next();
+        } else {
+            break;
+        }
     }
 }
 
 fn consume_ascii_dec(stream: &mut std::iter::Peekable<impl Iterator<Item = u8>>) -> u32 {
     let mut buffer = 0;
-    while stream.peek().unwrap().is_ascii_digit() {
-        let digit = stream.next().unwrap();
+    while let Some(digit) = stream.next_if(u8::is_ascii_digit) {
         buffer = buffer * 10 + (digit - b'0') as u32;
     }
     buffer
 }
 
-pub fn parse_img(data: impl Iterator<Item = u8>) -> RawImage {
-    let mut stream = data.peekable();
-    assert_eq!(stream.next(), Some(b'P'));
-    assert_eq!(stream.next(), Some(b'6'));
+///The `#` comments in a PPM header, without the `#`
+pub fn comments(data: &[u8]) -> Vec<String> {
+    let mut comments = vec![];
+    let mut stream = data.iter().copied().skip(2).peekable();
+    // Comments can sit before each of width, height and maxval
+    for _ in 0..3 {
+        loop {
+            match stream.peek() {
+                Some(b'#') => {
+                    let line: Vec<u8> = stream
+                        .by_ref()
+                        .skip(1)
+                        .take_while(|&b| b != b'\n')
+                        .collect();
+                    comments.push(String::from_utf8_lossy(&line).trim().to_string());
+                }
+                Some(b) if b.is_ascii_whitespace() => {
+                    stream.next();
+                }
+                _ => break,
+            }
+        }
+        consume_ascii_dec(&mut stream);
+    }
+    comments
+}
 
-    consume_ascii_whitespace(&mut stream);
+///Read just the header of a PPM, PGM or PBM
+pub fn read_header(data: &[u8]) -> Result<Header, String> {
+    let mut stream = data.iter().copied().peekable();
+    if data.starts_with(b"P6") || data.starts_with(b"P3") {
+        stream.nth(1);
+        consume_ascii_whitespace(&mut stream);
+        let width = consume_ascii_dec(&mut stream);
+        consume_ascii_whitespace(&mut stream);
+        let height = consume_ascii_dec(&mut stream);
+        consume_ascii_whitespace(&mut stream);
+        let maxval = consume_ascii_dec(&mut stream);
+        if maxval == 0 || maxval > 65535 {
+            return Err(format!("invalid maxval {}", maxval));
+        }
+        return Ok(Header {
+            width,
+            height,
+            channels: 3,
+            bit_depth: if maxval < 256 { 8 } else { 16 },
+            colorspace: None,
+        });
+    }
+    let header = parse_gray_header(&mut stream)?;
+    Ok(Header {
+        width: header.width,
+        height: header.height,
+        channels: 1,
+        bit_depth: match header.maxval {
+            1 => 1,
+            2..=255 => 8,
+            _ => 16,
+        },
+        colorspace: None,
+    })
+}
 
-    while stream.peek().map_or(false, |&byte| byte == b'#') {
-        // Repeat for any number of comment lines
-        while let Some(b) = stream.next() {
-            if b == b'\n' {
-                break;
-            }
+///A PGM or PBM header. `maxval` is 1 for bitmaps, which have none in the file.
+struct GrayHeader {
+    kind: u8,
+    width: u32,
+    height: u32,
+    maxval: u32,
+}
+
+///Read a P1, P2, P4 or P5 header, up to and including the single whitespace byte before
+///the data
+fn parse_gray_header(
+    stream: &mut Peekable<impl Iterator<Item = u8>>,
+) -> Result<GrayHeader, String> {
+    let kind = match (stream.next(), stream.next()) {
+        (Some(b'P'), Some(kind @ (b'1' | b'2' | b'4' | b'5'))) => kind,
+        _ => return Err("not a PGM or PBM (no P1, P2, P4 or P5 magic)".into()),
+    };
+    consume_ascii_whitespace(stream);
+    let width = consume_ascii_dec(stream);
+    consume_ascii_whitespace(stream);
+    let height = consume_ascii_dec(stream);
+    let maxval = match kind {
+        b'1' | b'4' => 1,
+        _ => {
+            consume_ascii_whitespace(stream);
+            consume_ascii_dec(stream)
         }
+    };
+    if maxval == 0 || maxval > 65535 {
+        return Err(format!("invalid maxval {}", maxval));
+    }
+    if !stream.next().is_some_and(|b| b.is_ascii_whitespace()) {
+        return Err("no whitespace between the header and the data".into());
     }
+    Ok(GrayHeader {
+        kind,
+        width,
+        height,
+        maxval,
+    })
+}
+
+///Decode a PGM (P5 binary or P2 plain) or PBM (P4 binary or P1 plain) to Gray8, which
+///`convert` expands to RGBA like any other format. Samples above 8 bits are scaled down
+///from 0..=maxval, and PBM's 1 (black) and 0 (white) become 0 and 255.
+#[tracing::instrument(name = "pgm::decode", skip_all)]
+pub fn parse_gray(data: impl Iterator<Item = u8>) -> Result<RawImage, String> {
+    let mut stream = data.peekable();
+    let GrayHeader {
+        kind,
+        width,
+        height,
+        maxval,
+    } = parse_gray_header(&mut stream)?;
+    let len = Dimensions::new(width, height)?.pixels();
+    let short = || "data ends before the last pixel".to_string();
+
+    let pixels = match kind {
+        b'5' => {
+            let samples = stream
+                .take_vec_exact(len * sample_bytes(maxval))
+                .ok_or_else(short)?;
+            scale_samples(samples, maxval)
+        }
+        b'2' => read_plain_samples(&mut stream, len, maxval)?,
+        b'4' => {
+            // Every row starts on a fresh byte, with the last byte padded out
+            let row_bytes = (width as usize).div_ceil(8);
+            let packed = stream
+                .take_vec_exact(row_bytes * height as usize)
+                .ok_or_else(short)?;
+            let mut pixels = Vec::with_capacity(len);
+            for row in packed.chunks_exact(row_bytes.max(1)).take(height as usize) {
+                pixels.extend((0..width as usize).map(|x| {
+                    let black = row[x / 8] & (0x80 >> (x % 8)) != 0;
+                    if black { 0 } else { 255 }
+                }));
+            }
+            pixels
+        }
+        _ => {
+            // Plain PBM digits may or may not be separated by whitespace
+            let mut pixels = Vec::with_capacity(len);
+            for _ in 0..len {
+                consume_ascii_whitespace(&mut stream);
+                match stream.next() {
+                    Some(b'0') => pixels.push(255),
+                    Some(b'1') => pixels.push(0),
+                    _ => return Err(short()),
+                }
+            }
+            pixels
+        }
+    };
+    Ok(RawImage(
+        width,
+        height,
+        pixels,
+        PixelFormat::Gray8,
+        ColorSpace::Srgb,
+    ))
+}
+
+///Decode a binary (P6) or plain (P3) PPM. Samples are scaled down from 0..=maxval, so
+///16-bit files (maxval above 255, two bytes per sample) come out as 8 bits too.
+#[tracing::instrument(name = "ppm::decode", skip_all)]
+pub fn parse_img(data: impl Iterator<Item = u8>) -> Result<RawImage, String> {
+    let mut stream = data.peekable();
+    let kind = match (stream.next(), stream.next()) {
+        (Some(b'P'), Some(kind @ (b'3' | b'6'))) => kind,
+        _ => return Err("not a PPM (no P3 or P6 magic)".into()),
+    };
 
     consume_ascii_whitespace(&mut stream);
     let width = consume_ascii_dec(&mut stream);
     consume_ascii_whitespace(&mut stream);
     let height = consume_ascii_dec(&mut stream);
     consume_ascii_whitespace(&mut stream);
-    assert_eq!(255, consume_ascii_dec(&mut stream)); //Only adding support for 8-bit images
-    assert_eq!(Some(b'\n'), stream.next());
+    let maxval = consume_ascii_dec(&mut stream);
+    let separator = stream.next();
+    if !separator.is_some_and(|b| b.is_ascii_whitespace()) {
+        return Err("no whitespace between the header and the data".into());
+    }
     //Stream should now be at the start of the image data
+    let expected_len = Dimensions::new(width, height)?.byte_len(PixelFormat::Rgb8);
 
-    let pixel_buf = stream.space_n(255, 3);
-    RawImage(width, height, pixel_buf.collect())
+    if maxval == 0 || maxval > 65535 {
+        return Err(format!("invalid maxval {}", maxval));
+    }
+    if kind == b'3' {
+        let pixels = read_plain_samples(&mut stream, expected_len, maxval)?;
+        return Ok(RawImage(
+            width,
+            height,
+            pixels,
+            PixelFormat::Rgb8,
+            ColorSpace::Srgb,
+        ));
+    }
+    // Six bytes a pixel at most, within what Dimensions allows for
+    let expected_len = expected_len * sample_bytes(maxval);
+    let mut pixels: Vec<u8> = stream.collect();
+    // The spec allows exactly one whitespace byte here, but headers written with CRLF line
+    // endings have two. Only drop the \n if the data is then exactly one byte too long,
+    // since a \n could also be a legitimate first sample.
+    if separator == Some(b'\r')
+        && pixels.first() == Some(&b'\n')
+        && pixels.len() == expected_len + 1
+    {
+        pixels.remove(0);
+    }
+    if pixels.len() < expected_len {
+        return Err("data ends before the last pixel".into());
+    }
+    pixels.truncate(expected_len);
+    let pixels = scale_samples(pixels, maxval);
+    Ok(RawImage(
+        width,
+        height,
+        pixels,
+        PixelFormat::Rgb8,
+        ColorSpace::Srgb,
+    ))
 }
 
-pub fn encode_img(img: RawImage) -> Vec<u8> {
-    let RawImage(width, height, pixels) = img;
-    let mut out = vec![b'P', b'6', b' '];
-    out.extend_from_slice(&(width.to_string().as_bytes()));
-    out.push(b' ');
-    out.extend_from_slice(&(height.to_string().as_bytes()));
-    out.push(b' ');
-    out.extend_from_slice(&[b'2', b'5', b'5', b'\n']);
-
-    out.extend_from_slice(
-        &pixels
-            .chunks(4)
-            .filter_map(|chunk| {
-                let [r, g, b, _a]: [u8; 4] = chunk.try_into().ok()?;
-                Some([r, g, b])
-            })
-            .flatten()
-            .collect::<Vec<u8>>(),
-    );
-
-    out
+///Bytes per binary sample: one up to maxval 255, two (big-endian) above that
+fn sample_bytes(maxval: u32) -> usize {
+    if maxval < 256 { 1 } else { 2 }
 }
 
-struct SpaceN<I, T: Clone>
-where
-    I: Iterator<Item = T>,
-{
-    stream: I,
-    count: usize,
-    period: usize,
-    spacer: T,
+///Scale binary samples from 0..=maxval to 0..=255
+fn scale_samples(samples: Vec<u8>, maxval: u32) -> Vec<u8> {
+    let scale = |v: u32| ((v.min(maxval) * 255 + maxval / 2) / maxval) as u8;
+    if maxval == 255 {
+        return samples;
+    }
+    match sample_bytes(maxval) {
+        1 => samples.iter().map(|&v| scale(v as u32)).collect(),
+        _ => samples
+            .chunks_exact(2)
+            .map(|v| scale(u16::from_be_bytes([v[0], v[1]]) as u32))
+            .collect(),
+    }
 }
 
-impl<I, T: Clone> Iterator for SpaceN<I, T>
-where
-    I: Iterator<Item = T>,
-{
-    type Item = T;
-
-    fn next(&mut self) -> Option<Self::Item> {
-        if self.count >= self.period {
-            self.count = 0;
-            Some(self.spacer.clone())
-        } else {
-            self.count += 1;
-            self.stream.next()
+///Read `count` whitespace-separated decimal samples, scaled from 0..=maxval to 0..=255
+fn read_plain_samples(
+    stream: &mut Peekable<impl Iterator<Item = u8>>,
+    count: usize,
+    maxval: u32,
+) -> Result<Vec<u8>, String> {
+    let mut samples = Vec::with_capacity(count);
+    for _ in 0..count {
+        consume_ascii_whitespace(stream);
+        if !stream.peek().is_some_and(u8::is_ascii_digit) {
+            return Err("data ends before the last pixel".into());
         }
+        let v = consume_ascii_dec(stream).min(maxval);
+        samples.push(((v * 255 + maxval / 2) / maxval) as u8);
     }
+    Ok(samples)
 }
 
-trait Spaceable<I, T: Clone>
-where
-    I: Iterator<Item = T>,
-{
-    fn space_n(self, spacer: T, period: usize) -> SpaceN<Self, T>
-    where
-        Self: Sized,
-        Self: Iterator<Item = T>,
-    {
-        SpaceN {
-            stream: self,
-            count: 0,
-            period,
-            spacer,
-        }
+///Read one image off a stream of back-to-back PPMs, as ffmpeg's image2pipe writes them.
+///Returns None once the stream is exhausted or ends partway through a frame.
+pub fn parse_frame(stream: &mut Peekable<impl Iterator<Item = u8>>) -> Option<RawImage> {
+    consume_ascii_whitespace(stream);
+    if stream.next()? != b'P' || stream.next()? != b'6' {
+        return None;
+    }
+    consume_ascii_whitespace(stream);
+    let width = consume_ascii_dec(stream);
+    consume_ascii_whitespace(stream);
+    let height = consume_ascii_dec(stream);
+    consume_ascii_whitespace(stream);
+    let maxval = consume_ascii_dec(stream);
+    if !(1..=65535).contains(&maxval) || !stream.next()?.is_ascii_whitespace() {
+        return None;
     }
+    let size = Dimensions::new(width, height).ok()?;
+    let len = size.byte_len(PixelFormat::Rgb8) * sample_bytes(maxval);
+    let pixels = scale_samples(stream.take_vec_exact(len)?, maxval);
+    Some(RawImage(
+        width,
+        height,
+        pixels,
+        PixelFormat::Rgb8,
+        ColorSpace::Srgb,
+    ))
 }
 
-impl<I, T: Clone> Spaceable<I, T> for I
-where
-    I: Iterator<Item = T>,
-{
-    fn space_n(self, spacer: T, period: usize) -> SpaceN<Self, T>
-    where
-        Self: Sized,
-        Self: Iterator<Item = T>,
-    {
-        SpaceN {
-            stream: self,
-            count: 0,
-            period,
-            spacer,
-        }
+#[tracing::instrument(name = "ppm::encode", skip_all, fields(width = img.0, height = img.1))]
+pub fn encode_img(img: RawImage) -> Vec<u8> {
+    let RawImage(width, height, pixels, ..) = img.convert(PixelFormat::Rgb8);
+    let mut out = vec![b'P', b'6', b' '];
+    out.extend_from_slice(width.to_string().as_bytes());
+    out.push(b' ');
+    out.extend_from_slice(height.to_string().as_bytes());
+    out.push(b' ');
+    out.extend_from_slice(b"255\n");
+
+    out.extend(pixels);
+
+    out
+}
+
+///Plain-text P3 with one pixel per line, for reading or diffing pixel values in a text
+///editor. Lines stay well under the 70 characters netpbm allows.
+#[tracing::instrument(name = "ppm::encode_plain", skip_all, fields(width = img.0, height = img.1))]
+pub fn encode_plain(img: RawImage) -> Vec<u8> {
+    let RawImage(width, height, pixels, ..) = img.convert(PixelFormat::Rgb8);
+    let mut out = format!("P3\n{} {}\n255\n", width, height);
+    for pixel in pixels.chunks_exact(3) {
+        out.push_str(&format!("{} {} {}\n", pixel[0], pixel[1], pixel[2]));
     }
+    out.into_bytes()
 }
@@ -0,0 +1,91 @@
+//! A percentage and ETA on stderr while one file converts, so a long conversion doesn't
+//! look like a hang. Nothing is printed unless stderr is a terminal and the file has
+//! already taken a second.
+
+use std::io::{self, IsTerminal};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+///Inputs at least this big also say which stage they are in, for the steps that can't
+///report how far along they are
+const BIG_INPUT: usize = 64 << 20;
+
+///How long a conversion runs before progress appears, and how often it is redrawn
+const DELAY: Duration = Duration::from_secs(1);
+const REDRAW: Duration = Duration::from_millis(200);
+
+pub struct Progress {
+    label: String,
+    enabled: bool,
+    stages: bool,
+    start: Instant,
+    drawn: Option<Instant>,
+}
+
+impl Progress {
+    ///For converting `path`, `input_len` bytes long
+    pub fn new(path: &Path, input_len: usize) -> Self {
+        let enabled = io::stderr().is_terminal();
+        Progress {
+            label: path.display().to_string(),
+            enabled,
+            stages: enabled && input_len >= BIG_INPUT,
+            start: Instant::now(),
+            drawn: None,
+        }
+    }
+
+    ///Never draw, for when other files convert alongside and would fight over the line
+    pub fn hide(&mut self) {
+        self.enabled = false;
+        self.stages = false;
+    }
+
+    ///`done` out of `total` units (rows, bytes) are finished
+    pub fn update(&mut self, done: u64, total: u64) {
+        let elapsed = self.start.elapsed();
+        if !self.enabled || total == 0 || elapsed < DELAY {
+            return;
+        }
+        if self.drawn.is_some_and(|at| at.elapsed() < REDRAW) {
+            return;
+        }
+        let fraction = done.min(total) as f64 / total as f64;
+        let left = match fraction > 0.0 {
+            true => clock(elapsed.mul_f64((1.0 - fraction) / fraction)),
+            false => "?".into(),
+        };
+        self.draw(&format!("{:3.0}%, about {} left", fraction * 100.0, left));
+    }
+
+    ///Starting a step that can't report progress, e.g. "decoding"
+    pub fn stage(&mut self, name: &str) {
+        if self.stages {
+            let elapsed = clock(self.start.elapsed());
+            self.draw(&format!("{} ({} so far)", name, elapsed));
+        }
+    }
+
+    fn draw(&mut self, status: &str) {
+        eprint!("\r{}: {}\x1b[K", self.label, status);
+        self.drawn = Some(Instant::now());
+    }
+}
+
+impl Drop for Progress {
+    ///Clear the line, so whatever is printed next starts clean
+    fn drop(&mut self) {
+        if self.drawn.is_some() {
+            eprint!("\r\x1b[K");
+        }
+    }
+}
+
+///`m:ss`, or `h:mm:ss` past an hour
+fn clock(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    match secs / 3600 {
+        0 => format!("{}:{:02}", secs / 60, secs % 60),
+        hours => format!("{}:{:02}:{:02}", hours, secs / 60 % 60, secs % 60),
+    }
+}
@@ -1,7 +1,5 @@
-use crate::img::RawImage;
-use crate::util::TakeArray;
-use std::iter::Peekable;
-use std::slice::Chunks;
+use crate::img::{ColorSpace, Header, PixelFormat, RawImage};
+use crate::stream::TakeArray;
 #[derive(Debug, PartialEq, Clone)]
 enum Chunk {
     Rgb { r: u8, g: u8, b: u8 },
@@ -30,15 +28,6 @@ where
     byte_stream: I,
 }
 
-struct Compresser<'a, I>
-where
-    I: Iterator<Item = &'a [u8]>,
-{
-    pix_stream: Peekable<I>,
-    last_pix: [u8; 4],
-    seen: [[u8; 4]; 64],
-}
-
 struct Assembler<I>
 where
     I: Iterator<Item = Chunk>,
@@ -46,53 +35,685 @@ where
     chunk_stream: I,
 }
 
-fn hash(c: [u8; 4]) -> usize {
+/// The implicit "previous pixel" before the first chunk is decoded.
+pub const START_PIXEL: [u8; 4] = [0, 0, 0, 255];
+
+/// The index table starts zeroed, so every slot holds transparent black {0,0,0,0}.
+/// Note that START_PIXEL is *not* in the table: an INDEX chunk that points at a
+/// slot nobody has written yet (including slot 53, where START_PIXEL would hash)
+/// must yield {0,0,0,0}. This is what the reference encoder relies on when the
+/// very first pixel is transparent black and it emits INDEX 0 for it.
+pub const START_INDEX: [[u8; 4]; 64] = [[0; 4]; 64];
+
+///Seven 0x00 bytes and a 0x01 close every file
+pub const END_MARKER: [u8; 8] = [0, 0, 0, 0, 0, 0, 0, 1];
+
+///Why a .qoi file couldn't be decoded
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum QoiError {
+    ///The file doesn't start with `qoif`
+    BadMagic,
+    ///The header is cut short or holds a value the spec doesn't allow
+    InvalidHeader(&'static str),
+    ///The data ends partway through a chunk, or is too short to hold the declared pixels
+    TruncatedStream,
+    ///The chunks before the end marker decode to fewer pixels than the header declares
+    PixelCountMismatch { expected: usize, decoded: usize },
+    ///Strict decoding only: the file doesn't end with the 8-byte end marker
+    MissingEndMarker,
+    ///Strict decoding only: the last run repeats its pixel past the end of the image
+    RunOverflow { pixels: usize },
+    ///Strict decoding only: chunks left between the last pixel and the end marker
+    TrailingData { bytes: usize },
+    ///The buffer passed to [`decode_into`] can't hold the declared pixels
+    BufferTooSmall { needed: usize, len: usize },
+}
+
+impl std::fmt::Display for QoiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            QoiError::BadMagic => write!(f, "not a QOI file (bad magic bytes)"),
+            QoiError::InvalidHeader(reason) => write!(f, "invalid QOI header: {}", reason),
+            QoiError::TruncatedStream => write!(f, "QOI data ends before the last pixel"),
+            QoiError::PixelCountMismatch { expected, decoded } => write!(
+                f,
+                "QOI header declares {} pixels but the chunks only hold {}",
+                expected, decoded
+            ),
+            QoiError::MissingEndMarker => write!(f, "QOI data doesn't end with the end marker"),
+            QoiError::RunOverflow { pixels } => write!(
+                f,
+                "QOI run goes {} pixels past the end of the image",
+                pixels
+            ),
+            QoiError::TrailingData { bytes } => write!(
+                f,
+                "QOI data has {} bytes of chunks after the last pixel",
+                bytes
+            ),
+            QoiError::BufferTooSmall { needed, len } => write!(
+                f,
+                "QOI image needs a {}-byte buffer but was given {} bytes",
+                needed, len
+            ),
+        }
+    }
+}
+
+impl std::error::Error for QoiError {}
+
+pub fn hash(c: [u8; 4]) -> usize {
     (c[0] as usize * 3 + c[1] as usize * 5 + c[2] as usize * 7 + c[3] as usize * 11) % 64
 }
 
-///Take in file data as an iterator and return (width, height, pixel data)
-pub fn parse_img(data: impl Iterator<Item = u8>) -> RawImage {
-    let mut stream = data;
+///Read the 14-byte header, returning (width, height, channels, colorspace)
+fn parse_header(stream: &mut impl Iterator<Item = u8>) -> Result<(u32, u32, u8, u8), QoiError> {
+    let short = QoiError::InvalidHeader("shorter than 14 bytes");
+    let magic: [u8; 4] = stream.take_array().ok_or(QoiError::BadMagic)?;
+    if magic != *b"qoif" {
+        return Err(QoiError::BadMagic);
+    }
+    let width = u32::from_be_bytes(stream.take_array().ok_or(short.clone())?);
+    let height = u32::from_be_bytes(stream.take_array().ok_or(short.clone())?);
+    let channels: u8 = stream.next().ok_or(short.clone())?;
+    if channels != 3 && channels != 4 {
+        return Err(QoiError::InvalidHeader("channels must be 3 or 4"));
+    }
+    let colorspace: u8 = stream.next().ok_or(short)?;
+    if colorspace > 1 {
+        return Err(QoiError::InvalidHeader("colorspace must be 0 or 1"));
+    }
+    Ok((width, height, channels, colorspace))
+}
+
+///Read just the header of a .qoi file
+pub fn read_header(data: &[u8]) -> Result<Header, QoiError> {
+    let (width, height, channels, colorspace) = parse_header(&mut data.iter().copied())?;
+    Ok(Header {
+        width,
+        height,
+        channels,
+        bit_depth: 8,
+        colorspace: Some(colorspace_from_byte(colorspace)),
+    })
+}
+
+fn colorspace_from_byte(byte: u8) -> ColorSpace {
+    match byte {
+        0 => ColorSpace::Srgb,
+        _ => ColorSpace::Linear,
+    }
+}
 
-    assert_eq!(stream.take_array().unwrap(), [b'q', b'o', b'i', b'f']);
-    let width = u32::from_be_bytes(stream.take_array().unwrap());
-    let height = u32::from_be_bytes(stream.take_array().unwrap());
-    let channels: u8 = stream.next().unwrap();
-    assert!(channels == 3 || channels == 4);
-    let colorspace: u8 = stream.next().unwrap();
-    assert!(colorspace == 0 || colorspace == 1);
+///Pixel count from the header, rejecting counts the remaining data couldn't possibly hold.
+///The densest chunk is a one-byte run of 62, so this also keeps a forged header from
+///reserving gigabytes up front.
+fn checked_pix_count(width: u32, height: u32, body_len: usize) -> Result<usize, QoiError> {
+    let pix_count = width as u64 * height as u64;
+    if pix_count > body_len as u64 * 62 {
+        return Err(QoiError::TruncatedStream);
+    }
+    Ok(pix_count as usize)
+}
 
-    RawImage(
+///Take in file data as an iterator and return (width, height, pixel data)
+pub fn parse_img(data: impl Iterator<Item = u8>) -> Result<RawImage, QoiError> {
+    let mut stream = data;
+    let (width, height, channels, colorspace) = parse_header(&mut stream)?;
+    // Not Dimensions: QOI allows a 0x0 image. Too many pixels to hold can't all be in the data.
+    let expected = usize::try_from(width as u64 * height as u64)
+        .ok()
+        .filter(|pixels| pixels.checked_mul(4).is_some())
+        .ok_or(QoiError::TruncatedStream)?;
+
+    let pixels: Vec<u8> = stream.parse().interpret(expected).flatten().collect();
+    if pixels.len() < expected * 4 {
+        return Err(QoiError::TruncatedStream);
+    }
+    let img = RawImage(
         width,
         height,
-        stream
-            .parse()
-            .interpret((width * height) as usize)
-            .flatten()
-            .collect(),
-    )
+        pixels,
+        PixelFormat::Rgba8,
+        colorspace_from_byte(colorspace),
+    );
+    // As many channels as the header declares, like `decode`
+    Ok(match channels {
+        3 => img.convert(PixelFormat::Rgb8),
+        _ => img,
+    })
 }
 
-///Take in pixel and dimension data, return the .qoi file as a Vec<u8>
-pub fn encode_img(img: RawImage) -> Vec<u8> {
-    let RawImage(width, height, pixels) = img;
-    let mut header = vec![b'q', b'o', b'i', b'f'];
-    header.extend_from_slice(&width.to_be_bytes());
-    header.extend_from_slice(&height.to_be_bytes());
-    if pixels.chunks(4).all(|slice| *slice.last().unwrap() == 255) {
-        header.push(3); //RGB colorspace
-    } else {
-        header.push(4); //RGBA
+///Fast path for when the whole file is already in memory. Decodes straight from the slice
+///into as many channels as the header declares, so 3-channel files come out as Rgb8.
+pub fn decode(data: &[u8]) -> Result<RawImage, QoiError> {
+    decode_with(Decoder::new(), data)
+}
+
+///[`decode`], but rejecting anything the spec doesn't allow instead of decoding what it
+///can: a missing end marker, a run past the last pixel, or chunks after it
+pub fn decode_strict(data: &[u8]) -> Result<RawImage, QoiError> {
+    decode_with(Decoder::strict(), data)
+}
+
+///Decode straight into `out`, e.g. staging memory for a texture upload, without allocating.
+///Pixels have as many channels as the header declares; size `out` from [`read_header`].
+///A larger buffer is fine and its tail is left alone.
+pub fn decode_into(data: &[u8], out: &mut [u8]) -> Result<Header, QoiError> {
+    Decoder::new().decode_into_slice(data, out)
+}
+
+fn decode_with(mut decoder: Decoder, data: &[u8]) -> Result<RawImage, QoiError> {
+    let mut pixels = vec![];
+    let (width, height, format) = decoder.decode_into(data, &mut pixels)?;
+    let colorspace = colorspace_from_byte(data[13]);
+    Ok(RawImage(width, height, pixels, format, colorspace))
+}
+
+///Reusable decoder for frame loops: decodes into a caller-owned buffer, so a stream of
+///same-sized frames only allocates once. The index table is reset per frame as the spec requires.
+#[derive(Default)]
+pub struct Decoder {
+    strict: bool,
+}
+
+impl Decoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    ///A decoder that errors on malformed files instead of decoding what it can, see
+    ///[`decode_strict`]
+    pub fn strict() -> Self {
+        Decoder { strict: true }
+    }
+
+    ///Decode a whole .qoi file into `out` (cleared first), returning (width, height, format)
+    #[tracing::instrument(name = "qoi::decode", skip_all, fields(bytes = data.len()))]
+    pub fn decode_into(
+        &mut self,
+        data: &[u8],
+        out: &mut Vec<u8>,
+    ) -> Result<(u32, u32, PixelFormat), QoiError> {
+        let body = Body::parse(data)?;
+        out.clear();
+        out.resize(body.pix_count * body.channels as usize, 0);
+        let ended = body.decode(out);
+        if let Err(Ended::Cleanly { decoded }) = ended {
+            out.truncate(decoded * body.channels as usize);
+        }
+        self.check(&body, ended)?;
+        let format = match body.channels {
+            3 => PixelFormat::Rgb8,
+            _ => PixelFormat::Rgba8,
+        };
+        Ok((body.width, body.height, format))
+    }
+
+    ///Decode a whole .qoi file into the start of `out`, see [`decode_into`]
+    #[tracing::instrument(name = "qoi::decode", skip_all, fields(bytes = data.len()))]
+    pub fn decode_into_slice(&mut self, data: &[u8], out: &mut [u8]) -> Result<Header, QoiError> {
+        let body = Body::parse(data)?;
+        let needed = body.pix_count * body.channels as usize;
+        let len = out.len();
+        let out = out
+            .get_mut(..needed)
+            .ok_or(QoiError::BufferTooSmall { needed, len })?;
+        self.check(&body, body.decode(out))?;
+        Ok(Header {
+            width: body.width,
+            height: body.height,
+            channels: body.channels,
+            bit_depth: 8,
+            colorspace: Some(colorspace_from_byte(data[13])),
+        })
+    }
+
+    ///Turn how decoding ended into an error, if it's one this decoder objects to
+    fn check(&self, body: &Body, ended: Result<Leftover, Ended>) -> Result<(), QoiError> {
+        match ended {
+            Ok(_) if self.strict && !body.has_marker => Err(QoiError::MissingEndMarker),
+            Ok(Leftover { pixels, .. }) if self.strict && pixels > 0 => {
+                Err(QoiError::RunOverflow { pixels })
+            }
+            Ok(Leftover { bytes, .. }) if self.strict && bytes > 0 => {
+                Err(QoiError::TrailingData { bytes })
+            }
+            Ok(_) => Ok(()),
+            Err(Ended::Cleanly { decoded }) if body.has_marker => {
+                Err(QoiError::PixelCountMismatch {
+                    expected: body.pix_count,
+                    decoded,
+                })
+            }
+            Err(_) => Err(QoiError::TruncatedStream),
+        }
+    }
+}
+
+///A .qoi file with its header read, ready to decode
+struct Body<'a> {
+    width: u32,
+    height: u32,
+    channels: u8,
+    pix_count: usize,
+    chunks: &'a [u8],
+    has_marker: bool,
+}
+
+impl<'a> Body<'a> {
+    fn parse(data: &'a [u8]) -> Result<Self, QoiError> {
+        let mut stream = data.iter().copied();
+        let (width, height, channels, _) = parse_header(&mut stream)?;
+        // With the end marker in place, running out of chunks early means the header lied
+        // about the size. Without it, the file was cut off.
+        let (chunks, has_marker) = match data[14..].strip_suffix(&END_MARKER) {
+            Some(chunks) => (chunks, true),
+            None => (&data[14..], false),
+        };
+        Ok(Body {
+            width,
+            height,
+            channels,
+            pix_count: checked_pix_count(width, height, chunks.len())?,
+            chunks,
+            has_marker,
+        })
+    }
+
+    ///Decode into `out`, which holds exactly the declared pixels
+    fn decode(&self, out: &mut [u8]) -> Result<Leftover, Ended> {
+        match self.channels {
+            3 => decode_pixels::<3>(self.chunks, out),
+            _ => decode_pixels::<4>(self.chunks, out),
+        }
+    }
+}
+
+///What to declare in the header's channels byte. The pixels are stored the same way
+///either way; it tells decoders whether to hand back RGB or RGBA.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum QoiChannels {
+    ///3 if the image's pixel format has no alpha, 4 if it does
+    #[default]
+    Format,
+    ///3 if every pixel is opaque, 4 otherwise
+    Auto,
+    ///3, making every pixel opaque
+    Rgb,
+    ///4
+    Rgba,
+}
+
+///Reusable encoder for frame loops: keeps its RGBA scratch buffer between frames and
+///writes into a caller-owned output buffer.
+#[derive(Default)]
+pub struct Encoder {
+    scratch: Vec<u8>,
+    channels: QoiChannels,
+}
+
+impl Encoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    ///An encoder that declares channels as `channels` says
+    pub fn with_channels(channels: QoiChannels) -> Self {
+        Encoder {
+            scratch: vec![],
+            channels,
+        }
+    }
+
+    ///Encode `img` as a .qoi file into `out` (cleared first)
+    #[tracing::instrument(name = "qoi::encode", skip_all, fields(width = img.0, height = img.1))]
+    pub fn encode_into(&mut self, img: &RawImage, out: &mut Vec<u8>) {
+        // Decoders make 3-channel files opaque, so that's what gets stored
+        let opaque = self.channels == QoiChannels::Rgb && img.format().has_alpha();
+        let pixels = if img.format() == PixelFormat::Rgba8 && !opaque {
+            img.2.as_slice()
+        } else {
+            img.rgba8_into(&mut self.scratch);
+            if opaque {
+                self.scratch
+                    .chunks_exact_mut(4)
+                    .for_each(|pixel| pixel[3] = 255);
+            }
+            self.scratch.as_slice()
+        };
+
+        out.clear();
+        out.extend_from_slice(b"qoif");
+        out.extend_from_slice(&img.0.to_be_bytes());
+        out.extend_from_slice(&img.1.to_be_bytes());
+        let rgb = match self.channels {
+            QoiChannels::Format => !img.format().has_alpha(),
+            QoiChannels::Auto => pixels.chunks_exact(4).all(|pixel| pixel[3] == 255),
+            QoiChannels::Rgb => true,
+            QoiChannels::Rgba => false,
+        };
+        out.push(if rgb { 3 } else { 4 });
+        out.push(match img.colorspace() {
+            ColorSpace::Srgb => 0,
+            ColorSpace::Linear => 1,
+        });
+
+        encode_pixels(pixels, out);
+        out.extend_from_slice(&END_MARKER);
+    }
+}
+
+///Each channel's share of [`hash`], so the encoder can add up four lookups instead
+const HASH_TABLES: [[u8; 256]; 4] = {
+    let factors = [3, 5, 7, 11];
+    let mut tables = [[0; 256]; 4];
+    let mut channel = 0;
+    while channel < 4 {
+        let mut value = 0;
+        while value < 256 {
+            tables[channel][value] = (value * factors[channel] % 64) as u8;
+            value += 1;
+        }
+        channel += 1;
+    }
+    tables
+};
+
+///Compress RGBA8 pixels into chunks, choosing the same ones as the reference encoder.
+///Pixels are compared as u32s.
+fn encode_pixels(pixels: &[u8], out: &mut Vec<u8>) {
+    // The worst case is an RGBA chunk, 5 bytes, for every pixel
+    out.reserve(pixels.len() + pixels.len() / 4);
+    let mut seen = START_INDEX.map(u32::from_ne_bytes);
+    let mut last = START_PIXEL;
+    let mut run = 0;
+    for pixel in pixels.chunks_exact(4) {
+        let pixel: [u8; 4] = pixel.try_into().unwrap();
+        if u32::from_ne_bytes(pixel) == u32::from_ne_bytes(last) {
+            run += 1;
+            if run == 62 {
+                out.push(0b1100_0000 | (run - 1));
+                run = 0;
+            }
+            continue;
+        }
+        if run > 0 {
+            out.push(0b1100_0000 | (run - 1));
+        }
+        run = 0;
+        encode_pixel(pixel, last, &mut seen, out);
+        last = pixel;
+    }
+    if run > 0 {
+        out.push(0b1100_0000 | (run - 1));
+    }
+}
+
+///Write the chunk for one pixel that doesn't continue a run
+#[inline(always)]
+fn encode_pixel(pixel: [u8; 4], last: [u8; 4], seen: &mut [u32; 64], out: &mut Vec<u8>) {
+    let [r, g, b, a] = pixel;
+    let at = (HASH_TABLES[0][r as usize]
+        + HASH_TABLES[1][g as usize]
+        + HASH_TABLES[2][b as usize]
+        + HASH_TABLES[3][a as usize]) as usize
+        % 64;
+    let packed = u32::from_ne_bytes(pixel);
+    if seen[at] == packed {
+        out.push(at as u8);
+        return;
+    }
+    seen[at] = packed;
+    // Diff, Luma and Rgb all keep the previous alpha. Checking this up front matters
+    // most for the first pixel, whose implicit predecessor is opaque black.
+    if a != last[3] {
+        out.extend_from_slice(&[0b1111_1111, r, g, b, a]);
+        return;
+    }
+    // The decoder adds differences modulo 256, so they wrap: 255 to 0 is a difference of 1
+    let dr = r.wrapping_sub(last[0]) as i8;
+    let dg = g.wrapping_sub(last[1]) as i8;
+    let db = b.wrapping_sub(last[2]) as i8;
+    if (-2..2).contains(&dr) && (-2..2).contains(&dg) && (-2..2).contains(&db) {
+        let (dr, dg, db) = ((dr + 2) as u8, (dg + 2) as u8, (db + 2) as u8);
+        out.push(0b0100_0000 | dr << 4 | dg << 2 | db);
+        return;
+    }
+    let (dr_dg, db_dg) = (dr.wrapping_sub(dg), db.wrapping_sub(dg));
+    if (-32..32).contains(&dg) && (-8..8).contains(&dr_dg) && (-8..8).contains(&db_dg) {
+        let (dg, dr_dg, db_dg) = ((dg + 32) as u8, (dr_dg + 8) as u8, (db_dg + 8) as u8);
+        out.extend_from_slice(&[0b1000_0000 | dg, dr_dg << 4 | db_dg]);
+        return;
+    }
+    out.extend_from_slice(&[0b1111_1110, r, g, b]);
+}
+
+///How the chunk data ran out before the last pixel
+enum Ended {
+    ///On a chunk boundary, after this many pixels
+    Cleanly { decoded: usize },
+    ///Partway through a chunk
+    MidChunk,
+}
+
+///What decoding left over once every pixel was out, which only strict decoding objects to
+struct Leftover {
+    ///Bytes of the body not read
+    bytes: usize,
+    ///Repeats of the last run beyond the last pixel
+    pixels: usize,
+}
+
+///Fill `out` with pixels, or say how the data ran out if it couldn't. Decoder state is
+///always RGBA, packed in a u32 (alpha feeds the hash), but only the first N channels are
+///written out. N is known at compile time, so the output loop doesn't branch on it, and
+///`out` is filled pixel by pixel through exact-size chunks, so nothing is bounds-checked.
+fn decode_pixels<const N: usize>(body: &[u8], out: &mut [u8]) -> Result<Leftover, Ended> {
+    let mut slots = out.chunks_exact_mut(N);
+    let pix_count = slots.len();
+    let mut pixel = u32::from_ne_bytes(START_PIXEL);
+    let mut seen = START_INDEX.map(u32::from_ne_bytes);
+    let mut bytes = body.iter().copied();
+    let mut overrun = 0;
+
+    while slots.len() > 0 {
+        let remaining = bytes.len();
+        let Some(run) = step(&mut pixel, &mut seen, &mut bytes) else {
+            return Err(if remaining == 0 {
+                Ended::Cleanly {
+                    decoded: pix_count - slots.len(),
+                }
+            } else {
+                Ended::MidChunk
+            });
+        };
+        let value = pixel.to_ne_bytes();
+        if run == 1 {
+            if let Some(slot) = slots.next() {
+                slot.copy_from_slice(&value[..N]);
+            }
+        } else {
+            overrun = run.saturating_sub(slots.len());
+            for slot in slots.by_ref().take(run) {
+                slot.copy_from_slice(&value[..N]);
+            }
+        }
+    }
+    Ok(Leftover {
+        bytes: bytes.len(),
+        pixels: overrun,
+    })
+}
+
+///Apply the next chunk to `pixel` and the index, returning how many times the resulting
+///pixel repeats, or None if the data ran out mid-chunk
+#[inline(always)]
+fn step(
+    pixel: &mut u32,
+    seen: &mut [u32; 64],
+    bytes: &mut impl Iterator<Item = u8>,
+) -> Option<usize> {
+    let byte = bytes.next()?;
+    let mut run = 1;
+    let [r, g, b, a] = pixel.to_ne_bytes();
+    match byte {
+        0b1111_1110 => {
+            let [r, g, b] = bytes.take_array()?;
+            *pixel = u32::from_ne_bytes([r, g, b, a]);
+        }
+        0b1111_1111 => *pixel = u32::from_ne_bytes(bytes.take_array()?),
+        _ => match byte >> 6 {
+            0b00 => *pixel = seen[byte as usize],
+            0b01 => {
+                *pixel = u32::from_ne_bytes([
+                    r.wrapping_add((byte >> 4) & 0b11).wrapping_sub(2),
+                    g.wrapping_add((byte >> 2) & 0b11).wrapping_sub(2),
+                    b.wrapping_add(byte & 0b11).wrapping_sub(2),
+                    a,
+                ]);
+            }
+            0b10 => {
+                let next_byte = bytes.next()?;
+                let dg = (byte & 0b0011_1111).wrapping_sub(32);
+                *pixel = u32::from_ne_bytes([
+                    r.wrapping_add(dg)
+                        .wrapping_add(next_byte >> 4)
+                        .wrapping_sub(8),
+                    g.wrapping_add(dg),
+                    b.wrapping_add(dg)
+                        .wrapping_add(next_byte & 0b1111)
+                        .wrapping_sub(8),
+                    a,
+                ]);
+            }
+            _ => run = (byte & 0b0011_1111) as usize + 1,
+        },
+    }
+    seen[hash(pixel.to_ne_bytes())] = *pixel;
+    Some(run)
+}
+
+///Decodes a .qoi file one row at a time, so a transcode only ever holds one row of pixels.
+///Rows come out with as many channels as the header declares.
+pub struct RowDecoder<'a> {
+    bytes: std::iter::Copied<std::slice::Iter<'a, u8>>,
+    pixel: u32,
+    seen: [u32; 64],
+    run: usize,
+    pub width: u32,
+    pub height: u32,
+    pub channels: u8,
+}
+
+impl<'a> RowDecoder<'a> {
+    pub fn new(data: &'a [u8]) -> Result<Self, QoiError> {
+        let mut stream = data.iter().copied();
+        let (width, height, channels, _) = parse_header(&mut stream)?;
+        let body = data[14..].strip_suffix(&END_MARKER).unwrap_or(&data[14..]);
+        checked_pix_count(width, height, body.len())?;
+        Ok(RowDecoder {
+            bytes: body.iter().copied(),
+            pixel: u32::from_ne_bytes(START_PIXEL),
+            seen: START_INDEX.map(u32::from_ne_bytes),
+            run: 0,
+            width,
+            height,
+            channels,
+        })
     }
-    header.push(1); // Not messing with sRGB yet
 
-    let compressed: Compresser<Chunks<'_, u8>> = pixels.as_slice().compress();
+    ///Decode the next row into `out` (cleared first)
+    pub fn read_row(&mut self, out: &mut Vec<u8>) -> Result<(), QoiError> {
+        let channels = self.channels as usize;
+        out.clear();
+        for _ in 0..self.width {
+            if self.run == 0 {
+                self.run = step(&mut self.pixel, &mut self.seen, &mut self.bytes)
+                    .ok_or(QoiError::TruncatedStream)?;
+            }
+            self.run -= 1;
+            out.extend_from_slice(&self.pixel.to_ne_bytes()[..channels]);
+        }
+        Ok(())
+    }
+}
 
-    header
-        .into_iter()
-        .chain(compressed.assemble().flatten())
-        .chain([0, 0, 0, 0, 0, 0, 0, 1])
-        .collect()
+///What `encode_within` gave up to fit the budget
+pub struct Sacrifice {
+    pub dropped_bits: u32,
+    pub original: (u32, u32),
+    pub scaled: (u32, u32),
+}
+
+impl std::fmt::Display for Sacrifice {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut lost = vec![];
+        if self.dropped_bits > 0 {
+            lost.push(format!(
+                "dropped the low {} bits of each color channel",
+                self.dropped_bits
+            ));
+        }
+        if self.scaled != self.original {
+            lost.push(format!(
+                "downscaled from {}x{} to {}x{}",
+                self.original.0, self.original.1, self.scaled.0, self.scaled.1
+            ));
+        }
+        if lost.is_empty() {
+            write!(f, "nothing")
+        } else {
+            write!(f, "{}", lost.join(", "))
+        }
+    }
+}
+
+///Encode into at most `budget` bytes, posterizing harder and then halving the size until
+///the file fits. Returns None if not even a 1x1 image fits.
+pub fn encode_within(img: RawImage, budget: usize) -> Option<(Vec<u8>, Sacrifice)> {
+    const MAX_DROPPED_BITS: u32 = 5;
+    let original = (img.0, img.1);
+    // Posterizing works in RGBA8, so the header goes by the format the image came in
+    let mut encoder = Encoder::with_channels(match img.format().has_alpha() {
+        true => QoiChannels::Rgba,
+        false => QoiChannels::Rgb,
+    });
+    let mut out = vec![];
+    let mut img = img.convert(PixelFormat::Rgba8);
+    loop {
+        for dropped_bits in 0..=MAX_DROPPED_BITS {
+            encoder.encode_into(&img.clone().posterize(dropped_bits), &mut out);
+            if out.len() <= budget {
+                let scaled = (img.0, img.1);
+                return Some((
+                    out,
+                    Sacrifice {
+                        dropped_bits,
+                        original,
+                        scaled,
+                    },
+                ));
+            }
+        }
+        if img.0 <= 1 && img.1 <= 1 {
+            return None;
+        }
+        img = img.half_size();
+    }
+}
+
+///Take in pixel and dimension data, return the .qoi file as a `Vec<u8>`. The header
+///declares 3 channels if the pixel format has no alpha and 4 if it does.
+pub fn encode_img(img: RawImage) -> Vec<u8> {
+    encode_img_with(img, QoiChannels::Format)
+}
+
+///Encode as QOI, declaring channels as `channels` says
+pub fn encode_img_with(img: RawImage, channels: QoiChannels) -> Vec<u8> {
+    let mut out = vec![];
+    Encoder::with_channels(channels).encode_into(&img, &mut out);
+    out
 }
 
 //==============BOILERPLATE====================================//
@@ -114,8 +735,8 @@ where
             max_pix: max_len,
             pix_count: 0,
             chunk_stream: self,
-            pixel: [0, 0, 0, 255],
-            seen: [[0; 4]; 64],
+            pixel: START_PIXEL,
+            seen: START_INDEX,
         } //Once called, create an Interpreter with all related state
     }
 }
@@ -137,27 +758,6 @@ where
     }
 }
 
-trait Compress<'a, I>
-where
-    Self: Sized,
-    I: Iterator<Item = &'a [u8]>,
-{
-    fn compress(self) -> Compresser<'a, I>;
-}
-
-impl<'a, I> Compress<'a, I> for &'a [u8]
-where
-    I: Iterator<Item = &'a [u8]> + std::convert::From<std::slice::Chunks<'a, u8>>,
-{
-    fn compress(self) -> Compresser<'a, I> {
-        Compresser {
-            pix_stream: <Chunks<'_, u8> as Into<I>>::into(self.chunks(4)).peekable(),
-            last_pix: [0, 0, 0, 255],
-            seen: [[0; 4]; 64],
-        }
-    }
-}
-
 trait Assemble<I>
 where
     I: Iterator<Item = Chunk>,
@@ -192,6 +792,8 @@ impl<I: Iterator<Item = Chunk>> Iterator for Interpreter<I> {
                 self.pixel = [r, g, b, a];
             }
             Chunk::Index { loc } => {
+                // Slots that were never written read back as {0,0,0,0} (see START_INDEX),
+                // never as the implicit previous pixel.
                 self.pixel = self.seen[loc];
             }
             Chunk::Diff { dr, dg, db } => {
@@ -257,11 +859,11 @@ impl<I: Iterator<Item = u8>> Iterator for Parser<I> {
             }),
             0b10 => {
                 let next_byte = self.byte_stream.next()?;
-                return Some(Chunk::Luma {
+                Some(Chunk::Luma {
                     dg: (byte & 0b0011_1111) as i8 - 32,
                     dr_dg: (next_byte >> 4) as i8 - 8,
                     db_dg: (next_byte & 0b1111) as i8 - 8,
-                });
+                })
             }
             0b11 => Some(Chunk::Run {
                 length: (byte & 0b11_1111) + 1,
@@ -278,96 +880,128 @@ where
 {
     type Item = Vec<u8>;
     fn next(&mut self) -> Option<<Self as Iterator>::Item> {
-        match self.chunk_stream.next()? {
-            Chunk::Rgb { r, g, b } => Some(vec![0b1111_1110, r, g, b]),
-            Chunk::Rgba { r, g, b, a } => Some(vec![0b1111_1111, r, g, b, a]),
-            Chunk::Index { loc } => Some(vec![loc as u8 & 0b0011_1111]),
-            Chunk::Diff { dr, dg, db } => Some(vec![
-                0b0100_0000 | ((dr + 2) as u8) << 4 | ((dg + 2) as u8) << 2 | (db + 2) as u8,
-            ]),
-            Chunk::Luma { dg, dr_dg, db_dg } => Some(vec![
+        let mut bytes = Vec::with_capacity(5);
+        self.chunk_stream.next()?.write_to(&mut bytes);
+        Some(bytes)
+    }
+}
+
+impl Chunk {
+    ///Append the byte representation of this chunk
+    fn write_to(&self, out: &mut Vec<u8>) {
+        match *self {
+            Chunk::Rgb { r, g, b } => out.extend_from_slice(&[0b1111_1110, r, g, b]),
+            Chunk::Rgba { r, g, b, a } => out.extend_from_slice(&[0b1111_1111, r, g, b, a]),
+            Chunk::Index { loc } => out.push(loc as u8 & 0b0011_1111),
+            Chunk::Diff { dr, dg, db } => out
+                .push(0b0100_0000 | ((dr + 2) as u8) << 4 | ((dg + 2) as u8) << 2 | (db + 2) as u8),
+            Chunk::Luma { dg, dr_dg, db_dg } => out.extend_from_slice(&[
                 0b1000_0000 | (dg + 32) as u8,
                 ((dr_dg + 8) as u8) << 4 | (db_dg + 8) as u8,
             ]),
-            Chunk::Run { length } => Some(vec![0b1100_0000 | (length & 0b0011_1111) - 1]),
+            Chunk::Run { length } => out.push(0b1100_0000 | ((length & 0b0011_1111) - 1)),
         }
     }
 }
 
-impl<'a, I> Iterator for Compresser<'a, I>
-where
-    I: Iterator<Item = &'a [u8]>,
-{
-    type Item = Chunk;
-    fn next(&mut self) -> Option<<Self as Iterator>::Item> {
-        let pix: [u8; 4] = self.pix_stream.next()?.try_into().unwrap();
-
-        if self.last_pix == pix {
-            let mut length: u8 = 1;
-            while let Some(&next_pix) = self.pix_stream.peek() {
-                if next_pix == pix && length <= 61 {
-                    length += 1;
-                    self.pix_stream.next();
-                } else {
-                    break;
-                }
-            }
-            if length > 1 {
-                return Some(Chunk::Run { length });
-            }
-        }
-        let (dr, dg, db) = dr_dg_db(pix, self.last_pix);
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        if self.seen[hash(pix)] == pix {
-            self.last_pix = pix;
-            return Some(Chunk::Index { loc: hash(pix) });
-        }
+    ///The chunks for RGBA8 pixels, without header or end marker
+    fn chunks(pixels: &[[u8; 4]]) -> Vec<u8> {
+        let mut out = vec![];
+        encode_pixels(pixels.as_flattened(), &mut out);
+        out
+    }
 
-        self.seen[hash(pix)] = pix;
+    ///Decode a row of `width` RGBA pixels from bare chunks with every decoder, checking
+    ///that they agree
+    fn decoded(width: u32, chunks: &[u8]) -> Vec<[u8; 4]> {
+        let mut data = b"qoif".to_vec();
+        data.extend_from_slice(&width.to_be_bytes());
+        data.extend_from_slice(&1u32.to_be_bytes());
+        data.extend_from_slice(&[4, 0]);
+        data.extend_from_slice(chunks);
+        data.extend_from_slice(&END_MARKER);
+        let fast = decode_strict(&data).unwrap();
+        let streamed = parse_img(data.iter().copied()).unwrap();
+        let mut row = vec![];
+        RowDecoder::new(&data).unwrap().read_row(&mut row).unwrap();
+        assert_eq!(fast.2, streamed.2);
+        assert_eq!(fast.2, row);
+        fast.2
+            .chunks_exact(4)
+            .map(|p| p.try_into().unwrap())
+            .collect()
+    }
 
-        if (-2..=1).contains(&dr) && (-2..=1).contains(&dg) && (-2..=1).contains(&db) {
-            self.last_pix = pix;
-            return Some(Chunk::Diff {
-                dr: dr as i8,
-                dg: dg as i8,
-                db: db as i8,
-            });
-        }
+    #[test]
+    fn index_before_any_write_is_transparent_black() {
+        // Slot 53 is where the start pixel would hash, but it was never stored there
+        assert_eq!(decoded(2, &[53, 12]), [[0, 0, 0, 0]; 2]);
+    }
 
-        if (-32..=31).contains(&dg) && (-8..7).contains(&(dr - dg)) && (-8..7).contains(&(db - dg))
-        {
-            self.last_pix = pix;
-            return Some(Chunk::Luma {
-                dg: dg as i8,
-                dr_dg: (dr - dg) as i8,
-                db_dg: (db - dg) as i8,
-            });
-        }
+    #[test]
+    fn first_pixel_keeps_or_sets_alpha() {
+        assert_eq!(decoded(1, &[0xff, 10, 20, 30, 40]), [[10, 20, 30, 40]]);
+        assert_eq!(decoded(1, &[0xfe, 10, 20, 30]), [[10, 20, 30, 255]]);
+        assert_eq!(chunks(&[[10, 20, 30, 40]]), [0xff, 10, 20, 30, 40]);
+    }
 
-        if pix[3] == self.last_pix[3] {
-            self.last_pix = pix;
-            return Some(Chunk::Rgb {
-                r: pix[0],
-                g: pix[1],
-                b: pix[2],
-            });
-        }
+    #[test]
+    fn diff_and_luma_boundaries() {
+        let chunks = [
+            0b0100_0000, // -2 each
+            0b0111_1111, // +1 each
+            0b1000_0000,
+            0x00, // green -32, red and blue -8 more
+            0b1011_1111,
+            0xff, // green +31, red and blue 7 more
+            0b0111_1111,
+            0b0111_1111, // green wraps past 255
+        ];
+        assert_eq!(
+            decoded(6, &chunks),
+            [
+                [254, 254, 254, 255],
+                [255, 255, 255, 255],
+                [215, 223, 215, 255],
+                [253, 254, 253, 255],
+                [254, 255, 254, 255],
+                [255, 0, 255, 255],
+            ]
+        );
+    }
 
-        self.last_pix = pix;
-        Some(Chunk::Rgba {
-            r: pix[0],
-            g: pix[1],
-            b: pix[2],
-            a: pix[3],
-        })
+    #[test]
+    fn luma_reaches_plus_seven() {
+        // Red and blue are 7 above green, the top of LUMA's range
+        assert_eq!(chunks(&[[7, 0, 7, 255]]), [0b1010_0000, 0xff]);
+        assert_eq!(chunks(&[[0, 8, 0, 255]]), [0b1010_1000, 0x00]);
+        // One further needs the whole pixel
+        assert_eq!(chunks(&[[8, 0, 0, 255]]), [0b1111_1110, 8, 0, 0]);
+    }
+
+    #[test]
+    fn differences_wrap() {
+        // 0 to 255 is a difference of -1 in every channel
+        assert_eq!(chunks(&[[255, 255, 255, 255]]), [0b0101_0101]);
+        assert_eq!(chunks(&[[250, 255, 250, 255]]), [0b1001_1111, 0x33]);
+        assert_eq!(
+            chunks(&[[255, 0, 0, 255], [1, 0, 0, 255]]),
+            [0b0101_1010, 0b1000_0000 | 32, 0xa8]
+        );
     }
-}
 
-fn dr_dg_db(pix: [u8; 4], last_pix: [u8; 4]) -> (i16, i16, i16) {
-    // println!("{:?}-{:?}", pix, last_pix);
-    (
-        ((pix[0] as i16) - (last_pix[0] as i16)),
-        ((pix[1] as i16) - (last_pix[1] as i16)),
-        ((pix[2] as i16) - (last_pix[2] as i16)),
-    )
+    #[test]
+    fn pics_match_the_reference_encoder() {
+        let dir = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("pics");
+        for name in ["kodim10", "kodim23"] {
+            let ppm = std::fs::read(dir.join(format!("{}.ppm", name))).unwrap();
+            let qoi = std::fs::read(dir.join(format!("{}.qoi", name))).unwrap();
+            let img = crate::ppm::parse_img(ppm.into_iter()).unwrap();
+            assert!(encode_img(img) == qoi, "{}", name);
+        }
+    }
 }
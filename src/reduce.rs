@@ -0,0 +1,95 @@
+//! Per-pixel reductions across a sequence of same-sized frames, for `reduce`
+
+use crate::img::{PixelFormat, RawImage};
+use std::path::{Path, PathBuf};
+
+///Roughly how much memory a median may spend holding the same strip of every frame
+const MEDIAN_BUDGET: usize = 256 << 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ReduceOp {
+    ///Average of every frame, e.g. to average out sensor noise
+    Mean,
+    ///Middle value of every frame (the lower one for an even count), which drops outliers
+    ///like passing cars or satellites
+    Median,
+    ///Brightest value of every frame, e.g. for star trails
+    Max,
+}
+
+///Reduce `frames` to a single RGBA8 image, loading them one at a time through `load`.
+///Mean and max only ever hold one frame and the running result. Median needs every
+///frame's value for a pixel at once, so it works in horizontal strips sized to
+///`MEDIAN_BUDGET`, loading each frame once per strip.
+pub fn reduce(
+    frames: &[PathBuf],
+    op: ReduceOp,
+    load: impl Fn(&Path) -> Result<RawImage, String>,
+) -> Result<RawImage, String> {
+    let load = |path: &Path| load(path).map_err(|e| format!("{}: {}", path.display(), e));
+    let first = frames
+        .first()
+        .ok_or("At least one input file is required")?;
+    let RawImage(width, height, first, _, space) = load(first)?.convert(PixelFormat::Rgba8);
+    let load_rgba = |path: &Path| -> Result<Vec<u8>, String> {
+        let img = load(path)?;
+        if (img.0, img.1) != (width, height) {
+            return Err(format!(
+                "{}: {}x{} doesn't match the first frame's {}x{}",
+                path.display(),
+                img.0,
+                img.1,
+                width,
+                height
+            ));
+        }
+        Ok(img.convert(PixelFormat::Rgba8).2)
+    };
+
+    let pixels = match op {
+        ReduceOp::Mean => {
+            let mut sums: Vec<u32> = first.iter().map(|&v| v as u32).collect();
+            for path in &frames[1..] {
+                for (sum, v) in sums.iter_mut().zip(load_rgba(path)?) {
+                    *sum += v as u32;
+                }
+            }
+            let count = frames.len() as u32;
+            sums.iter()
+                .map(|sum| ((sum + count / 2) / count) as u8)
+                .collect()
+        }
+        ReduceOp::Max => {
+            let mut max = first;
+            for path in &frames[1..] {
+                for (max, v) in max.iter_mut().zip(load_rgba(path)?) {
+                    *max = (*max).max(v);
+                }
+            }
+            max
+        }
+        ReduceOp::Median => {
+            drop(first);
+            let row_len = width as usize * 4;
+            let strip_rows = (MEDIAN_BUDGET / (frames.len() * row_len).max(1)).max(1);
+            let mut out = Vec::with_capacity(row_len * height as usize);
+            let mut stack = vec![];
+            let mut values = Vec::with_capacity(frames.len());
+            for top in (0..height as usize).step_by(strip_rows) {
+                let strip = top * row_len..(top + strip_rows).min(height as usize) * row_len;
+                stack.clear();
+                for path in frames {
+                    stack.extend_from_slice(&load_rgba(path)?[strip.clone()]);
+                }
+                for i in 0..strip.len() {
+                    values.clear();
+                    values.extend(stack[i..].iter().step_by(strip.len()));
+                    let middle = (values.len() - 1) / 2;
+                    out.push(*values.select_nth_unstable(middle).1);
+                }
+            }
+            out
+        }
+    };
+    Ok(RawImage(width, height, pixels, PixelFormat::Rgba8, space))
+}
@@ -0,0 +1,168 @@
+//! Output names built from a template like `{date}_{w}x{h}_{n}`, so a batch of converted
+//! photos says what it is without opening anything.
+
+use crate::{jpeg, png, tiff};
+use std::path::{Component, Path};
+use std::time::UNIX_EPOCH;
+
+///What a template can refer to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Field {
+    ///The input's file name without its extension
+    Name,
+    ///Position in the batch from 1, zero-padded so names sort in order
+    N,
+    W,
+    H,
+    ///`YYYY-MM-DD`
+    Date,
+    ///`HHMMSS`
+    Time,
+}
+
+const FIELDS: [(&str, Field); 6] = [
+    ("name", Field::Name),
+    ("n", Field::N),
+    ("w", Field::W),
+    ("h", Field::H),
+    ("date", Field::Date),
+    ("time", Field::Time),
+];
+
+enum Part {
+    Text(String),
+    Field(Field),
+}
+
+pub struct Template(Vec<Part>);
+
+///Everything known about one input, to fill a template in with
+pub struct Fields<'a> {
+    pub name: &'a str,
+    pub n: usize,
+    ///How many files the batch has, which sets the width of `{n}`
+    pub count: usize,
+    pub width: u32,
+    pub height: u32,
+    ///`YYYY:MM:DD HH:MM:SS`, the way EXIF writes dates
+    pub taken: &'a str,
+}
+
+impl Template {
+    pub fn parse(template: &str) -> Result<Self, String> {
+        if !Path::new(template)
+            .components()
+            .all(|c| matches!(c, Component::Normal(_)))
+        {
+            return Err(format!(
+                "Rename template '{}' must stay inside the input's folder",
+                template
+            ));
+        }
+        let mut parts = vec![];
+        let mut rest = template;
+        while let Some(open) = rest.find(['{', '}']) {
+            if !rest[..open].is_empty() {
+                parts.push(Part::Text(rest[..open].to_string()));
+            }
+            let close = match rest[open..].starts_with('{') {
+                true => rest[open..].find('}').map(|close| open + close),
+                false => None,
+            }
+            .ok_or_else(|| format!("Unmatched brace in rename template '{}'", template))?;
+            let name = &rest[open + 1..close];
+            let &(_, field) = FIELDS.iter().find(|(n, _)| *n == name).ok_or_else(|| {
+                let known: Vec<String> = FIELDS.iter().map(|(n, _)| format!("{{{}}}", n)).collect();
+                format!(
+                    "Unknown field {{{}}} in rename template, expected one of {}",
+                    name,
+                    known.join(" ")
+                )
+            })?;
+            parts.push(Part::Field(field));
+            rest = &rest[close + 1..];
+        }
+        if !rest.is_empty() {
+            parts.push(Part::Text(rest.to_string()));
+        }
+        Ok(Template(parts))
+    }
+
+    ///The output name, without an extension
+    pub fn render(&self, fields: &Fields) -> String {
+        let mut out = String::new();
+        for part in &self.0 {
+            match part {
+                Part::Text(text) => out.push_str(text),
+                Part::Field(Field::Name) => out.push_str(fields.name),
+                Part::Field(Field::N) => {
+                    let digits = fields.count.to_string().len();
+                    out.push_str(&format!("{:0digits$}", fields.n));
+                }
+                Part::Field(Field::W) => out.push_str(&fields.width.to_string()),
+                Part::Field(Field::H) => out.push_str(&fields.height.to_string()),
+                Part::Field(Field::Date) => out.push_str(&fields.taken[..10].replace(':', "-")),
+                Part::Field(Field::Time) => out.push_str(&fields.taken[11..].replace(':', "")),
+            }
+        }
+        out
+    }
+}
+
+///When a photo was taken, from the EXIF block of a JPEG or TIFF or a PNG's eXIf or tIME
+///chunk, falling back to when the file was last modified
+pub fn taken(path: &Path, extension: &str, data: &[u8]) -> Result<String, String> {
+    let date = match extension {
+        "jpg" | "jpeg" => jpeg::exif(data).and_then(tiff::exif_date),
+        "tif" | "tiff" => tiff::exif_date(data),
+        "png" => png::date(data),
+        _ => None,
+    };
+    match date.filter(|date| valid_date(date)) {
+        Some(date) => Ok(date),
+        None => modified(path),
+    }
+}
+
+///Whether a date is in full EXIF form; cameras with no clock set write blanks or zeros
+fn valid_date(date: &str) -> bool {
+    let shape = b"dddd:dd:dd dd:dd:dd";
+    date.len() == shape.len()
+        && date.bytes().zip(shape).all(|(b, &s)| match s {
+            b'd' => b.is_ascii_digit(),
+            _ => b == s,
+        })
+        && !date.starts_with("0000")
+}
+
+///A file's modification time in UTC, as an EXIF-style date, for when it carries none
+fn modified(path: &Path) -> Result<String, String> {
+    let modified = path
+        .metadata()
+        .and_then(|m| m.modified())
+        .map_err(|e| format!("{}: {}", path.display(), e))?;
+    let secs = modified
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let (days, secs) = (secs / 86400, secs % 86400);
+    // Days since 1970 to a civil date, from Howard Hinnant's date algorithms
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as i64;
+    Ok(format!(
+        "{:04}:{:02}:{:02} {:02}:{:02}:{:02}",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    ))
+}
@@ -0,0 +1,218 @@
+//! `shell`: an image stays loaded while commands typed at a prompt transform, inspect and
+//! save it, so trying things out doesn't decode the file again for every step
+
+use crate::filter::Filter;
+use crate::img::{RawImage, Redaction};
+use crate::slice::{self, Borders};
+use crate::{qoi, term};
+use std::collections::HashSet;
+use std::io::{self, BufRead, IsTerminal, Write};
+use std::path::Path;
+
+const HELP: &str = "\
+crop WxH+X+Y       keep just that rectangle
+trim [TOLERANCE]   crop away borders the color of the top-left pixel
+resize WxH         nearest-neighbour scale to exactly that size
+half               halve both sides, averaging 2x2 blocks
+posterize BITS     drop the lowest BITS bits of every color channel
+filter EXPR        run a per-pixel expression, as convert --filter does
+redact REGION      black out or blur a region, as convert --redact does
+stats              size, format, colors and encoded QOI size
+show               preview in the terminal
+save PATH          write the current image, format picked by the extension
+undo               go back one step
+quit               leave (so does end of input)";
+
+///Read commands from stdin until `quit` or end of input. `save` writes an image the same
+///way convert does.
+pub fn run(
+    name: &Path,
+    img: RawImage,
+    save: impl Fn(RawImage, &Path) -> Result<(), String>,
+) -> Result<(), String> {
+    let interactive = io::stdin().is_terminal();
+    if interactive {
+        println!(
+            "{}: {}x{} loaded. Type 'help' for commands.",
+            name.display(),
+            img.0,
+            img.1
+        );
+    }
+    let mut history = vec![img];
+    let mut lines = io::stdin().lock().lines();
+    loop {
+        if interactive {
+            print!("qoi> ");
+            io::stdout().flush().map_err(|e| e.to_string())?;
+        }
+        let Some(line) = lines.next() else {
+            break;
+        };
+        let line = line.map_err(|e| e.to_string())?;
+        let (command, args) = line.trim().split_once(' ').unwrap_or((line.trim(), ""));
+        let args = args.trim();
+        let current = history.last().unwrap();
+        let result = match command {
+            "" => continue,
+            "quit" | "exit" => break,
+            "help" => {
+                println!("{}", HELP);
+                continue;
+            }
+            "undo" => {
+                if history.len() > 1 {
+                    history.pop();
+                } else {
+                    println!("Nothing to undo");
+                }
+                continue;
+            }
+            "stats" => {
+                println!("{}", stats(current));
+                continue;
+            }
+            "show" => {
+                print!("{}", term::render(current, term::columns()));
+                continue;
+            }
+            "save" => {
+                let saved = match args {
+                    "" => Err("save needs a path".to_string()),
+                    path => save(current.clone(), Path::new(path)),
+                };
+                if let Err(e) = saved {
+                    println!("Error: {}", e);
+                }
+                continue;
+            }
+            _ => transform(current.clone(), command, args),
+        };
+        match result {
+            Ok(img) => {
+                if (img.0, img.1) != (current.0, current.1) {
+                    println!("{}x{}", img.0, img.1);
+                }
+                history.push(img);
+            }
+            Err(e) => println!("Error: {}", e),
+        }
+    }
+    Ok(())
+}
+
+fn transform(img: RawImage, command: &str, args: &str) -> Result<RawImage, String> {
+    match command {
+        "crop" => {
+            let (width, height, x, y) = parse_rect(args)?;
+            if x as u64 + width as u64 > img.0 as u64 || y as u64 + height as u64 > img.1 as u64 {
+                return Err(format!("{} is outside the {}x{} image", args, img.0, img.1));
+            }
+            img.crop(x, y, width, height)
+        }
+        "trim" => {
+            let tolerance = match args {
+                "" => 0,
+                n => n
+                    .parse()
+                    .map_err(|_| format!("Invalid tolerance '{}'", n))?,
+            };
+            match img.content_bounds(tolerance) {
+                Some((x, y, width, height)) => img.crop(x, y, width, height),
+                None => Err("nothing but border".into()),
+            }
+        }
+        "resize" => {
+            let (width, height) = slice::parse_size(args)?;
+            if width == 0 || height == 0 {
+                return Err(format!("Invalid size '{}': it has no pixels", args));
+            }
+            slice::stretch(&img, &Borders::default(), width, height)
+        }
+        "half" => Ok(img.half_size()),
+        "posterize" => {
+            let bits = args
+                .parse::<u32>()
+                .ok()
+                .filter(|bits| (0..8).contains(bits))
+                .ok_or_else(|| format!("Invalid bit count '{}', expected 0 to 7", args))?;
+            Ok(img.posterize(bits))
+        }
+        "filter" => Ok(args.parse::<Filter>()?.apply(img)),
+        "redact" => {
+            let mut img = img;
+            if !img.redact(&args.parse::<Redaction>()?) {
+                return Err(format!("{} is outside the {}x{} image", args, img.0, img.1));
+            }
+            Ok(img)
+        }
+        _ => Err(format!("Unknown command '{}', try 'help'", command)),
+    }
+}
+
+///`WxH+X+Y` as (width, height, x, y)
+fn parse_rect(s: &str) -> Result<(u32, u32, u32, u32), String> {
+    let usage = || format!("Invalid rectangle '{}': expected WxH+X+Y", s);
+    let (size, offset) = s.split_once('+').ok_or_else(usage)?;
+    let (x, y) = offset.split_once('+').ok_or_else(usage)?;
+    let (width, height) = slice::parse_size(size).map_err(|_| usage())?;
+    if width == 0 || height == 0 {
+        return Err(format!("Invalid rectangle '{}': it has no pixels", s));
+    }
+    let number = |n: &str| n.trim().parse::<u32>().map_err(|_| usage());
+    Ok((width, height, number(x)?, number(y)?))
+}
+
+fn stats(img: &RawImage) -> String {
+    let mut rgba = vec![];
+    img.rgba8_into(&mut rgba);
+    let colors: HashSet<&[u8]> = rgba.chunks_exact(4).collect();
+    let transparent = rgba.chunks_exact(4).filter(|pixel| pixel[3] == 0).count();
+    let encoded = qoi::encode_img(img.clone()).len();
+    format!(
+        "{}x{} {:?}, {} colors, {} fully transparent pixels, {} bytes as QOI ({:.1}% of raw RGBA)",
+        img.0,
+        img.1,
+        img.format(),
+        colors.len(),
+        transparent,
+        encoded,
+        encoded as f64 * 100.0 / rgba.len().max(1) as f64
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::img::{ColorSpace, PixelFormat};
+
+    fn image() -> RawImage {
+        let data = (0..4 * 3 * 4).map(|i| i as u8).collect();
+        RawImage(4, 3, data, PixelFormat::Rgba8, ColorSpace::Srgb)
+    }
+
+    #[test]
+    fn crop_keeps_the_rectangle() {
+        let img = transform(image(), "crop", "2x1+1+2").unwrap();
+        assert_eq!((img.0, img.1), (2, 1));
+        assert_eq!(img.2, (36..44).collect::<Vec<u8>>());
+    }
+
+    #[test]
+    fn empty_rectangles_are_rejected() {
+        for rect in ["0x1+0+0", "1x0+0+0", "0x0+0+0"] {
+            let error = transform(image(), "crop", rect).err().unwrap();
+            assert!(error.contains("no pixels"), "{}", error);
+        }
+        for size in ["0x2", "2x0"] {
+            assert!(transform(image(), "resize", size).is_err());
+        }
+    }
+
+    #[test]
+    fn rectangles_past_the_edge_are_rejected() {
+        for rect in ["5x1+0+0", "1x1+4+0", "4x3+0+1", "1x1+4294967295+0"] {
+            assert!(transform(image(), "crop", rect).is_err(), "{}", rect);
+        }
+    }
+}
@@ -0,0 +1,142 @@
+//! 9-slice scaling for UI assets, for `slice`: fixed corners, edges that stretch in one
+//! direction and a center that stretches in both
+
+use crate::img::{Dimensions, RawImage};
+use std::str::FromStr;
+
+///How far each border reaches into the image, written like CSS `border-image-slice`:
+///`all`, `vertical,horizontal` or `top,right,bottom,left`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Borders {
+    pub top: u32,
+    pub right: u32,
+    pub bottom: u32,
+    pub left: u32,
+}
+
+impl FromStr for Borders {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let usage = || format!("Invalid borders '{}': expected N, V,H or T,R,B,L", s);
+        let sizes: Vec<u32> = s
+            .split(',')
+            .map(|n| n.trim().parse().map_err(|_| usage()))
+            .collect::<Result<_, _>>()?;
+        let [top, right, bottom, left] = match sizes[..] {
+            [all] => [all; 4],
+            [vertical, horizontal] => [vertical, horizontal, vertical, horizontal],
+            [top, right, bottom, left] => [top, right, bottom, left],
+            _ => return Err(usage()),
+        };
+        Ok(Borders {
+            top,
+            right,
+            bottom,
+            left,
+        })
+    }
+}
+
+impl Borders {
+    ///Check that the borders fit inside a width x height image
+    pub fn check(&self, width: u32, height: u32) -> Result<(), String> {
+        if self.left as u64 + self.right as u64 > width as u64
+            || self.top as u64 + self.bottom as u64 > height as u64
+        {
+            return Err(format!(
+                "Borders {},{},{},{} don't fit in a {}x{} image",
+                self.top, self.right, self.bottom, self.left, width, height
+            ));
+        }
+        Ok(())
+    }
+
+    ///The nine regions of a width x height image as (name, x, y, width, height), row by
+    ///row from the top left. Regions are empty where a border is 0 or fills the image.
+    pub fn regions(&self, width: u32, height: u32) -> [(&'static str, u32, u32, u32, u32); 9] {
+        let columns = [
+            (0, self.left),
+            (self.left, width - self.left - self.right),
+            (width - self.right, self.right),
+        ];
+        let rows = [
+            (0, self.top),
+            (self.top, height - self.top - self.bottom),
+            (height - self.bottom, self.bottom),
+        ];
+        const NAMES: [&str; 9] = [
+            "top-left",
+            "top",
+            "top-right",
+            "left",
+            "center",
+            "right",
+            "bottom-left",
+            "bottom",
+            "bottom-right",
+        ];
+        std::array::from_fn(|i| {
+            let ((x, w), (y, h)) = (columns[i % 3], rows[i / 3]);
+            (NAMES[i], x, y, w, h)
+        })
+    }
+}
+
+///Which source index an output index along one axis reads from: the borders are copied
+///as they are and the middle is stretched with nearest-neighbour sampling
+fn source_index(i: u32, size: u32, source_size: u32, start: u32, end: u32) -> u32 {
+    if i < start {
+        i
+    } else if i >= size - end {
+        source_size - (size - i)
+    } else {
+        let (middle, source_middle) = (size - start - end, source_size - start - end);
+        start + ((i - start) as u64 * source_middle as u64 / middle as u64) as u32
+    }
+}
+
+///Scale `img` to width x height the way a UI toolkit draws a 9-slice sprite. With no
+///borders this is a plain nearest-neighbour resize.
+pub fn stretch(
+    img: &RawImage,
+    borders: &Borders,
+    width: u32,
+    height: u32,
+) -> Result<RawImage, String> {
+    let RawImage(source_width, source_height, data, format, space) = img;
+    // An empty source has no pixels to stretch
+    Dimensions::new(*source_width, *source_height)?;
+    let size = Dimensions::new(width, height)?;
+    borders.check(*source_width, *source_height)?;
+    borders.check(width, height)?;
+    let stretches_nothing = |size: u32, source_size: u32, start: u32, end: u32| {
+        size > start + end && source_size == start + end
+    };
+    if stretches_nothing(width, *source_width, borders.left, borders.right)
+        || stretches_nothing(height, *source_height, borders.top, borders.bottom)
+    {
+        return Err("The borders leave no middle to stretch".into());
+    }
+    let bpp = format.bytes_per_pixel();
+    let row_len = *source_width as usize * bpp;
+    let columns: Vec<usize> = (0..width)
+        .map(|x| source_index(x, width, *source_width, borders.left, borders.right) as usize)
+        .collect();
+    let mut out = Vec::with_capacity(size.byte_len(*format));
+    for y in 0..height {
+        let source_y = source_index(y, height, *source_height, borders.top, borders.bottom);
+        let row = &data[source_y as usize * row_len..][..row_len];
+        for &x in &columns {
+            out.extend_from_slice(&row[x * bpp..][..bpp]);
+        }
+    }
+    Ok(RawImage(width, height, out, *format, *space))
+}
+
+///A `WxH` size, for `--preview`
+pub fn parse_size(s: &str) -> Result<(u32, u32), String> {
+    s.split_once('x')
+        .and_then(|(w, h)| Some((w.trim().parse().ok()?, h.trim().parse().ok()?)))
+        .ok_or_else(|| format!("Invalid size '{}': expected WxH", s))
+}
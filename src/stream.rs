@@ -0,0 +1,310 @@
+//! Small iterator adaptors the codecs are built on. Every format module consumes
+//! its input as a byte stream, so these cover the common "take exactly N more"
+//! and "regroup the stream" cases without going through intermediate slices.
+
+///Take a fixed number of items off the front of a stream as an array.
+///Returns None (having consumed what was left) if the stream ends early.
+pub trait TakeArray<T, const N: usize> {
+    fn take_array(&mut self) -> Option<[T; N]>;
+}
+
+impl<I, T, const N: usize> TakeArray<T, N> for I
+where
+    I: Iterator<Item = T>,
+    T: Copy + Default,
+{
+    fn take_array(&mut self) -> Option<[T; N]> {
+        let mut out = [T::default(); N];
+        for slot in out.iter_mut() {
+            *slot = self.next()?;
+        }
+        Some(out)
+    }
+}
+
+///Take a runtime-sized number of items off the front of a stream.
+pub trait TakeVec<T> {
+    ///Take up to n items. The result is shorter than n if the stream ran out.
+    fn take_vec(&mut self, n: usize) -> Vec<T>;
+
+    ///Take exactly n items, or None if the stream ran out first.
+    fn take_vec_exact(&mut self, n: usize) -> Option<Vec<T>> {
+        let taken = self.take_vec(n);
+        (taken.len() == n).then_some(taken)
+    }
+}
+
+impl<I, T> TakeVec<T> for I
+where
+    I: Iterator<Item = T>,
+{
+    fn take_vec(&mut self, n: usize) -> Vec<T> {
+        self.by_ref().take(n).collect()
+    }
+}
+
+///Insert `spacer` after every `period` items, e.g. to pad RGB out to RGBA.
+pub struct SpaceN<I, T: Clone>
+where
+    I: Iterator<Item = T>,
+{
+    stream: I,
+    count: usize,
+    period: usize,
+    spacer: T,
+}
+
+impl<I, T: Clone> Iterator for SpaceN<I, T>
+where
+    I: Iterator<Item = T>,
+{
+    type Item = T;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.count >= self.period {
+            self.count = 0;
+            Some(self.spacer.clone())
+        } else {
+            self.count += 1;
+            self.stream.next()
+        }
+    }
+}
+
+///Construct a SpaceN
+pub trait Spaceable<T: Clone>: Iterator<Item = T> + Sized {
+    fn space_n(self, spacer: T, period: usize) -> SpaceN<Self, T> {
+        SpaceN {
+            stream: self,
+            count: 0,
+            period,
+            spacer,
+        }
+    }
+}
+
+impl<I, T: Clone> Spaceable<T> for I where I: Iterator<Item = T> {}
+
+///Regroup a stream into arrays of N items. A trailing partial group is dropped,
+///like `slice::chunks_exact`.
+pub struct ChunkedExact<I, const N: usize> {
+    stream: I,
+}
+
+impl<I, T, const N: usize> Iterator for ChunkedExact<I, N>
+where
+    I: Iterator<Item = T>,
+    T: Copy + Default,
+{
+    type Item = [T; N];
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.stream.take_array()
+    }
+}
+
+///Construct a ChunkedExact
+pub trait ChunkExact: Iterator + Sized {
+    fn chunked_exact<const N: usize>(self) -> ChunkedExact<Self, N> {
+        ChunkedExact { stream: self }
+    }
+}
+
+impl<I: Iterator> ChunkExact for I {}
+
+///Reorder (or duplicate, or drop) the channels of each pixel in a stream of
+///pixel arrays. Output channel k is input channel `order[k]`.
+pub struct Reorder<I, const M: usize> {
+    stream: I,
+    order: [usize; M],
+}
+
+impl<I, T, const N: usize, const M: usize> Iterator for Reorder<I, M>
+where
+    I: Iterator<Item = [T; N]>,
+    T: Copy,
+{
+    type Item = [T; M];
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let pixel = self.stream.next()?;
+        Some(self.order.map(|channel| pixel[channel]))
+    }
+}
+
+///Construct a Reorder
+pub trait Reorderable<T, const N: usize>: Iterator<Item = [T; N]> + Sized {
+    fn reorder<const M: usize>(self, order: [usize; M]) -> Reorder<Self, M> {
+        assert!(order.iter().all(|&channel| channel < N));
+        Reorder {
+            stream: self,
+            order,
+        }
+    }
+}
+
+impl<I, T, const N: usize> Reorderable<T, N> for I where I: Iterator<Item = [T; N]> {}
+
+///RGBA <-> BGRA. Swapping red and blue is its own inverse, so one order covers both ways.
+pub const SWAP_RB: [usize; 4] = [2, 1, 0, 3];
+///RGBA -> ARGB
+pub const RGBA_TO_ARGB: [usize; 4] = [3, 0, 1, 2];
+///ARGB -> RGBA
+pub const ARGB_TO_RGBA: [usize; 4] = [1, 2, 3, 0];
+///Gray -> RGB
+pub const GRAY_TO_RGB: [usize; 3] = [0, 0, 0];
+///Gray+alpha -> RGBA
+pub const GRAY_ALPHA_TO_RGBA: [usize; 4] = [0, 0, 0, 1];
+
+///Reorder the channels of an interleaved buffer in place, without a second buffer.
+pub fn reorder_in_place<T: Copy, const N: usize>(buf: &mut [T], order: [usize; N]) {
+    for pixel in buf.chunks_exact_mut(N) {
+        let old: [T; N] = (*pixel).try_into().unwrap();
+        for (slot, &channel) in pixel.iter_mut().zip(order.iter()) {
+            *slot = old[channel];
+        }
+    }
+}
+
+///Walk N equally sized planes in lockstep, yielding one interleaved pixel at a time.
+pub struct Interleave<'a, T, const N: usize> {
+    planes: [&'a [T]; N],
+    pos: usize,
+}
+
+impl<T: Copy, const N: usize> Iterator for Interleave<'_, T, N> {
+    type Item = [T; N];
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.planes.iter().any(|plane| self.pos >= plane.len()) {
+            return None;
+        }
+        let pixel = self.planes.map(|plane| plane[self.pos]);
+        self.pos += 1;
+        Some(pixel)
+    }
+}
+
+///Planar -> interleaved. Stops at the end of the shortest plane.
+pub fn interleave<T: Copy, const N: usize>(planes: [&[T]; N]) -> Interleave<'_, T, N> {
+    Interleave { planes, pos: 0 }
+}
+
+///Interleaved -> planar, writing into caller-provided planes. Pixels past the end of the
+///shortest plane are ignored.
+pub fn deinterleave<T: Copy, const N: usize>(interleaved: &[T], mut planes: [&mut [T]; N]) {
+    for (pos, pixel) in interleaved.chunks_exact(N).enumerate() {
+        if planes.iter().any(|plane| pos >= plane.len()) {
+            return;
+        }
+        for (plane, &value) in planes.iter_mut().zip(pixel) {
+            plane[pos] = value;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn take_array() {
+        let mut stream = 1..=5u8;
+        assert_eq!(stream.take_array(), Some([1, 2]));
+        assert_eq!(stream.take_array(), Some([3, 4, 5]));
+        assert_eq!(TakeArray::<u8, 1>::take_array(&mut stream), None);
+
+        let mut short = 1..=2u8;
+        assert_eq!(TakeArray::<u8, 3>::take_array(&mut short), None);
+        assert_eq!(short.next(), None);
+    }
+
+    #[test]
+    fn take_vec() {
+        let mut stream = 1..=5u8;
+        assert_eq!(stream.take_vec(2), [1, 2]);
+        assert_eq!(stream.take_vec(0), []);
+        assert_eq!(stream.take_vec(4), [3, 4, 5]);
+        assert_eq!(stream.take_vec(1), []);
+    }
+
+    #[test]
+    fn take_vec_exact() {
+        assert_eq!((1..=3u8).take_vec_exact(3), Some(vec![1, 2, 3]));
+        assert_eq!((1..=3u8).take_vec_exact(4), None);
+        assert_eq!((1..=3u8).take_vec_exact(0), Some(vec![]));
+
+        let mut stream = 1..=3u8;
+        assert_eq!(stream.take_vec_exact(2), Some(vec![1, 2]));
+        assert_eq!(stream.take_vec_exact(2), None);
+        assert_eq!(stream.take_vec_exact(0), Some(vec![]));
+    }
+
+    #[test]
+    fn space_n() {
+        let rgba: Vec<u8> = [1, 2, 3, 4, 5, 6].into_iter().space_n(255, 3).collect();
+        assert_eq!(rgba, [1, 2, 3, 255, 4, 5, 6, 255]);
+        // The spacer comes after a full period, so a partial one gets none
+        let partial: Vec<u8> = [1, 2].into_iter().space_n(0, 3).collect();
+        assert_eq!(partial, [1, 2]);
+    }
+
+    #[test]
+    fn chunked_exact_drops_the_partial_group() {
+        let groups: Vec<[u8; 3]> = (1..=7u8).chunked_exact().collect();
+        assert_eq!(groups, [[1, 2, 3], [4, 5, 6]]);
+        let exact: Vec<[u8; 2]> = (1..=4u8).chunked_exact().collect();
+        assert_eq!(exact, [[1, 2], [3, 4]]);
+    }
+
+    #[test]
+    fn reorder() {
+        let pixels = [[1u8, 2, 3, 4], [5, 6, 7, 8]];
+        let bgra: Vec<_> = pixels.into_iter().reorder(SWAP_RB).collect();
+        assert_eq!(bgra, [[3, 2, 1, 4], [7, 6, 5, 8]]);
+        let back: Vec<_> = bgra.into_iter().reorder(SWAP_RB).collect();
+        assert_eq!(back, pixels);
+        let argb: Vec<_> = pixels.into_iter().reorder(RGBA_TO_ARGB).collect();
+        assert_eq!(argb, [[4, 1, 2, 3], [8, 5, 6, 7]]);
+        let rgba: Vec<_> = argb.into_iter().reorder(ARGB_TO_RGBA).collect();
+        assert_eq!(rgba, pixels);
+        let rgb: Vec<_> = [[9u8]].into_iter().reorder(GRAY_TO_RGB).collect();
+        assert_eq!(rgb, [[9, 9, 9]]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn reorder_rejects_missing_channels() {
+        let _ = [[1u8, 2, 3]].into_iter().reorder([0, 3]);
+    }
+
+    #[test]
+    fn reorder_in_place_matches_reorder() {
+        let mut buf = [1u8, 2, 3, 4, 5, 6, 7, 8];
+        reorder_in_place(&mut buf, RGBA_TO_ARGB);
+        assert_eq!(buf, [4, 1, 2, 3, 8, 5, 6, 7]);
+    }
+
+    #[test]
+    fn interleave_round_trip() {
+        let interleaved = [1u8, 10, 100, 2, 20, 200, 3, 30, 255];
+        let mut planes = [[0u8; 3]; 3];
+        let [r, g, b] = &mut planes;
+        deinterleave(&interleaved, [r, g, b]);
+        assert_eq!(planes, [[1, 2, 3], [10, 20, 30], [100, 200, 255]]);
+        let again: Vec<u8> = interleave(planes.each_ref().map(|plane| &plane[..]))
+            .flatten()
+            .collect();
+        assert_eq!(again, interleaved);
+    }
+
+    #[test]
+    fn interleave_stops_at_the_shortest_plane() {
+        let pixels: Vec<_> = interleave([&[1u8, 2, 3][..], &[4, 5]]).collect();
+        assert_eq!(pixels, [[1, 4], [2, 5]]);
+        let mut short = [0u8; 1];
+        let mut long = [0u8; 3];
+        deinterleave(&[1, 2, 3, 4], [&mut short, &mut long]);
+        assert_eq!((short, long), ([1], [2, 0, 0]));
+    }
+}
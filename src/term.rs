@@ -0,0 +1,125 @@
+use crate::img::{PixelFormat, RawImage};
+use std::fmt::Write;
+
+///Render an image as 24-bit ANSI color using upper-half-block characters, so each
+///character cell shows two pixel rows. The image is nearest-neighbour scaled down to
+///fit `max_cols` columns; transparent pixels are composited over black.
+pub fn render(img: &RawImage, max_cols: u32) -> String {
+    let RawImage(width, height, ref data, format, _) = *img;
+    let mut rgba = vec![];
+    if format == PixelFormat::Rgba8 {
+        rgba.extend_from_slice(data);
+    } else {
+        img.rgba8_into(&mut rgba);
+    }
+
+    let cols = width.min(max_cols.max(1));
+    let rows = (height as u64 * cols as u64 / width.max(1) as u64).max(1) as u32;
+    let sample = |x: u32, y: u32| -> [u8; 3] {
+        let sx = (x as u64 * width as u64 / cols as u64) as usize;
+        let sy = (y as u64 * height as u64 / rows as u64) as usize;
+        let i = (sy * width as usize + sx) * 4;
+        match rgba.get(i..i + 4) {
+            Some(&[r, g, b, a]) => [r, g, b].map(|c| (c as u16 * a as u16 / 255) as u8),
+            _ => [0, 0, 0],
+        }
+    };
+
+    let mut out = String::new();
+    for y in (0..rows).step_by(2) {
+        for x in 0..cols {
+            let [tr, tg, tb] = sample(x, y);
+            if y + 1 < rows {
+                let [br, bg, bb] = sample(x, y + 1);
+                write!(out, "\x1b[38;2;{tr};{tg};{tb}m\x1b[48;2;{br};{bg};{bb}m▀").unwrap();
+            } else {
+                write!(out, "\x1b[38;2;{tr};{tg};{tb}m\x1b[49m▀").unwrap();
+            }
+        }
+        out.push_str("\x1b[0m\n");
+    }
+    out
+}
+
+///Terminal width from $COLUMNS, falling back to 80
+pub fn columns() -> u32 {
+    std::env::var("COLUMNS")
+        .ok()
+        .and_then(|c| c.parse().ok())
+        .unwrap_or(80)
+}
+
+///Make a file name safe to show: control characters (escape sequences, newlines) and
+///bidi overrides that could disguise the name become U+FFFD, and names longer than
+///`max_chars` lose their middle so both the start and the extension stay visible.
+pub fn label(text: &str, max_chars: usize) -> String {
+    let chars: Vec<char> = text
+        .chars()
+        .map(|c| match c {
+            c if c.is_control() => '\u{FFFD}',
+            '\u{200E}' | '\u{200F}' | '\u{202A}'..='\u{202E}' | '\u{2066}'..='\u{2069}' => {
+                '\u{FFFD}'
+            }
+            c => c,
+        })
+        .collect();
+    if chars.len() <= max_chars {
+        return chars.into_iter().collect();
+    }
+    let keep = max_chars.saturating_sub(1);
+    let head = keep.div_ceil(2);
+    let tail = keep - head;
+    chars[..head]
+        .iter()
+        .chain(['…'].iter())
+        .chain(&chars[chars.len() - tail..])
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn control_characters_are_replaced() {
+        assert_eq!(
+            label("a\nb\rc\td\0", 80),
+            "a\u{FFFD}b\u{FFFD}c\u{FFFD}d\u{FFFD}"
+        );
+        // An escape sequence loses its ESC, so the rest is shown as plain text
+        assert_eq!(
+            label("\x1b[2J\x1b]0;pwned\x07.png", 80),
+            "\u{FFFD}[2J\u{FFFD}]0;pwned\u{FFFD}.png"
+        );
+        assert_eq!(label("\u{9b}31m", 80), "\u{FFFD}31m");
+        assert_eq!(label("plain name.qoi", 80), "plain name.qoi");
+    }
+
+    #[test]
+    fn bidi_overrides_are_replaced() {
+        // Would display as "photo_gnp.exe" the other way round
+        assert_eq!(label("photo_\u{202E}gnp.exe", 80), "photo_\u{FFFD}gnp.exe");
+        assert_eq!(
+            label("\u{2066}a\u{2069}\u{200F}", 80),
+            "\u{FFFD}a\u{FFFD}\u{FFFD}"
+        );
+    }
+
+    #[test]
+    fn long_names_keep_both_ends() {
+        assert_eq!(label("abcdefghij.qoi", 14), "abcdefghij.qoi");
+        assert_eq!(label("abcdefghij.qoi", 9), "abcd….qoi");
+        assert_eq!(label("abcdefghij.qoi", 8), "abcd…qoi");
+        assert_eq!(label("abcdefghij.qoi", 1), "…");
+        // Counted in chars, so multi-byte ones aren't cut in half
+        assert_eq!(label("ééééé", 4), "éé…é");
+    }
+
+    #[test]
+    fn truncation_counts_replaced_characters() {
+        let name = "\n".repeat(100);
+        let shown = label(&name, 10);
+        assert_eq!(shown.chars().count(), 10);
+        assert!(!shown.contains('\n'));
+    }
+}
@@ -0,0 +1,345 @@
+//! Baseline TIFF input: the first image of a file, stored in strips, 8 bits per sample,
+//! uncompressed or LZW (with or without the horizontal predictor). RGB, RGBA, gray and
+//! gray with alpha decode to the matching 8-bit format. Tiles, planar layout, palettes
+//! and the fax and JPEG compressions are reported as unsupported.
+
+use crate::img::{ColorSpace, Dimensions, Header, PixelFormat, RawImage};
+
+const IMAGE_WIDTH: u16 = 256;
+const IMAGE_LENGTH: u16 = 257;
+const BITS_PER_SAMPLE: u16 = 258;
+const COMPRESSION: u16 = 259;
+const PHOTOMETRIC: u16 = 262;
+const STRIP_OFFSETS: u16 = 273;
+const SAMPLES_PER_PIXEL: u16 = 277;
+const ROWS_PER_STRIP: u16 = 278;
+const STRIP_BYTE_COUNTS: u16 = 279;
+const PLANAR_CONFIGURATION: u16 = 284;
+const PREDICTOR: u16 = 317;
+const EXTRA_SAMPLES: u16 = 338;
+const DATE_TIME: u16 = 306;
+const EXIF_IFD: u16 = 34665;
+const DATE_TIME_ORIGINAL: u16 = 36867;
+
+///An IFD (image file directory) and the file it points into
+struct Ifd<'a> {
+    data: &'a [u8],
+    big_endian: bool,
+    ///(tag, type, count, value or offset)
+    entries: Vec<(u16, u16, u32, [u8; 4])>,
+}
+
+impl<'a> Ifd<'a> {
+    ///The first IFD
+    fn parse(data: &'a [u8]) -> Result<Self, String> {
+        let big_endian = match data.get(..4) {
+            Some(b"II*\0") => false,
+            Some(b"MM\0*") => true,
+            _ => return Err("not a TIFF file (bad magic bytes)".into()),
+        };
+        let header = Ifd {
+            data,
+            big_endian,
+            entries: vec![],
+        };
+        header.at(header.u32_at(4)? as usize)
+    }
+
+    ///Another IFD in the same file, starting at `start`
+    fn at(&self, start: usize) -> Result<Self, String> {
+        let mut ifd = Ifd {
+            data: self.data,
+            big_endian: self.big_endian,
+            entries: vec![],
+        };
+        let count = ifd.u16_at(start)? as usize;
+        for n in 0..count {
+            let at = start + 2 + n * 12;
+            let value = self
+                .data
+                .get(at + 8..at + 12)
+                .ok_or("TIFF directory runs past the end of the file")?;
+            ifd.entries.push((
+                ifd.u16_at(at)?,
+                ifd.u16_at(at + 2)?,
+                ifd.u32_at(at + 4)?,
+                value.try_into().unwrap(),
+            ));
+        }
+        Ok(ifd)
+    }
+
+    fn u16_from(&self, bytes: [u8; 2]) -> u16 {
+        match self.big_endian {
+            true => u16::from_be_bytes(bytes),
+            false => u16::from_le_bytes(bytes),
+        }
+    }
+
+    fn u32_from(&self, bytes: [u8; 4]) -> u32 {
+        match self.big_endian {
+            true => u32::from_be_bytes(bytes),
+            false => u32::from_le_bytes(bytes),
+        }
+    }
+
+    fn u16_at(&self, at: usize) -> Result<u16, String> {
+        let bytes = self.data.get(at..at + 2).ok_or("TIFF is truncated")?;
+        Ok(self.u16_from(bytes.try_into().unwrap()))
+    }
+
+    fn u32_at(&self, at: usize) -> Result<u32, String> {
+        let bytes = self.data.get(at..at + 4).ok_or("TIFF is truncated")?;
+        Ok(self.u32_from(bytes.try_into().unwrap()))
+    }
+
+    ///Every value of an integer (BYTE, SHORT or LONG) field, or None if it's absent
+    fn values(&self, tag: u16) -> Result<Option<Vec<u32>>, String> {
+        let Some(&(_, kind, count, value)) = self.entries.iter().find(|e| e.0 == tag) else {
+            return Ok(None);
+        };
+        let size = match kind {
+            1 => 1,
+            3 => 2,
+            4 => 4,
+            _ => return Err(format!("TIFF tag {} has unexpected type {}", tag, kind)),
+        };
+        let count = count as usize;
+        let len = count.checked_mul(size).ok_or("TIFF field is too large")?;
+        // Up to 4 bytes are stored in the entry itself, anything longer elsewhere
+        let bytes = match len <= 4 {
+            true => &value[..len],
+            false => {
+                let offset = self.u32_from(value) as usize;
+                self.data
+                    .get(offset..offset.saturating_add(len))
+                    .ok_or_else(|| format!("TIFF tag {} points past the end of the file", tag))?
+            }
+        };
+        Ok(Some(
+            bytes
+                .chunks_exact(size)
+                .map(|v| match size {
+                    1 => v[0] as u32,
+                    2 => self.u16_from([v[0], v[1]]) as u32,
+                    _ => self.u32_from([v[0], v[1], v[2], v[3]]),
+                })
+                .collect(),
+        ))
+    }
+
+    ///An ASCII field, up to its terminating NUL
+    fn text(&self, tag: u16) -> Option<String> {
+        let &(_, kind, count, value) = self.entries.iter().find(|e| e.0 == tag)?;
+        let count = count as usize;
+        let bytes = match (kind, count <= 4) {
+            (2, true) => &value[..count],
+            (2, false) => {
+                let offset = self.u32_from(value) as usize;
+                self.data.get(offset..offset.saturating_add(count))?
+            }
+            _ => return None,
+        };
+        let text = bytes.split(|&b| b == 0).next().unwrap_or_default();
+        Some(String::from_utf8_lossy(text).trim().to_string())
+    }
+
+    fn value(&self, tag: u16) -> Result<Option<u32>, String> {
+        Ok(self.values(tag)?.and_then(|values| values.first().copied()))
+    }
+
+    fn required(&self, tag: u16, name: &str) -> Result<u32, String> {
+        self.value(tag)?
+            .ok_or_else(|| format!("TIFF has no {}", name))
+    }
+}
+
+///When a photo was taken, from an EXIF block (which is laid out like a TIFF file):
+///DateTimeOriginal, or DateTime if that's missing, as `YYYY:MM:DD HH:MM:SS` in the
+///camera's local time
+pub fn exif_date(exif: &[u8]) -> Option<String> {
+    let ifd = Ifd::parse(exif).ok()?;
+    let original = match ifd.value(EXIF_IFD) {
+        Ok(Some(offset)) => ifd
+            .at(offset as usize)
+            .ok()
+            .and_then(|exif| exif.text(DATE_TIME_ORIGINAL)),
+        _ => None,
+    };
+    original
+        .or_else(|| ifd.text(DATE_TIME))
+        .filter(|date| !date.is_empty())
+}
+
+///Read just the header
+pub fn read_header(data: &[u8]) -> Result<Header, String> {
+    let ifd = Ifd::parse(data)?;
+    Ok(Header {
+        width: ifd.required(IMAGE_WIDTH, "ImageWidth")?,
+        height: ifd.required(IMAGE_LENGTH, "ImageLength")?,
+        channels: ifd.value(SAMPLES_PER_PIXEL)?.unwrap_or(1) as u8,
+        bit_depth: ifd.value(BITS_PER_SAMPLE)?.unwrap_or(1) as u8,
+        colorspace: None,
+    })
+}
+
+#[tracing::instrument(name = "tiff::decode", skip_all)]
+pub fn parse_img(data: &[u8]) -> Result<RawImage, String> {
+    let ifd = Ifd::parse(data)?;
+    let width = ifd.required(IMAGE_WIDTH, "ImageWidth")?;
+    let height = ifd.required(IMAGE_LENGTH, "ImageLength")?;
+    let samples = ifd.value(SAMPLES_PER_PIXEL)?.unwrap_or(1);
+    let photometric = ifd.required(PHOTOMETRIC, "PhotometricInterpretation")?;
+    let format = match (photometric, samples) {
+        (0 | 1, 1) => PixelFormat::Gray8,
+        (0 | 1, 2) => PixelFormat::GrayAlpha8,
+        (2, 3) => PixelFormat::Rgb8,
+        (2, 4) => PixelFormat::Rgba8,
+        _ => {
+            return Err(format!(
+                "TIFF with photometric interpretation {} and {} samples per pixel isn't supported",
+                photometric, samples
+            ));
+        }
+    };
+    let bits = ifd.values(BITS_PER_SAMPLE)?.unwrap_or(vec![1]);
+    if bits.iter().any(|&b| b != 8) {
+        return Err(format!(
+            "TIFF with {:?} bits per sample isn't supported, only 8",
+            bits
+        ));
+    }
+    if ifd.value(PLANAR_CONFIGURATION)?.unwrap_or(1) != 1 {
+        return Err("planar TIFF isn't supported, only interleaved".into());
+    }
+    let compression = ifd.value(COMPRESSION)?.unwrap_or(1);
+    if !matches!(compression, 1 | 5) {
+        return Err(format!(
+            "TIFF compression {} isn't supported, only none (1) and LZW (5)",
+            compression
+        ));
+    }
+    let offsets = ifd
+        .values(STRIP_OFFSETS)?
+        .ok_or("TIFF has no StripOffsets (tiled TIFF isn't supported)")?;
+    let counts = ifd
+        .values(STRIP_BYTE_COUNTS)?
+        .ok_or("TIFF has no StripByteCounts")?;
+    if offsets.len() != counts.len() {
+        return Err("TIFF StripOffsets and StripByteCounts differ in length".into());
+    }
+
+    let size = Dimensions::new(width, height).map_err(|e| format!("TIFF {}", e))?;
+    let row_len = width as usize * format.bytes_per_pixel();
+    let len = size.byte_len(format);
+    let rows_per_strip = ifd.value(ROWS_PER_STRIP)?.unwrap_or(height).max(1) as usize;
+    let mut pixels = Vec::with_capacity(len);
+    for (&offset, &count) in offsets.iter().zip(&counts) {
+        let strip = data
+            .get(offset as usize..(offset as usize).saturating_add(count as usize))
+            .ok_or("TIFF strip runs past the end of the file")?;
+        let expected = rows_per_strip
+            .saturating_mul(row_len)
+            .min(len - pixels.len());
+        match compression {
+            5 => pixels.extend(lzw_decode(strip, expected)?),
+            _ => pixels.extend_from_slice(&strip[..expected.min(strip.len())]),
+        }
+        if pixels.len() >= len {
+            break;
+        }
+    }
+    if pixels.len() < len {
+        return Err("TIFF data ends before the last pixel".into());
+    }
+    pixels.truncate(len);
+
+    if ifd.value(PREDICTOR)?.unwrap_or(1) == 2 {
+        let step = samples as usize;
+        for row in pixels.chunks_exact_mut(row_len) {
+            for i in step..row.len() {
+                row[i] = row[i].wrapping_add(row[i - step]);
+            }
+        }
+    }
+    if photometric == 0 {
+        // WhiteIsZero: flip the gray sample, leave alpha alone
+        for pixel in pixels.chunks_exact_mut(samples as usize) {
+            pixel[0] = 255 - pixel[0];
+        }
+    }
+    // Associated alpha is premultiplied, which nothing downstream expects
+    if samples % 2 == 0 && ifd.value(EXTRA_SAMPLES)? == Some(1) {
+        for pixel in pixels.chunks_exact_mut(samples as usize) {
+            let (color, alpha) = pixel.split_at_mut(samples as usize - 1);
+            if alpha[0] > 0 {
+                for c in color {
+                    *c = ((*c as u32 * 255 + alpha[0] as u32 / 2) / alpha[0] as u32).min(255) as u8;
+                }
+            }
+        }
+    }
+    Ok(RawImage(width, height, pixels, format, ColorSpace::Srgb))
+}
+
+///TIFF's LZW: codes start at 9 bits, most significant bit first, and widen one code
+///earlier than GIF's. 256 resets the table and 257 ends the strip.
+fn lzw_decode(data: &[u8], expected: usize) -> Result<Vec<u8>, String> {
+    const CLEAR: usize = 256;
+    const END: usize = 257;
+    let mut out: Vec<u8> = Vec::with_capacity(expected);
+    // Every string is a run of earlier output, so the table is (start, length) pairs into
+    // `out` for the codes from 258 up
+    let mut table: Vec<(usize, usize)> = Vec::with_capacity(4096 - 258);
+    let mut previous: Option<(usize, usize)> = None;
+    let (mut buffer, mut buffered, mut next_byte) = (0u32, 0, 0);
+    loop {
+        let width = match 258 + table.len() {
+            ..511 => 9,
+            511..1023 => 10,
+            1023..2047 => 11,
+            _ => 12,
+        };
+        while buffered < width {
+            let Some(&byte) = data.get(next_byte) else {
+                return Ok(out); // Some writers leave out the end code
+            };
+            buffer = buffer << 8 | byte as u32;
+            buffered += 8;
+            next_byte += 1;
+        }
+        buffered -= width;
+        let code = (buffer >> buffered) as usize & ((1 << width) - 1);
+        if code == CLEAR {
+            table.clear();
+            previous = None;
+            continue;
+        }
+        if code == END || out.len() >= expected {
+            return Ok(out);
+        }
+        let start = out.len();
+        let entry = if code < 256 {
+            out.push(code as u8);
+            (start, 1)
+        } else if let Some(&(from, len)) = table.get(code - 258) {
+            out.extend_from_within(from..from + len);
+            (start, len)
+        } else if let (true, Some((from, len))) = (code - 258 == table.len(), previous) {
+            // The code being defined right now: the previous string plus its own first byte
+            out.extend_from_within(from..from + len);
+            out.push(out[from]);
+            (start, len + 1)
+        } else {
+            return Err(format!("TIFF LZW code {} is out of range", code));
+        };
+        // The previous string directly precedes this one in `out`, so it plus this
+        // string's first byte is one contiguous run
+        if let Some((from, len)) = previous
+            && table.len() < 4096 - 258
+        {
+            table.push((from, len + 1));
+        }
+        previous = Some(entry);
+    }
+}
@@ -0,0 +1,126 @@
+//! Wall time spent in each stage of a conversion, for `convert --timings`, and frame rates
+//! for the streaming commands
+
+use std::fmt;
+use std::ops::AddAssign;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Timings {
+    pub read: Duration,
+    pub decode: Duration,
+    pub encode: Duration,
+    pub write: Duration,
+}
+
+impl Timings {
+    ///Run `f`, adding the time it took to `stage`
+    pub fn time<T>(stage: &mut Duration, f: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let result = f();
+        *stage += start.elapsed();
+        result
+    }
+
+    pub fn total(&self) -> Duration {
+        self.read + self.decode + self.encode + self.write
+    }
+}
+
+impl AddAssign for Timings {
+    fn add_assign(&mut self, other: Self) {
+        self.read += other.read;
+        self.decode += other.decode;
+        self.encode += other.encode;
+        self.write += other.write;
+    }
+}
+
+impl fmt::Display for Timings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+        write!(
+            f,
+            "read {:>8.2}ms  decode {:>8.2}ms  encode {:>8.2}ms  write {:>8.2}ms  total {:>8.2}ms",
+            ms(self.read),
+            ms(self.decode),
+            ms(self.encode),
+            ms(self.write),
+            ms(self.total())
+        )
+    }
+}
+
+///How often `FrameStats::report_due` fires
+const REPORT_EVERY: Duration = Duration::from_secs(1);
+
+///Frame rate bookkeeping for a stream of frames, split into time spent waiting for the
+///producer and time spent handling each frame
+#[derive(Debug)]
+pub struct FrameStats {
+    start: Instant,
+    last_report: Instant,
+    frames: u32,
+    waiting: Duration,
+    processing: Duration,
+    late: u32,
+}
+
+impl FrameStats {
+    pub fn new() -> Self {
+        let now = Instant::now();
+        FrameStats {
+            start: now,
+            last_report: now,
+            frames: 0,
+            waiting: Duration::ZERO,
+            processing: Duration::ZERO,
+            late: 0,
+        }
+    }
+
+    ///Record one frame. It counts as late if handling it took longer than frames have
+    ///been arriving on average, i.e. it made the producer wait.
+    pub fn record(&mut self, waited: Duration, processed: Duration) {
+        self.frames += 1;
+        self.waiting += waited;
+        self.processing += processed;
+        if processed > self.start.elapsed() / self.frames {
+            self.late += 1;
+        }
+    }
+
+    ///True once per `REPORT_EVERY`
+    pub fn report_due(&mut self) -> bool {
+        if self.last_report.elapsed() < REPORT_EVERY {
+            return false;
+        }
+        self.last_report = Instant::now();
+        true
+    }
+}
+
+impl Default for FrameStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Display for FrameStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let fps = |time: Duration| match time.as_secs_f64() {
+            0.0 => 0.0,
+            secs => self.frames as f64 / secs,
+        };
+        write!(
+            f,
+            "{} frames in {:.1}s  incoming {:>6.1} fps  processing {:>6.1} fps  waiting {:.1}s  late {}",
+            self.frames,
+            self.start.elapsed().as_secs_f64(),
+            fps(self.start.elapsed()),
+            fps(self.processing),
+            self.waiting.as_secs_f64(),
+            self.late
+        )
+    }
+}
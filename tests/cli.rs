@@ -0,0 +1,114 @@
+//! Runs the qoi binary the way scripts and CI jobs do
+
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+
+fn qoi() -> Command {
+    Command::new(env!("CARGO_BIN_EXE_qoi"))
+}
+
+///An empty scratch directory for one test
+fn scratch(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("qoi-test-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn pic(name: &str) -> Vec<u8> {
+    std::fs::read(
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("pics")
+            .join(name),
+    )
+    .unwrap()
+}
+
+///Run `command` with `input` piped to its stdin, like `cat file | qoi ...`
+fn piped(mut command: Command, input: &[u8]) -> Output {
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    // Written from another thread so a full stdout pipe can't deadlock us
+    let mut stdin = child.stdin.take().unwrap();
+    let input = input.to_vec();
+    let writer = std::thread::spawn(move || stdin.write_all(&input));
+    let output = child.wait_with_output().unwrap();
+    writer.join().unwrap().unwrap();
+    output
+}
+
+fn assert_success(output: &Output) {
+    assert!(
+        output.status.success(),
+        "{}\n{}",
+        String::from_utf8_lossy(&output.stdout),
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
+
+#[test]
+fn convert_reads_a_piped_qoi() {
+    let dir = scratch("piped-qoi");
+    for name in ["testcard_rgba.qoi", "kodim23.qoi"] {
+        let input = pic(name);
+        let out = dir.join(name);
+        let mut command = qoi();
+        command
+            .args(["convert", "-", "--from", "qoi", "--output"])
+            .arg(&out);
+        assert_success(&piped(command, &input));
+        // These were written by the reference encoder, which ours matches chunk for chunk
+        assert!(std::fs::read(&out).unwrap() == input, "{}", name);
+    }
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn convert_round_trips_a_pipe_through_png() {
+    let dir = scratch("piped-png");
+    let input = pic("testcard_rgba.qoi");
+    let png = dir.join("testcard.png");
+    let mut command = qoi();
+    command
+        .args(["convert", "-", "--from", "qoi", "--output"])
+        .arg(&png);
+    assert_success(&piped(command, &input));
+
+    let back = dir.join("back.qoi");
+    let mut command = qoi();
+    command
+        .args(["convert", "-", "--from", "png", "--output"])
+        .arg(&back);
+    assert_success(&piped(command, &std::fs::read(&png).unwrap()));
+    assert!(std::fs::read(&back).unwrap() == input);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn convert_needs_a_format_for_stdin() {
+    let mut command = qoi();
+    command.args(["convert", "-", "--output", "out.qoi"]);
+    let output = piped(command, &pic("img.qoi"));
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("--from"));
+}
+
+#[cfg(feature = "cli")]
+#[test]
+fn examples_pass_their_check() {
+    // Examples needing a tool that isn't installed are skipped, not failed
+    let output = qoi().args(["examples", "--check"]).output().unwrap();
+    assert_success(&output);
+    let report = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        report.lines().any(|line| line.starts_with("ok ")),
+        "{}",
+        report
+    );
+    assert!(!report.contains("FAIL"), "{}", report);
+}